@@ -5,21 +5,54 @@ const USAGE: &str = "
 A simple blockchain written in Rust.
 
 Usage:
-  rust-blockchain broadcast [--peers=<ports>]
-  rust-blockchain mine
+  rust-blockchain broadcast [--peers=<ports>] [--key=<file>]
+  rust-blockchain mine [--threads=<n>] [--key=<file>] [--validator=<file>]
+  rust-blockchain miner --pool=<addr> [--threads=<n>] [--key=<file>]
+  rust-blockchain sync [--peers=<ports>] [--key=<file>]
+  rust-blockchain estimatefee <blocks> [--peers=<ports>] [--key=<file>]
+  rust-blockchain mininginfo [--peers=<ports>] [--key=<file>]
+  rust-blockchain stake <account> <amount> <validator> [--peers=<ports>] [--key=<file>]
+  rust-blockchain generate <blocks> [<address>] [--peers=<ports>] [--key=<file>]
+  rust-blockchain setmocktime <ms> [--peers=<ports>] [--key=<file>]
+  rust-blockchain keygen <file>
+  rust-blockchain validatorkey <file>
   rust-blockchain (-h | --help)
 
 Options:
-  --peers=<ports>  Broadcast to specific ports only.
-  -h --help        Show this screen.
+  --peers=<ports>     Talk to specific ports only.
+  --key=<file>        Static key used for encrypted transport.
+  --validator=<file>  Key this node signs blocks with, without proof of work.
+  --threads=<n>       Mining threads, 0 to only relay; all cores if unset.
+  --pool=<addr>       Node to mine for, as host:port or a local port.
+  -h --help           Show this screen.
 ";
 
 #[derive(Debug, Deserialize)]
 pub struct Args {
     pub flag_peers: Vec<String>,
+    pub flag_key: Option<String>,
+    pub flag_threads: Option<usize>,
+    pub flag_validator: Option<String>,
+    pub flag_pool: Option<String>,
     pub arg_ports: Vec<u16>,
+    pub arg_file: Option<String>,
+    pub arg_blocks: Option<usize>,
+    pub arg_account: Option<char>,
+    pub arg_amount: Option<i32>,
+    pub arg_validator: Option<String>,
+    pub arg_address: Option<char>,
+    pub arg_ms: Option<u64>,
     pub cmd_broadcast: bool,
     pub cmd_mine: bool,
+    pub cmd_miner: bool,
+    pub cmd_sync: bool,
+    pub cmd_estimatefee: bool,
+    pub cmd_mininginfo: bool,
+    pub cmd_stake: bool,
+    pub cmd_generate: bool,
+    pub cmd_setmocktime: bool,
+    pub cmd_keygen: bool,
+    pub cmd_validatorkey: bool,
 }
 
 pub fn get() -> Args {
@@ -0,0 +1,58 @@
+use serde::Deserialize;
+use docopt::Docopt;
+
+const USAGE: &str = "
+A command line client for a running node, over its json-rpc api.
+
+Usage:
+  rust-blockchain-cli getinfo [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli getblock <block> [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli gettx <txid> [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli mempool [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli peers [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli addpeer <addr> [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli send <to> <amount> [--fee=<fee>] [--from=<account>] [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli generate <blocks> [<address>] [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli stop [--node=<port>] [--rpc=<addr>] [--json]
+  rust-blockchain-cli (-h | --help)
+
+Options:
+  --node=<port>     P2p port of the node to talk to [default: 4000].
+  --rpc=<addr>      Rpc address, as host:port or a local port; the node's
+                    port plus rpc_port_offset if unset.
+  --fee=<fee>       Fee to pay; the node estimates one if unset.
+  --from=<account>  Account to pay from; the node's miner_account if unset.
+  --json            Print the node's json instead.
+  -h --help         Show this screen.
+";
+
+#[derive(Debug, Deserialize)]
+pub struct Args {
+    pub flag_node: u16,
+    pub flag_rpc: Option<String>,
+    pub flag_fee: Option<f32>,
+    pub flag_from: Option<char>,
+    pub flag_json: bool,
+    pub arg_block: Option<String>,
+    pub arg_txid: Option<String>,
+    pub arg_addr: Option<String>,
+    pub arg_to: Option<char>,
+    pub arg_amount: Option<i32>,
+    pub arg_blocks: Option<usize>,
+    pub arg_address: Option<char>,
+    pub cmd_getinfo: bool,
+    pub cmd_getblock: bool,
+    pub cmd_gettx: bool,
+    pub cmd_mempool: bool,
+    pub cmd_peers: bool,
+    pub cmd_addpeer: bool,
+    pub cmd_send: bool,
+    pub cmd_generate: bool,
+    pub cmd_stop: bool,
+}
+
+pub fn get() -> Args {
+    Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit())
+}
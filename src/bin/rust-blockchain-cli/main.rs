@@ -0,0 +1,80 @@
+#[macro_use]
+extern crate lazy_static;
+
+#[path = "../../config.rs"]
+mod config;
+#[path = "../../network/addr.rs"]
+mod addr;
+mod args;
+mod rpc;
+mod print;
+
+use serde_json::{json, Value};
+use args::Args;
+use rpc::Client;
+
+fn output(args: &Args, value: &Value, human: fn(&Value) -> String) {
+    if args.flag_json {
+        println!("{}", serde_json::to_string_pretty(value).unwrap());
+    } else {
+        print!("{}", human(value));
+    }
+}
+
+fn run(client: &Client, args: &Args) -> Result<(), String> {
+    match args {
+        Args { cmd_getinfo: true, .. } => {
+            let calls = [
+                ("getblockchaininfo", json!([])),
+                ("getmininginfo", json!([])),
+                ("getpeerinfo", json!([])),
+                ("getrawmempool", json!([])),
+            ];
+            let results = client.batch(&calls)?;
+            let info = json!({ "chain": results[0], "mining": results[1], "peers": results[2], "mempool": results[3] });
+            output(args, &info, print::info);
+        },
+        Args { cmd_getblock: true, arg_block: Some(block), .. } => {
+            // a height, or else a hash
+            let hash = match block.parse::<usize>() {
+                Ok(height) => client.call("getblockhash", json!([height]))?,
+                Err(_) => json!(block),
+            };
+            output(args, &client.call("getblock", json!([hash]))?, print::block);
+        },
+        Args { cmd_gettx: true, arg_txid: Some(id), .. } => {
+            output(args, &client.call("gettransaction", json!([id]))?, print::tx);
+        },
+        Args { cmd_mempool: true, .. } => {
+            output(args, &client.call("getrawmempool", json!([true]))?, print::mempool);
+        },
+        Args { cmd_peers: true, .. } => {
+            output(args, &client.call("getpeerinfo", json!([]))?, print::peers);
+        },
+        Args { cmd_addpeer: true, arg_addr: Some(addr), .. } => {
+            output(args, &client.call("addpeer", json!([addr]))?, print::added);
+        },
+        Args { cmd_send: true, .. } => {
+            let params = json!({ "address": args.arg_to, "amount": args.arg_amount, "fee": args.flag_fee, "from": args.flag_from });
+            output(args, &client.call("sendtoaddress", params)?, print::lines);
+        },
+        Args { cmd_generate: true, .. } => {
+            output(args, &client.call("generate", json!([args.arg_blocks, args.arg_address]))?, print::lines);
+        },
+        Args { cmd_stop: true, .. } => {
+            output(args, &client.call("stop", json!([]))?, print::lines);
+        },
+        _ => (),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = args::get();
+
+    let result = Client::new(args.flag_node, args.flag_rpc.as_deref()).and_then(|client| run(&client, &args));
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
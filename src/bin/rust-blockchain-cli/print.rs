@@ -0,0 +1,130 @@
+use std::fmt::Write;
+use serde_json::Value;
+
+// enough of a hash to tell it apart
+fn short(hash: &Value) -> &str {
+    let hash = hash.as_str().unwrap_or_default();
+    &hash[..hash.len().min(16)]
+}
+
+fn tx_line(tx: &Value) -> String {
+    format!("{} -> {} {} (fee {}, nonce {})", text(&tx["from"]), text(&tx["to"]), tx["amount"], tx["fee"], tx["nonce"])
+}
+
+// a json string without its quotes
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// `getinfo`: what `getblockchaininfo`, `getmininginfo`, `getpeerinfo` and
+/// `getrawmempool` said, under `chain`, `mining`, `peers` and `mempool`.
+pub fn info(info: &Value) -> String {
+    let (chain, mining) = (&info["chain"], &info["mining"]);
+    let mut out = String::new();
+    writeln!(out, "chain       {} ({}, {} at difficulty {})", text(&chain["chain"]), text(&chain["consensus"]), text(&chain["pow"]), chain["difficulty"]).unwrap();
+    writeln!(out, "blocks      {}", chain["blocks"]).unwrap();
+    writeln!(out, "best block  {}", short(&chain["bestblockhash"])).unwrap();
+    writeln!(out, "mempool     {} tx(s)", info["mempool"].as_array().map_or(0, Vec::len)).unwrap();
+    writeln!(out, "peers       {}", info["peers"].as_array().map_or(0, Vec::len)).unwrap();
+    writeln!(out, "mining      {:.0} H/s on {} thread(s)", mining["hashrate"].as_f64().unwrap_or(0.0), mining["threads"]).unwrap();
+    out
+}
+
+pub fn block(block: &Value) -> String {
+    let mut out = String::new();
+    writeln!(out, "block {} at height {} ({} confirmation(s))", text(&block["hash"]), block["height"], block["confirmations"]).unwrap();
+    if !text(&block["prev"]).is_empty() {
+        writeln!(out, "prev  {}", text(&block["prev"])).unwrap();
+    }
+    writeln!(out, "from  {}, nonce {}, {} ms", block["id"], block["nonce"], block["ms"]).unwrap();
+    if let Some(coinbase) = block.get("coinbase").filter(|c| !c.is_null()) {
+        writeln!(out, "pays  {} to {}", coinbase["amount"], text(&coinbase["to"])).unwrap();
+    }
+
+    let txs = block["txs"].as_array().cloned().unwrap_or_default();
+    writeln!(out, "txs   {}", txs.len()).unwrap();
+    for tx in &txs {
+        writeln!(out, "  {}", tx_line(tx)).unwrap();
+    }
+    out
+}
+
+pub fn tx(tx: &Value) -> String {
+    let mut out = String::new();
+    writeln!(out, "tx {}", text(&tx["id"])).unwrap();
+    writeln!(out, "  {}", tx_line(&tx["tx"])).unwrap();
+    match tx["confirmed"].as_bool() {
+        Some(true) => writeln!(out, "mined in block {} at height {} ({} confirmation(s))", short(&tx["block"]), tx["height"], tx["confirmations"]),
+        _ => writeln!(out, "pending for {}s", tx["age"]),
+    }.unwrap();
+    out
+}
+
+/// `getrawmempool` with verbose entries, best fee rate first.
+pub fn mempool(entries: &Value) -> String {
+    let mut entries: Vec<(&String, &Value)> = entries.as_object().map(|e| e.iter().collect()).unwrap_or_default();
+    entries.sort_by(|(_, a), (_, b)| b["feerate"].as_f64().partial_cmp(&a["feerate"].as_f64()).unwrap());
+
+    let mut out = String::new();
+    writeln!(out, "{} tx(s)", entries.len()).unwrap();
+    for (id, entry) in entries {
+        writeln!(out, "  {}  {}  {:.4}/byte", &id[..id.len().min(16)], tx_line(&entry["tx"]), entry["feerate"].as_f64().unwrap_or(0.0)).unwrap();
+    }
+    out
+}
+
+pub fn peers(peers: &Value) -> String {
+    match peers.as_array() {
+        Some(peers) if !peers.is_empty() => peers.iter().map(|peer| format!("{}\n", text(&peer["addr"]))).collect(),
+        _ => "no peers\n".to_string(),
+    }
+}
+
+pub fn added(added: &Value) -> String {
+    match added["added"].as_bool() {
+        Some(true) => format!("added peer {}\n", text(&added["addr"])),
+        _ => format!("already knew peer {}\n", text(&added["addr"])),
+    }
+}
+
+/// A bare string, or a list of them, one per line.
+pub fn lines(value: &Value) -> String {
+    match value {
+        Value::Array(items) => items.iter().map(|item| format!("{}\n", text(item))).collect(),
+        value => format!("{}\n", text(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::print::*;
+
+    #[test]
+    fn test_block() {
+        let printed = block(&json!({
+            "hash": "ab12", "prev": "", "id": 4000, "nonce": 26, "ms": 3, "height": 1, "confirmations": 2,
+            "coinbase": { "to": "Z", "amount": 10.5, "extra_nonce": 0 },
+            "txs": [{ "from": "A", "to": "B", "amount": 5, "fee": 0.5, "nonce": 0 }],
+        }));
+        assert_eq!(printed, "block ab12 at height 1 (2 confirmation(s))\n\
+                             from  4000, nonce 26, 3 ms\n\
+                             pays  10.5 to Z\n\
+                             txs   1\n  \
+                             A -> B 5 (fee 0.5, nonce 0)\n");
+    }
+
+    #[test]
+    fn test_tx_and_lists() {
+        let pending = json!({ "id": "cd34", "tx": { "from": "A", "to": "B", "amount": 5, "fee": 0.5, "nonce": 1 }, "confirmed": false, "age": 7 });
+        assert_eq!(tx(&pending), "tx cd34\n  A -> B 5 (fee 0.5, nonce 1)\npending for 7s\n");
+
+        assert_eq!(peers(&json!([])), "no peers\n");
+        assert_eq!(peers(&json!([{ "addr": "127.0.0.1:4001" }])), "127.0.0.1:4001\n");
+        assert_eq!(lines(&json!(["a", "b"])), "a\nb\n");
+        assert_eq!(lines(&json!("stopping")), "stopping\n");
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::{json, Value};
+use crate::config::*;
+use crate::addr::parse_addr;
+
+// how long a call may take, generous enough for `generate`
+const CALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Talks json-rpc over http to one node, one call per connection.
+pub struct Client {
+    addr: SocketAddr,
+    authorization: String,
+}
+
+/// `rpc_user` and `rpc_password` when both are set, otherwise the cookie
+/// the node on `node` wrote when it started.
+fn credentials(node: u16) -> Result<String, String> {
+    let user = SETTINGS.get::<String>("rpc_user").unwrap();
+    let password = SETTINGS.get::<String>("rpc_password").unwrap();
+    if !user.is_empty() && !password.is_empty() {
+        return Ok(format!("{}:{}", user, password));
+    }
+
+    let path = format!("{}/.cookie-{}", SETTINGS.get::<String>("data_dir").unwrap(), node);
+    fs::read_to_string(&path).map_err(|e| format!("could not read {}, is the node running? ({})", path, e))
+}
+
+/// Splits an http response into its status code and json body.
+fn parse_response(raw: &[u8]) -> Result<(u16, Value), String> {
+    let raw = String::from_utf8_lossy(raw);
+    let (head, body) = raw.split_once("\r\n\r\n").ok_or("no response from node")?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or("bad response from node")?;
+
+    match body.is_empty() {
+        true => Ok((status, Value::Null)),
+        false => serde_json::from_str(body).map(|body| (status, body)).map_err(|e| format!("bad json from node, {}", e)),
+    }
+}
+
+/// The result of one call, or its error as `message (code)`.
+fn result(response: &Value) -> Result<Value, String> {
+    match response.get("error") {
+        Some(error) if !error.is_null() => Err(format!("{} ({})", error["message"].as_str().unwrap_or_default(), error["code"])),
+        _ => Ok(response["result"].clone()),
+    }
+}
+
+impl Client {
+    /// A client for the node on p2p port `node`, reached at `rpc` if given.
+    pub fn new(node: u16, rpc: Option<&str>) -> Result<Self, String> {
+        let addr = match rpc {
+            Some(rpc) => parse_addr(rpc).ok_or_else(|| format!("could not parse rpc address {}", rpc))?,
+            None => {
+                let offset = SETTINGS.get::<u16>("rpc_port_offset").unwrap();
+                SocketAddr::from(([127, 0, 0, 1], node.saturating_add(offset)))
+            },
+        };
+        let authorization = format!("Basic {}", BASE64.encode(credentials(node)?));
+        Ok(Client { addr, authorization })
+    }
+
+    fn post(&self, body: &Value) -> Result<Value, String> {
+        let unreachable = |e: std::io::Error| format!("could not reach node at {}, {}", self.addr, e);
+        let mut stream = TcpStream::connect_timeout(&self.addr, CALL_TIMEOUT).map_err(unreachable)?;
+        stream.set_read_timeout(Some(CALL_TIMEOUT)).map_err(unreachable)?;
+
+        let body = body.to_string();
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: {}\r\nAuthorization: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.addr, self.authorization, body.len(), body,
+        );
+        stream.write_all(request.as_bytes()).map_err(unreachable)?;
+
+        // the node closes the connection once it has answered
+        let mut raw = vec![];
+        stream.read_to_end(&mut raw).map_err(unreachable)?;
+        match parse_response(&raw)? {
+            (200, body) => Ok(body),
+            (401, _) => Err("the node turned down our rpc credentials".to_string()),
+            (status, _) => Err(format!("the node answered with http {}", status)),
+        }
+    }
+
+    pub fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        let response = self.post(&json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 0 }))?;
+        result(&response)
+    }
+
+    /// Makes several calls in one request, returning their results in order.
+    pub fn batch(&self, calls: &[(&str, Value)]) -> Result<Vec<Value>, String> {
+        let requests: Vec<Value> = calls
+            .iter()
+            .enumerate()
+            .map(|(id, (method, params))| json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id }))
+            .collect();
+        let responses = self.post(&Value::Array(requests))?;
+
+        // responses can come back in any order
+        let mut results = vec![Value::Null; calls.len()];
+        for response in responses.as_array().ok_or("expected a batch response")? {
+            if let Some(slot) = response["id"].as_u64().and_then(|id| results.get_mut(id as usize)) {
+                *slot = result(response)?;
+            }
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rpc::*;
+
+    #[test]
+    fn test_parse_response() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 38\r\n\r\n{\"jsonrpc\":\"2.0\",\"result\":7,\"id\":0}";
+        let (status, body) = parse_response(raw).unwrap();
+        assert_eq!((status, result(&body)), (200, Ok(json!(7))));
+
+        let (status, body) = parse_response(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert_eq!((status, body), (401, Value::Null));
+        assert!(parse_response(b"").is_err());
+
+        let error = json!({ "jsonrpc": "2.0", "error": { "code": -5, "message": "block not found" }, "id": 0 });
+        assert_eq!(result(&error), Err("block not found (-5)".to_string()));
+    }
+}
@@ -0,0 +1,55 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The clock blocks are timed with. Regtest can stop it, so a block takes
+/// no time at all to mine and the same txs always give the same hashes.
+/// Clones share one clock; separate ones stop separately.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    // unix ms the clock is stopped at; 0 while it follows the system clock
+    mock_time: Arc<AtomicU64>,
+}
+
+impl Clock {
+    pub fn now(&self) -> SystemTime {
+        match self.mock_time.load(Ordering::Relaxed) {
+            0 => SystemTime::now(),
+            ms => UNIX_EPOCH + Duration::from_millis(ms),
+        }
+    }
+
+    /// Stops the clock at `unix_ms`, or restarts it with 0.
+    pub fn set_mock_time(&self, unix_ms: u64) {
+        self.mock_time.store(unix_ms, Ordering::Relaxed);
+    }
+
+    pub fn mock_time(&self) -> Option<u64> {
+        Some(self.mock_time.load(Ordering::Relaxed)).filter(|&ms| ms != 0)
+    }
+
+    /// Milliseconds from `since` until now.
+    pub fn elapsed_ms(&self, since: SystemTime) -> u64 {
+        self.now().duration_since(since).unwrap_or_default().as_millis().try_into().unwrap_or(u64::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::clock::*;
+
+    #[test]
+    fn test_mock_time() {
+        let clock = Clock::default();
+        let other = Clock::default();
+        clock.clone().set_mock_time(1_600_000_000_000);
+        assert_eq!(clock.mock_time(), Some(1_600_000_000_000));
+        assert_eq!(clock.elapsed_ms(clock.now()), 0);
+
+        // clocks only stop together when cloned from one another
+        assert_eq!(other.mock_time(), None);
+        clock.set_mock_time(0);
+        assert_eq!(clock.mock_time(), None);
+    }
+}
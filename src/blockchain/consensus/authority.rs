@@ -0,0 +1,201 @@
+use std::ops::Range;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime};
+use crate::blockchain::{Block, BlockTemplate, Clock, Pow, State};
+use super::{signed_block, slot_at, slot_follows, wait_out, Engine, ValidatorKey};
+
+/// Validators take turns in fixed length slots, in the order they're
+/// listed; a slot whose validator is offline just passes without a block.
+pub struct ProofOfAuthority {
+    validators: Vec<String>,
+    interval: Duration,
+    pow: Pow,
+    key: Option<ValidatorKey>,
+
+    // the last slot we sealed a block in, so we seal at most one per slot
+    sealed: Mutex<Option<u64>>,
+}
+
+impl ProofOfAuthority {
+    pub fn new(validators: Vec<String>, interval: Duration, pow: Pow, key: Option<ValidatorKey>) -> Self {
+        ProofOfAuthority {
+            validators,
+            interval,
+            pow,
+            key,
+            sealed: Mutex::new(None),
+        }
+    }
+
+    fn leader(&self, slot: u64) -> Option<&String> {
+        if self.validators.is_empty() {
+            return None;
+        }
+        self.validators.get((slot % self.validators.len() as u64) as usize)
+    }
+}
+
+impl Engine for ProofOfAuthority {
+    fn seal(
+        &self,
+        template: &BlockTemplate,
+        id: u16,
+        _nonces: Range<u64>,
+        clock: &Clock,
+        time: SystemTime,
+        abort: &AtomicBool,
+    ) -> Option<Block> {
+        let key = self.key.as_ref()?;
+        let slot = slot_at(self.interval, SystemTime::now());
+
+        let mut sealed = self.sealed.lock().unwrap();
+        if self.leader(slot) != Some(&key.public) || *sealed >= Some(slot) {
+            drop(sealed);
+            wait_out(self.interval, slot, abort);
+            return None;
+        }
+
+        *sealed = Some(slot);
+        Some(signed_block(template, id, clock.elapsed_ms(time), self.pow, slot, key))
+    }
+
+    /// Signed by the slot's validator, over the block's real hash, for the
+    /// height after `state`, in a slot after the parent's that has started
+    /// (give or take one, for clock drift) and, for a `live` block, hasn't
+    /// long ended.
+    fn verify(&self, block: &Block, parent: Option<&Block>, state: &State, live: bool) -> bool {
+        let seal = match &block.seal {
+            Some(seal) => seal,
+            None => return false,
+        };
+        if self.leader(seal.slot) != Some(&seal.validator) {
+            return false;
+        }
+        let parent = parent.and_then(|parent| parent.seal.as_ref());
+        if !slot_follows(self.interval, seal.slot, parent, live) || seal.height != state.height() + 1 {
+            return false;
+        }
+        block.generate_hash(self.pow) == block.hash && seal.verify(&block.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::consensus::authority::*;
+    use crate::blockchain::{Coinbase, BlockTemplate};
+    use crate::blockchain::consensus::slot_at;
+
+    fn template() -> BlockTemplate {
+        BlockTemplate {
+            prev: String::new(),
+            height: 1,
+            difficulty: 0,
+            pow: Pow::Sha256,
+            txs: vec![],
+            coinbase: Coinbase { to: 'Z', amount: 10.0, extra_nonce: 0 },
+            stakes: Default::default(),
+            seed: String::new(),
+            evidence: vec![],
+            fees: 0.0,
+            size: 0,
+        }
+    }
+
+    // a single validator always holds the slot
+    fn engine(key: &ValidatorKey) -> ProofOfAuthority {
+        ProofOfAuthority::new(vec![key.public.clone()], Duration::from_secs(60), Pow::Sha256, Some(key.clone()))
+    }
+
+    #[test]
+    fn test_seal_and_verify() {
+        let key = ValidatorKey::generate();
+        let engine = engine(&key);
+        let genesis = State::new(0.0);
+        let block = engine.seal(&template(), 1, 0..1, &Clock::default(), SystemTime::now(), &AtomicBool::new(false)).unwrap();
+        assert!(engine.verify(&block, None, &genesis, true));
+
+        // another node, without a key, agrees
+        let other = ProofOfAuthority::new(vec![key.public.clone()], Duration::from_secs(60), Pow::Sha256, None);
+        assert!(other.verify(&block, None, &genesis, true));
+
+        // tampering breaks the hash or signature
+        let mut tampered = block.clone();
+        tampered.coinbase.as_mut().unwrap().amount = 20.0;
+        assert!(!engine.verify(&tampered, None, &genesis, true));
+
+        let mut tampered = block.clone();
+        tampered.seal.as_mut().unwrap().slot -= 1;
+        assert!(!engine.verify(&tampered, None, &genesis, true));
+
+        // sealed for the wrong height
+        let mut next = State::new(0.0);
+        next.apply_block(&[]);
+        assert!(!engine.verify(&block, None, &next, true));
+
+        let mut unsealed = block;
+        unsealed.seal = None;
+        assert!(!engine.verify(&unsealed, None, &genesis, true));
+    }
+
+    #[test]
+    fn test_slots_only_move_forward() {
+        let key = ValidatorKey::generate();
+        let engine = engine(&key);
+        let now = slot_at(Duration::from_secs(60), SystemTime::now());
+        let sealed_in = |slot: u64, height: usize| {
+            let template = BlockTemplate { height, ..template() };
+            signed_block(&template, 1, 0, Pow::Sha256, slot, &key)
+        };
+        let genesis = State::new(0.0);
+        let mut next = State::new(0.0);
+        next.apply_block(&[]);
+
+        // the validator missed its slot an hour ago, and goes back for it
+        let backdated = sealed_in(now - 60, 1);
+        assert!(!engine.verify(&backdated, None, &genesis, true));
+        assert!(engine.verify(&backdated, None, &genesis, false));
+        assert!(engine.verify(&sealed_in(now - 1, 1), None, &genesis, true));
+
+        // nor can a block take its parent's slot, or an earlier one, even
+        // when synced
+        let parent = sealed_in(now - 10, 1);
+        assert!(!engine.verify(&sealed_in(now - 10, 2), Some(&parent), &next, false));
+        assert!(!engine.verify(&sealed_in(now - 11, 2), Some(&parent), &next, false));
+        assert!(engine.verify(&sealed_in(now - 9, 2), Some(&parent), &next, false));
+        assert!(engine.verify(&sealed_in(now, 2), Some(&parent), &next, true));
+
+        // nor one from the future
+        assert!(!engine.verify(&sealed_in(now + 2, 2), Some(&parent), &next, false));
+    }
+
+    #[test]
+    fn test_one_block_per_slot() {
+        let key = ValidatorKey::generate();
+        let engine = engine(&key);
+        let abort = AtomicBool::new(true);
+
+        assert!(engine.seal(&template(), 1, 0..1, &Clock::default(), SystemTime::now(), &abort).is_some());
+        assert!(engine.seal(&template(), 1, 0..1, &Clock::default(), SystemTime::now(), &abort).is_none());
+    }
+
+    #[test]
+    fn test_only_the_slot_leader_seals() {
+        let ours = ValidatorKey::generate();
+        let outsider = ValidatorKey::generate();
+        let abort = AtomicBool::new(true);
+        let genesis = State::new(0.0);
+
+        let engine = ProofOfAuthority::new(
+            vec![ours.public.clone()],
+            Duration::from_secs(60),
+            Pow::Sha256,
+            Some(outsider.clone()),
+        );
+        assert!(engine.seal(&template(), 1, 0..1, &Clock::default(), SystemTime::now(), &abort).is_none());
+
+        // signed by someone outside the set
+        let block = self::engine(&outsider).seal(&template(), 1, 0..1, &Clock::default(), SystemTime::now(), &abort).unwrap();
+        assert!(!engine.verify(&block, None, &genesis, true));
+    }
+}
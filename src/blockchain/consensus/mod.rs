@@ -0,0 +1,126 @@
+mod seal;
+mod authority;
+mod stake;
+mod vrf;
+
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime};
+use crate::blockchain::{mine, Block, BlockTemplate, ChainParams, Clock, Pow, State};
+
+use seal::{slot_at, slot_follows, wait_out};
+pub use seal::{Evidence, Seal, ValidatorKey};
+pub use authority::ProofOfAuthority;
+pub use stake::ProofOfStake;
+
+/// How a network agrees on who adds the next block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Consensus {
+    // whoever finds a hash under the difficulty first
+    Work,
+
+    // `validators` take turns, one block per `interval`
+    Authority { validators: Vec<String>, interval: Duration },
+
+    // one block per `interval` from a leader drawn by stake; `validators`
+    // start out with `genesis_stake` each
+    Stake { validators: Vec<String>, genesis_stake: f64, interval: Duration },
+}
+
+/// Produces and checks blocks under one consensus rule.
+pub trait Engine: Send + Sync {
+    /// Tries once to turn `template` into a block, timed by `clock` from
+    /// `time`. Proof of work hashes `nonces`; proof of authority and stake
+    /// wait for this node's slot. Either gives up early once `abort` is set.
+    fn seal(
+        &self,
+        template: &BlockTemplate,
+        id: u16,
+        nonces: Range<u64>,
+        clock: &Clock,
+        time: SystemTime,
+        abort: &AtomicBool,
+    ) -> Option<Block>;
+
+    /// Whether `block` is properly sealed to follow `parent`, the tip, with
+    /// `state` as of the tip. A `live` block, just sealed or relayed, also
+    /// has to be recent; a synced one is history.
+    fn verify(&self, block: &Block, parent: Option<&Block>, state: &State, live: bool) -> bool;
+}
+
+/// A block from `template` that took `ms` to seal, signed by `key` in `slot`.
+fn signed_block(template: &BlockTemplate, id: u16, ms: u64, pow: Pow, slot: u64, key: &ValidatorKey) -> Block {
+    let mut block = Block::new(
+        id,
+        template.prev.clone(),
+        template.txs.clone(),
+        0,
+        ms,
+    );
+    block.coinbase = Some(template.coinbase.clone());
+    block.evidence = template.evidence.clone();
+    block.hash = block.generate_hash(pow);
+    block.seal = Some(Seal::sign(&block.hash, slot, template.height, key));
+    block
+}
+
+pub struct ProofOfWork {
+    difficulty: usize,
+    pow: Pow,
+}
+
+impl Engine for ProofOfWork {
+    fn seal(
+        &self,
+        template: &BlockTemplate,
+        id: u16,
+        nonces: Range<u64>,
+        clock: &Clock,
+        time: SystemTime,
+        abort: &AtomicBool,
+    ) -> Option<Block> {
+        mine(template, id, nonces, clock, time, abort)
+    }
+
+    /// The block hashes to what it claims under the network's algorithm,
+    /// and that hash meets the difficulty.
+    fn verify(&self, block: &Block, _parent: Option<&Block>, _state: &State, _live: bool) -> bool {
+        block.hash.starts_with(&"0".repeat(self.difficulty)) && block.generate_hash(self.pow) == block.hash
+    }
+}
+
+/// The engine for `params`. Only a validator holding `key` can seal blocks
+/// under proof of authority or stake; without one it still verifies them.
+pub fn engine(params: &ChainParams, key: Option<ValidatorKey>) -> Box<dyn Engine> {
+    match &params.consensus {
+        Consensus::Work => Box::new(ProofOfWork { difficulty: params.difficulty, pow: params.pow }),
+        Consensus::Authority { validators, interval } => {
+            Box::new(ProofOfAuthority::new(validators.clone(), *interval, params.pow, key))
+        },
+        Consensus::Stake { interval, .. } => Box::new(ProofOfStake::new(*interval, params.pow, key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::consensus::*;
+
+    #[test]
+    fn test_proof_of_work_verify() {
+        let state = State::new(0.0);
+        let engine = ProofOfWork { difficulty: 1, pow: Pow::Blake3 };
+
+        let mut block = Block::new(1, String::new(), vec![], 0, 0);
+        while !block.generate_hash(Pow::Blake3).starts_with('0') {
+            block.nonce += 1;
+        }
+        block.hash = block.generate_hash(Pow::Blake3);
+        assert!(engine.verify(&block, None, &state, true));
+
+        // right hash, wrong algorithm
+        assert!(!ProofOfWork { difficulty: 1, pow: Pow::Sha256d }.verify(&block, None, &state, true));
+
+        block.nonce += 1;
+        assert!(!engine.verify(&block, None, &state, true));
+    }
+}
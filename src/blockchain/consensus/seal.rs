@@ -0,0 +1,215 @@
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::convert::TryInto;
+use ed25519_compact::{KeyPair, PublicKey, Seed, Signature};
+use serde::{Serialize, Deserialize};
+use super::vrf;
+
+// how often a validator waiting for its slot checks whether to give up
+const POLL: Duration = Duration::from_millis(50);
+
+/// A validator's signature over a block, made in its slot.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Seal {
+    pub slot: u64,
+
+    // the block's position in the chain, so signing two blocks for the
+    // same position can be proven
+    #[serde(default)]
+    pub height: usize,
+
+    pub validator: String,
+    pub signature: String,
+
+    // proof of stake's vrf proof over the parent's seed and the slot,
+    // which the next leader is drawn from
+    #[serde(default)]
+    pub proof: String,
+}
+
+fn message(hash: &str, slot: u64, height: usize) -> Vec<u8> {
+    let mut message = hash.as_bytes().to_vec();
+    message.extend_from_slice(&slot.to_le_bytes());
+    message.extend_from_slice(&(height as u64).to_le_bytes());
+    message
+}
+
+impl Seal {
+    pub fn sign(hash: &str, slot: u64, height: usize, key: &ValidatorKey) -> Self {
+        let signature = key.keypair().sk.sign(message(hash, slot, height), None);
+        Seal {
+            slot,
+            height,
+            validator: key.public.clone(),
+            signature: hex::encode(*signature),
+            proof: String::new(),
+        }
+    }
+
+    /// Whether the validator really signed `hash` with this seal.
+    pub fn verify(&self, hash: &str) -> bool {
+        let key = hex::decode(&self.validator).ok().and_then(|k| PublicKey::from_slice(&k).ok());
+        let signature = hex::decode(&self.signature).ok().and_then(|s| Signature::from_slice(&s).ok());
+        match (key, signature) {
+            (Some(key), Some(signature)) => key.verify(message(hash, self.slot, self.height), &signature).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// The randomness this seal leaves for the next block: its vrf output,
+    /// or nothing without a proof.
+    pub fn seed(&self) -> String {
+        hex::decode(&self.proof).ok().and_then(|proof| vrf::output(&proof)).map(hex::encode).unwrap_or_default()
+    }
+
+    /// Whether `proof` is the validator's one vrf proof over `alpha`.
+    pub fn verify_proof(&self, alpha: &[u8]) -> bool {
+        match (hex::decode(&self.validator), hex::decode(&self.proof)) {
+            (Ok(key), Ok(proof)) => vrf::verify(&key, alpha, &proof).is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Proof that a validator signed two different blocks at the same height,
+/// which gets its stake slashed once a block includes it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Evidence {
+    pub first: (String, Seal),
+    pub second: (String, Seal),
+}
+
+impl Evidence {
+    pub fn validator(&self) -> &str {
+        &self.first.1.validator
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let (a, a_seal) = &self.first;
+        let (b, b_seal) = &self.second;
+        a != b
+            && a_seal.validator == b_seal.validator
+            && a_seal.height == b_seal.height
+            && a_seal.verify(a)
+            && b_seal.verify(b)
+    }
+}
+
+/// The ed25519 key a validator signs blocks with.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ValidatorKey {
+    pub private: String,
+    pub public: String,
+}
+
+impl ValidatorKey {
+    pub fn generate() -> Self {
+        let seed = Seed::generate();
+        let keypair = KeyPair::from_seed(seed);
+
+        ValidatorKey {
+            private: hex::encode(*seed),
+            public: hex::encode(*keypair.pk),
+        }
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let text = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, text)
+    }
+
+    /// This key's vrf proof over `alpha`.
+    pub fn prove(&self, alpha: &[u8]) -> String {
+        let seed = hex::decode(&self.private).expect("invalid validator key");
+        hex::encode(vrf::prove(&seed.try_into().expect("invalid validator key"), alpha))
+    }
+
+    fn keypair(&self) -> KeyPair {
+        let seed = hex::decode(&self.private).expect("invalid validator key");
+        KeyPair::from_seed(Seed::from_slice(&seed).expect("invalid validator key"))
+    }
+}
+
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Slots of `interval` counted from the unix epoch.
+pub fn slot_at(interval: Duration, time: SystemTime) -> u64 {
+    (since_epoch(time).as_millis() / interval.as_millis().max(1)) as u64
+}
+
+/// Whether a block sealed in `slot` can follow its parent's `seal`, if the
+/// parent has one. Slots only move forward, and never past the current one
+/// give or take one for clock drift. A `live` block can't be more than a
+/// slot late either, or a validator could go back and take an old slot that
+/// happens to be its own.
+pub fn slot_follows(interval: Duration, slot: u64, parent: Option<&Seal>, live: bool) -> bool {
+    let now = slot_at(interval, SystemTime::now());
+    parent.is_none_or(|parent| slot > parent.slot) && slot <= now + 1 && (!live || slot + 1 >= now)
+}
+
+/// Sleeps until `slot` is over, or until the work goes stale.
+pub fn wait_out(interval: Duration, slot: u64, abort: &AtomicBool) {
+    let end = interval * (slot + 1).try_into().unwrap_or(u32::MAX);
+    while !abort.load(Ordering::Relaxed) {
+        let now = since_epoch(SystemTime::now());
+        if now >= end {
+            break;
+        }
+        thread::sleep(POLL.min(end - now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::consensus::seal::*;
+
+    #[test]
+    fn test_key_save_and_load() {
+        let key = ValidatorKey::generate();
+        let path = std::env::temp_dir().join(format!("validator-{}.key", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        key.save(path).unwrap();
+        assert_eq!(ValidatorKey::load(path).unwrap(), key);
+        assert_eq!(hex::encode(*key.keypair().pk), key.public);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_evidence() {
+        let key = ValidatorKey::generate();
+        let a = ("a".to_string(), Seal::sign("a", 1, 5, &key));
+        let b = ("b".to_string(), Seal::sign("b", 2, 5, &key));
+        assert!(Evidence { first: a.clone(), second: b.clone() }.is_valid());
+
+        // the same block twice proves nothing
+        assert!(!Evidence { first: a.clone(), second: a.clone() }.is_valid());
+
+        // neither do different heights
+        let c = ("c".to_string(), Seal::sign("c", 2, 6, &key));
+        assert!(!Evidence { first: a.clone(), second: c }.is_valid());
+
+        // nor someone else's signature
+        let other = ValidatorKey::generate();
+        let d = ("d".to_string(), Seal::sign("d", 2, 5, &other));
+        assert!(!Evidence { first: a.clone(), second: d }.is_valid());
+
+        // nor a forged one
+        let mut forged = b;
+        forged.0 = "e".to_string();
+        assert!(!Evidence { first: a, second: forged }.is_valid());
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime};
+use sha2::{Sha256, Digest};
+use crate::blockchain::{Block, BlockTemplate, Clock, Pow, State};
+use super::{signed_block, slot_at, slot_follows, wait_out, Engine, Seal, ValidatorKey};
+
+/// Picks the leader of `slot` after a block whose seal left `seed`. The
+/// seed and slot hash to a point in the total stake; whoever's share covers
+/// it leads, so the odds follow stake.
+pub fn leader<'a>(stakes: &'a BTreeMap<String, f64>, seed: &str, slot: u64) -> Option<&'a String> {
+    let bonded = || stakes.iter().filter(|(_, &stake)| stake > 0.0);
+    let total: f64 = bonded().map(|(_, stake)| stake).sum();
+    if total <= 0.0 {
+        return None;
+    }
+
+    let mut hasher = Sha256::default();
+    hasher.update(seed);
+    hasher.update(slot.to_le_bytes());
+    let seed = u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap());
+
+    let mut point = seed as f64 / (u64::MAX as f64 + 1.0) * total;
+    for (validator, &stake) in bonded() {
+        if point < stake {
+            return Some(validator);
+        }
+        point -= stake;
+    }
+
+    // rounding can leave the point just past the last share
+    bonded().next_back().map(|(validator, _)| validator)
+}
+
+// what a leader's vrf proof covers: the seed it was picked with, and the slot
+fn alpha(seed: &str, slot: u64) -> Vec<u8> {
+    let mut alpha = seed.as_bytes().to_vec();
+    alpha.extend_from_slice(&slot.to_le_bytes());
+    alpha
+}
+
+/// A block from `template` that took `ms` to seal, signed by `key` in `slot`
+/// with the vrf proof that seeds the next leader.
+fn staked_block(template: &BlockTemplate, id: u16, ms: u64, pow: Pow, slot: u64, key: &ValidatorKey) -> Block {
+    let mut block = signed_block(template, id, ms, pow, slot, key);
+    block.seal.as_mut().unwrap().proof = key.prove(&alpha(&template.seed, slot));
+    block
+}
+
+/// Validators lock up coins with stake txs, and each slot's leader is drawn
+/// by stake from the seed the previous block's seal left. That seed is the
+/// previous leader's vrf output, which it can withhold but not choose. A
+/// slot whose leader is offline passes without a block.
+pub struct ProofOfStake {
+    interval: Duration,
+    pow: Pow,
+    key: Option<ValidatorKey>,
+
+    // the last slot we sealed a block in, so we seal at most one per slot
+    sealed: Mutex<Option<u64>>,
+}
+
+impl ProofOfStake {
+    pub fn new(interval: Duration, pow: Pow, key: Option<ValidatorKey>) -> Self {
+        ProofOfStake {
+            interval,
+            pow,
+            key,
+            sealed: Mutex::new(None),
+        }
+    }
+}
+
+impl Engine for ProofOfStake {
+    fn seal(
+        &self,
+        template: &BlockTemplate,
+        id: u16,
+        _nonces: Range<u64>,
+        clock: &Clock,
+        time: SystemTime,
+        abort: &AtomicBool,
+    ) -> Option<Block> {
+        let key = self.key.as_ref()?;
+        let slot = slot_at(self.interval, SystemTime::now());
+
+        let mut sealed = self.sealed.lock().unwrap();
+        if leader(&template.stakes, &template.seed, slot) != Some(&key.public) || *sealed >= Some(slot) {
+            drop(sealed);
+            wait_out(self.interval, slot, abort);
+            return None;
+        }
+
+        *sealed = Some(slot);
+        Some(staked_block(template, id, clock.elapsed_ms(time), self.pow, slot, key))
+    }
+
+    /// Signed by the leader the stakes in `state` and the parent's seed pick
+    /// for the seal's slot, over the block's real hash, for the height after
+    /// `state`, with the leader's vrf proof for the next seed. The slot is
+    /// after the parent's, has started and, for a `live` block, hasn't long
+    /// ended, as under proof of authority.
+    fn verify(&self, block: &Block, parent: Option<&Block>, state: &State, live: bool) -> bool {
+        let seal = match &block.seal {
+            Some(seal) => seal,
+            None => return false,
+        };
+        let parent = parent.and_then(|parent| parent.seal.as_ref());
+        let seed = parent.map(Seal::seed).unwrap_or_default();
+        if leader(state.stakes(), &seed, seal.slot) != Some(&seal.validator) {
+            return false;
+        }
+        if !slot_follows(self.interval, seal.slot, parent, live) || seal.height != state.height() + 1 {
+            return false;
+        }
+        seal.verify_proof(&alpha(&seed, seal.slot))
+            && block.generate_hash(self.pow) == block.hash
+            && seal.verify(&block.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::consensus::stake::*;
+    use crate::blockchain::Coinbase;
+
+    fn stakes(shares: &[(&str, f64)]) -> BTreeMap<String, f64> {
+        shares.iter().map(|&(v, s)| (v.to_string(), s)).collect()
+    }
+
+    fn template(state: &State, seed: &str) -> BlockTemplate {
+        BlockTemplate {
+            prev: String::new(),
+            height: state.height() + 1,
+            difficulty: 0,
+            pow: Pow::Sha256,
+            txs: vec![],
+            coinbase: Coinbase { to: 'Z', amount: 10.0, extra_nonce: 0 },
+            stakes: state.stakes().clone(),
+            seed: seed.to_string(),
+            evidence: vec![],
+            fees: 0.0,
+            size: 0,
+        }
+    }
+
+    #[test]
+    fn test_leader_follows_stake() {
+        let stakes = stakes(&[("a", 1.0), ("b", 3.0), ("c", 0.0)]);
+        let led = (0..4000).filter(|&slot| leader(&stakes, "seed", slot).unwrap() == "b").count();
+        assert!((2800..3200).contains(&led), "b led {} of 4000 slots", led);
+
+        // no stake, no slots
+        assert!((0..1000).all(|slot| leader(&stakes, "seed", slot).unwrap() != "c"));
+        assert_eq!(leader(&BTreeMap::new(), "seed", 0), None);
+
+        // the same seed always picks the same leader
+        assert_eq!(leader(&stakes, "seed", 7), leader(&stakes, "seed", 7));
+    }
+
+    #[test]
+    fn test_seal_and_verify() {
+        let key = ValidatorKey::generate();
+        let engine = ProofOfStake::new(Duration::from_secs(60), Pow::Sha256, Some(key.clone()));
+        let mut genesis = State::new(0.0);
+        genesis.bond(&key.public, 10.0);
+
+        let template = template(&genesis, "");
+        let abort = AtomicBool::new(true);
+        let block = engine.seal(&template, 1, 0..1, &Clock::default(), SystemTime::now(), &abort).unwrap();
+        assert!(engine.verify(&block, None, &genesis, true));
+        assert!(engine.seal(&template, 1, 0..1, &Clock::default(), SystemTime::now(), &abort).is_none());
+
+        // once slashed, the validator no longer leads
+        let mut slashed = genesis.clone();
+        slashed.slash(&key.public);
+        assert!(!engine.verify(&block, None, &slashed, true));
+
+        let mut tampered = block;
+        tampered.coinbase.as_mut().unwrap().amount = 20.0;
+        assert!(!engine.verify(&tampered, None, &genesis, true));
+    }
+
+    #[test]
+    fn test_slots_only_move_forward() {
+        let key = ValidatorKey::generate();
+        let engine = ProofOfStake::new(Duration::from_secs(60), Pow::Sha256, None);
+        let now = slot_at(Duration::from_secs(60), SystemTime::now());
+        let mut genesis = State::new(0.0);
+        genesis.bond(&key.public, 10.0);
+        let mut next = genesis.clone();
+        next.apply_block(&[]);
+
+        let parent = staked_block(&template(&genesis, ""), 1, 0, Pow::Sha256, now - 10, &key);
+        let seed = parent.seal.as_ref().unwrap().seed();
+        let sealed_in = |slot: u64| staked_block(&template(&next, &seed), 1, 0, Pow::Sha256, slot, &key);
+
+        // the leader missed its slot an hour ago, and goes back for it
+        let backdated = staked_block(&template(&genesis, ""), 1, 0, Pow::Sha256, now - 60, &key);
+        assert!(!engine.verify(&backdated, None, &genesis, true));
+        assert!(engine.verify(&backdated, None, &genesis, false));
+
+        // nor can a block take its parent's slot, an earlier one, or one
+        // from the future
+        assert!(!engine.verify(&sealed_in(now - 10), Some(&parent), &next, false));
+        assert!(!engine.verify(&sealed_in(now - 11), Some(&parent), &next, false));
+        assert!(!engine.verify(&sealed_in(now + 2), Some(&parent), &next, false));
+        assert!(engine.verify(&sealed_in(now - 9), Some(&parent), &next, false));
+        assert!(engine.verify(&sealed_in(now), Some(&parent), &next, true));
+    }
+
+    #[test]
+    fn test_seed_comes_from_the_parent_proof() {
+        let key = ValidatorKey::generate();
+        let engine = ProofOfStake::new(Duration::from_secs(60), Pow::Sha256, None);
+        let now = slot_at(Duration::from_secs(60), SystemTime::now());
+        let mut genesis = State::new(0.0);
+        genesis.bond(&key.public, 10.0);
+        let mut next = genesis.clone();
+        next.apply_block(&[]);
+
+        let parent = staked_block(&template(&genesis, ""), 1, 0, Pow::Sha256, now - 1, &key);
+        let seed = parent.seal.as_ref().unwrap().seed();
+        assert_eq!(seed.len(), 128);
+        let block = staked_block(&template(&next, &seed), 1, 0, Pow::Sha256, now, &key);
+        assert!(engine.verify(&block, Some(&parent), &next, true));
+
+        // a proof over any other seed doesn't count, nor does none at all
+        let other = staked_block(&template(&next, "grind"), 1, 0, Pow::Sha256, now, &key);
+        assert!(!engine.verify(&other, Some(&parent), &next, true));
+        let mut unproven = block.clone();
+        unproven.seal.as_mut().unwrap().proof = String::new();
+        assert!(!engine.verify(&unproven, Some(&parent), &next, true));
+
+        // only the leader's own key proves its output
+        let stranger = ValidatorKey::generate();
+        let mut borrowed = block;
+        borrowed.seal.as_mut().unwrap().proof = stranger.prove(&alpha(&seed, now));
+        assert!(!engine.verify(&borrowed, Some(&parent), &next, true));
+    }
+}
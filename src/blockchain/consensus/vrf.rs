@@ -0,0 +1,145 @@
+use std::convert::TryInto;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::{clamp_integer, Scalar};
+use curve25519_dalek::traits::IsIdentity;
+use sha2::{Digest, Sha512};
+
+// ECVRF-EDWARDS25519-SHA512-TAI from RFC 9381, on the validator's ed25519
+// key. Unlike a signature, there is exactly one valid proof per key and
+// input, so whoever proves can't pick among outputs.
+const SUITE: u8 = 0x03;
+const PROOF_LEN: usize = 80;
+
+fn sha512(parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// hashes `alpha` to a point in the prime order subgroup, trying counters
+// until the hash decodes
+fn hash_to_curve(public: &[u8; 32], alpha: &[u8]) -> EdwardsPoint {
+    (0..=u8::MAX)
+        .find_map(|ctr| {
+            let hash = sha512(&[&[SUITE, 0x01], public, alpha, &[ctr, 0x00]]);
+            let point = CompressedEdwardsY(hash[..32].try_into().unwrap()).decompress()?.mul_by_cofactor();
+            Some(point).filter(|point| !point.is_identity())
+        })
+        .expect("no curve point in 256 tries")
+}
+
+fn challenge(points: [&EdwardsPoint; 5]) -> Scalar {
+    let mut hasher = Sha512::new();
+    hasher.update([SUITE, 0x02]);
+    for point in points {
+        hasher.update(point.compress().as_bytes());
+    }
+    hasher.update([0x00]);
+
+    let mut c = [0; 32];
+    c[..16].copy_from_slice(&hasher.finalize()[..16]);
+    Scalar::from_bytes_mod_order(c)
+}
+
+/// The proof that `alpha` hashes to this key's one output, from the key's
+/// ed25519 `seed`.
+pub fn prove(seed: &[u8; 32], alpha: &[u8]) -> Vec<u8> {
+    // the same secret scalar and nonce prefix ed25519 signing uses
+    let expanded = sha512(&[seed]);
+    let x = Scalar::from_bytes_mod_order(clamp_integer(expanded[..32].try_into().unwrap()));
+    let y = EdwardsPoint::mul_base(&x);
+    let public = y.compress().to_bytes();
+
+    let h = hash_to_curve(&public, alpha);
+    let gamma = h * x;
+    let k = Scalar::from_bytes_mod_order_wide(&sha512(&[&expanded[32..], h.compress().as_bytes()]));
+    let c = challenge([&y, &h, &gamma, &EdwardsPoint::mul_base(&k), &(h * k)]);
+    let s = k + c * x;
+
+    let mut proof = gamma.compress().to_bytes().to_vec();
+    proof.extend_from_slice(&c.as_bytes()[..16]);
+    proof.extend_from_slice(s.as_bytes());
+    proof
+}
+
+/// The output `proof` commits to, whether or not it's valid.
+pub fn output(proof: &[u8]) -> Option<[u8; 64]> {
+    if proof.len() != PROOF_LEN {
+        return None;
+    }
+    let gamma = CompressedEdwardsY(proof[..32].try_into().unwrap()).decompress()?;
+    Some(sha512(&[&[SUITE, 0x03], gamma.mul_by_cofactor().compress().as_bytes(), &[0x00]]))
+}
+
+/// The output, if `proof` shows the holder of `public` hashed `alpha`.
+pub fn verify(public: &[u8], alpha: &[u8], proof: &[u8]) -> Option<[u8; 64]> {
+    let public: [u8; 32] = public.try_into().ok()?;
+    let y = CompressedEdwardsY(public).decompress().filter(|y| !y.is_small_order())?;
+    if proof.len() != PROOF_LEN {
+        return None;
+    }
+    let gamma = CompressedEdwardsY(proof[..32].try_into().unwrap()).decompress()?;
+    let mut c = [0; 32];
+    c[..16].copy_from_slice(&proof[32..48]);
+    let c = Scalar::from_bytes_mod_order(c);
+    let s = Option::<Scalar>::from(Scalar::from_canonical_bytes(proof[48..].try_into().unwrap()))?;
+
+    let h = hash_to_curve(&public, alpha);
+    let u = EdwardsPoint::mul_base(&s) - y * c;
+    let v = h * s - gamma * c;
+    match challenge([&y, &h, &gamma, &u, &v]) == c {
+        true => output(proof),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::consensus::vrf::*;
+
+    fn bytes(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    #[test]
+    fn test_rfc_9381_vector() {
+        let seed = bytes("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        let public = bytes("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let proof = prove(&seed.try_into().unwrap(), b"");
+        assert_eq!(
+            hex::encode(&proof),
+            "8657106690b5526245a92b003bb079ccd1a92130477671f6fc01ad16f26f723f\
+             26f8a57ccaed74ee1b190bed1f479d9727d2d0f9b005a6e456a35d4fb0daab12\
+             68a1b0db10836d9826a528ca76567805"
+        );
+        assert_eq!(
+            hex::encode(verify(&public, b"", &proof).unwrap()),
+            "90cf1df3b703cce59e2a35b925d411164068269d7b2d29f3301c03dd757876ff\
+             66b71dda49d2de59d03450451af026798e8f81cd2e333de5cdf4f3e140fdd8ae"
+        );
+    }
+
+    #[test]
+    fn test_prove_and_verify() {
+        let seed = [7; 32];
+        let public = ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new(seed)).pk;
+        let proof = prove(&seed, b"alpha");
+        let beta = verify(public.as_ref(), b"alpha", &proof).unwrap();
+        assert_eq!(output(&proof), Some(beta));
+
+        // one output per input
+        assert_eq!(prove(&seed, b"alpha"), proof);
+        assert_ne!(verify(public.as_ref(), b"other", &prove(&seed, b"other")), Some(beta));
+
+        // not for another input, another key or a changed proof
+        assert_eq!(verify(public.as_ref(), b"other", &proof), None);
+        let other = ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new([8; 32])).pk;
+        assert_eq!(verify(other.as_ref(), b"alpha", &proof), None);
+        let mut tampered = proof.clone();
+        tampered[40] ^= 1;
+        assert_eq!(verify(public.as_ref(), b"alpha", &tampered), None);
+        assert_eq!(verify(public.as_ref(), b"alpha", &proof[..79]), None);
+    }
+}
@@ -1,86 +1,219 @@
+mod state;
+mod template;
+mod pow;
+mod params;
+mod consensus;
+mod clock;
+
 use std::time::SystemTime;
 use rayon::prelude::*;
 use crate::types::*;
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
-use hex;
 use crate::config::*;
-use std::convert::TryInto;
+use crate::events::{Event, Events};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub use state::{State, TxError};
+pub use template::{BlockTemplate, Head, TemplateBuilder};
+pub use pow::{Pow, PowAlgorithm};
+pub use params::ChainParams;
+pub use consensus::{engine, Consensus, Engine, Evidence, Seal, ValidatorKey};
+pub use clock::Clock;
+
+// how many heights back signed blocks are remembered, to catch double signing
+const EVIDENCE_WINDOW: usize = 100;
+
+// how far below the tip blocks on other branches are kept, in case theirs
+// overtakes ours
+const MAX_REORG_DEPTH: usize = 100;
 
 pub struct Blockchain {
     chain: Vec<Block>,
-    min_tx_per_block: usize,
-    difficulty: usize,
-    concurrent_hashes: u64,
+    state: State,
+
+    // every block's hash, on the chain or off it, to spot the ones we
+    // already have
+    hashes: HashSet<String>,
+
+    // blocks on other branches, with their heights
+    side: HashMap<String, (Block, usize)>,
+
+    params: ChainParams,
+
+    // checks blocks from peers; it holds no key, so it never seals any
+    engine: Box<dyn Engine>,
+
+    // the first block each validator signed at each recent height
+    signed: HashMap<(usize, String), (String, Seal)>,
+
+    // double signing caught but not yet in a block
+    evidence: Vec<Evidence>,
+
+    events: Events,
+
+    // what the node times blocks by; regtest can stop it
+    clock: Clock,
 }
 
 impl Blockchain {
-    pub fn new(
-        min_tx_per_block: usize,
-        difficulty: usize,
-        concurrent_hashes: u64,
-    ) -> Self {
+    pub fn new(params: ChainParams) -> Self {
         Blockchain {
             chain: vec![],
-            min_tx_per_block,
-            difficulty,
-            concurrent_hashes,
+            state: genesis(&params),
+            hashes: HashSet::new(),
+            side: HashMap::new(),
+            engine: engine(&params, None),
+            params,
+            signed: HashMap::new(),
+            evidence: vec![],
+            events: Events::default(),
+            clock: Clock::default(),
         }
     }
 
+    /// Reports new blocks to `events`.
+    pub fn set_events(&mut self, events: Events) {
+        self.events = events;
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
     pub fn get_all(&self) -> &Vec<Block> {
         &self.chain
     }
 
-    pub fn get_concurrent_hashes(&self) -> u64 {
-        self.concurrent_hashes
+    /// Balances and nonces after the last block.
+    pub fn state(&self) -> &State {
+        &self.state
     }
 
-    pub fn ok_to_mine(&self, txs: &[Tx]) -> bool {
-        txs.len() >= self.min_tx_per_block
+    pub fn params(&self) -> &ChainParams {
+        &self.params
     }
 
-    pub fn mine(
-        &self,
-        id: u16,
-        nonce: u64,
-        time: SystemTime,
-        txs: Vec<Tx>,
-    ) -> Option<Block> {
-        let target = "0".repeat(self.difficulty);
+    /// Hash of the last block, which the next one builds on.
+    pub fn tip(&self) -> String {
+        match self.chain.len() {
+            0 => String::new(),
+            _ => self.chain[self.chain.len() - 1].hash.clone(),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(hash)
+    }
 
-        let mut nonces = vec![];
-        for i in 0..self.concurrent_hashes {
-            nonces.push(nonce + i);
+    /// What the next block builds on.
+    pub fn head(&self) -> Head {
+        Head {
+            hash: self.tip(),
+            height: self.state.height(),
+            stakes: self.state.stakes().clone(),
+            seed: self.chain.last().and_then(|block| block.seal.as_ref()).map(Seal::seed).unwrap_or_default(),
+            evidence: self.evidence.clone(),
         }
+    }
 
-        let prev = match self.chain.len() {
-            0 => String::new(),
-            _ => self.chain[self.chain.len() - 1].hash.clone(),
+    /// Whether `block` builds on the tip and is properly sealed to follow it,
+    /// `live` meaning it was just sealed or relayed rather than synced. A
+    /// block on another branch only gets its hash and signature checked,
+    /// since the state it builds on isn't at hand; the rest waits until its
+    /// branch is worth switching to. Signed blocks are remembered even when
+    /// they don't fit, since a second one at the same height from the same
+    /// validator is evidence of double signing.
+    pub fn verify(&mut self, block: &Block, live: bool) -> bool {
+        if let Some(seal) = &block.seal {
+            if seal.verify(&block.hash) {
+                self.witness(&block.hash, seal);
+            }
+        }
+        if block.prev == self.tip() {
+            return self.engine.verify(block, self.chain.last(), &self.state, live);
+        }
+
+        let height = match self.height_of(&block.prev) {
+            Some(height) => height + 1,
+            None => return false,
         };
+        match (&self.params.consensus, &block.seal) {
+            (Consensus::Work, _) => self.engine.verify(block, None, &self.state, false),
+            (_, Some(seal)) => seal.height == height
+                && seal.verify(&block.hash)
+                && block.generate_hash(self.params.pow) == block.hash,
+            (_, None) => false,
+        }
+    }
 
-        nonces
-            .par_iter()
-            .find_map_any(move |&nonce| {
-                let mut block = Block::new(
-                    id,
-                    prev.clone(),
-                    txs.clone(),
-                    nonce,
-                    time.elapsed().unwrap().as_millis().try_into().unwrap(),
-                );
-
-                let hash = block.generate_hash();
-                if hash.starts_with(&target) {
-                    block.hash = hash;
-                    return Some(block);
+    /// How many blocks lead up to and include `hash`, on the chain or off it.
+    fn height_of(&self, hash: &str) -> Option<usize> {
+        if hash.is_empty() {
+            return Some(0);
+        }
+        if let Some((_, height)) = self.side.get(hash) {
+            return Some(*height);
+        }
+        self.chain.iter().rposition(|block| block.hash == hash).map(|i| i + 1)
+    }
+
+    /// Whether a branch ending in `tip`, `height` blocks long, beats ours.
+    /// Every block is mined at the same difficulty, so under proof of work
+    /// the branch with the most work is the longest, and on a tie the one
+    /// seen first stays. Under proof of authority or stake, the longest
+    /// branch filled the most slots; on a tie, the tip sealed in the earlier
+    /// slot wins, so every node settles on the same one.
+    fn beats_tip(&self, tip: &Block, height: usize) -> bool {
+        let ours = self.state.height();
+        if height != ours || self.params.consensus == Consensus::Work {
+            return height > ours;
+        }
+        let slot = |block: Option<&Block>| block.and_then(|b| b.seal.as_ref()).map(|seal| seal.slot);
+        slot(Some(tip)) < slot(self.chain.last())
+    }
+
+    fn witness(&mut self, hash: &str, seal: &Seal) {
+        let height = self.state.height();
+        self.signed.retain(|(h, _), _| h + EVIDENCE_WINDOW > height);
+
+        let key = (seal.height, seal.validator.clone());
+        match self.signed.get(&key) {
+            Some(first) if first.0 != hash => {
+                let evidence = Evidence { first: first.clone(), second: (hash.to_string(), seal.clone()) };
+                if self.state.stake(&seal.validator) > 0.0
+                    && !self.evidence.iter().any(|e| e.validator() == seal.validator)
+                {
+                    println!("validator {} signed two blocks at height {}", &seal.validator[..8], seal.height);
+                    self.evidence.push(evidence);
                 }
+            },
+            Some(_) => (),
+            None => {
+                self.signed.insert(key, (hash.to_string(), seal.clone()));
+            },
+        }
+    }
 
-                None
-            })
+    /// Adds a block checked by `verify`. One building on the tip extends
+    /// the chain; one on another branch is kept aside, and once its branch
+    /// beats ours the chain switches over to it, which is returned.
+    pub fn add(&mut self, block: Block) -> Option<Reorg> {
+        if block.prev != self.tip() {
+            return self.add_side(block);
+        }
+        self.hashes.insert(block.hash.clone());
+        self.extend(block);
+        None
     }
 
-    pub fn add(&mut self, block: Block) {
+    fn extend(&mut self, block: Block) {
         let prev_s = if !block.prev.is_empty() {
             format!(" (prev {})", &block.prev[..8])
         } else {
@@ -101,8 +234,226 @@ impl Blockchain {
             time_s,
         );
 
+        apply(&mut self.state, &block, &self.params, true);
+        let state = &self.state;
+        self.evidence.retain(|e| state.stake(e.validator()) > 0.0);
+
+        self.emit_new_block(&block, self.state.height());
         self.chain.push(block);
     }
+
+    fn emit_new_block(&self, block: &Block, height: usize) {
+        self.events.emit(Event::NewBlock {
+            hash: block.hash.clone(),
+            height,
+            prev: block.prev.clone(),
+            txs: block.txs.iter().map(Tx::id).collect(),
+        });
+    }
+
+    fn add_side(&mut self, block: Block) -> Option<Reorg> {
+        let height = self.height_of(&block.prev)? + 1;
+        let tip = self.state.height();
+        println!("block {} at height {} is on another branch", &block.hash[..8], height);
+
+        // branches too far behind won't catch up
+        let forgotten: Vec<String> = self.side
+            .iter()
+            .filter(|(_, (_, h))| h + MAX_REORG_DEPTH < tip)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in forgotten {
+            self.side.remove(&hash);
+            self.hashes.remove(&hash);
+        }
+
+        let beats_tip = self.beats_tip(&block, height);
+        let hash = block.hash.clone();
+        self.hashes.insert(hash.clone());
+        self.side.insert(hash.clone(), (block, height));
+        if !beats_tip {
+            return None;
+        }
+
+        // trace the branch back to where it leaves the chain
+        let mut branch = vec![];
+        let mut next = hash;
+        while let Some((block, _)) = self.side.get(&next) {
+            next = block.prev.clone();
+            branch.push(block.clone());
+        }
+        branch.reverse();
+        self.reorg(branch)
+    }
+
+    /// Replays the chain up to where `branch` leaves it, then `branch`
+    /// checked block by block, and switches over to it if it all holds up.
+    /// A bad block is forgotten, along with anything built on it.
+    fn reorg(&mut self, branch: Vec<Block>) -> Option<Reorg> {
+        let fork = self.height_of(&branch[0].prev)?;
+        let mut state = genesis(&self.params);
+        for block in &self.chain[..fork] {
+            apply(&mut state, block, &self.params, false);
+        }
+
+        for (i, block) in branch.iter().enumerate() {
+            let parent = match i {
+                0 => self.chain[..fork].last(),
+                _ => Some(&branch[i - 1]),
+            };
+            if !self.engine.verify(block, parent, &state, false) {
+                println!("dropping branch at block {}: bad seal", &block.hash[..8]);
+                self.forget(&block.hash);
+                return None;
+            }
+            apply(&mut state, block, &self.params, false);
+        }
+
+        let disconnected = self.chain.split_off(fork);
+        for (i, block) in disconnected.iter().enumerate() {
+            self.side.insert(block.hash.clone(), (block.clone(), fork + i + 1));
+        }
+        for block in &branch {
+            self.side.remove(&block.hash);
+        }
+        println!(
+            "switched to branch at {} -- {} block(s) off, {} on",
+            &branch[branch.len() - 1].hash[..8],
+            disconnected.len(),
+            branch.len(),
+        );
+
+        let hashes = |blocks: &[Block]| blocks.iter().map(|b| b.hash.clone()).collect();
+        self.events.emit(Event::Reorg {
+            tip: branch[branch.len() - 1].hash.clone(),
+            height: fork + branch.len(),
+            disconnected: hashes(&disconnected),
+            connected: hashes(&branch),
+        });
+        for (i, block) in branch.iter().enumerate() {
+            self.emit_new_block(block, fork + i + 1);
+        }
+        self.state = state;
+        self.chain.extend(branch.iter().cloned());
+
+        // evidence in the blocks taken off can go in the next one instead
+        for evidence in disconnected.iter().flat_map(|block| &block.evidence) {
+            if evidence.is_valid() && !self.evidence.iter().any(|e| e.validator() == evidence.validator()) {
+                self.evidence.push(evidence.clone());
+            }
+        }
+        let state = &self.state;
+        self.evidence.retain(|e| state.stake(e.validator()) > 0.0);
+
+        Some(Reorg { disconnected, connected: branch })
+    }
+
+    /// Drops a block on another branch and everything built on it.
+    fn forget(&mut self, hash: &str) {
+        let mut queue = vec![hash.to_string()];
+        while let Some(hash) = queue.pop() {
+            self.side.remove(&hash);
+            self.hashes.remove(&hash);
+            queue.extend(self.side.iter().filter(|(_, (b, _))| b.prev == hash).map(|(h, _)| h.clone()));
+        }
+    }
+}
+
+/// The blocks taken off the chain, oldest first, and the ones that replaced
+/// them, when it switches branches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reorg {
+    pub disconnected: Vec<Block>,
+    pub connected: Vec<Block>,
+}
+
+/// The state before the first block.
+fn genesis(params: &ChainParams) -> State {
+    let mut state = State::new(params.genesis_balance);
+    if let Consensus::Stake { validators, genesis_stake, .. } = &params.consensus {
+        for validator in validators {
+            state.bond(validator, *genesis_stake);
+        }
+    }
+    state
+}
+
+/// Applies `block` to `state`: slashes whoever its evidence convicts, then
+/// applies its txs and pays its miner. `log` reports what was skipped.
+fn apply(state: &mut State, block: &Block, params: &ChainParams, log: bool) {
+    for evidence in &block.evidence {
+        if !evidence.is_valid() {
+            if log {
+                println!("ignoring bad evidence in block {}", &block.hash[..8]);
+            }
+            continue;
+        }
+        let slashed = state.slash(evidence.validator());
+        if slashed > 0.0 && log {
+            println!("slashed {} of stake from {}", slashed, &evidence.validator()[..8]);
+        }
+    }
+
+    let skipped = state.apply_block(block.get_all());
+    if log {
+        for (tx, e) in &skipped {
+            println!("skipping {:?} in block {}: {}", tx, &block.hash[..8], e);
+        }
+    }
+
+    // the miner can claim the reward plus the fees of the txs that applied
+    if let Some(coinbase) = &block.coinbase {
+        let fees: f64 = block.txs.iter().map(|tx| tx.fee as f64).sum::<f64>()
+            - skipped.iter().map(|(tx, _)| tx.fee as f64).sum::<f64>();
+        if coinbase.amount >= 0.0 && coinbase.amount <= params.block_reward + fees {
+            state.credit(coinbase.to, coinbase.amount);
+        } else if log {
+            println!("ignoring coinbase of {} in block {}", coinbase.amount, &block.hash[..8]);
+        }
+    }
+}
+
+/// Hashes `template` with each of `nonces` in parallel, returning the first
+/// block that meets the template's difficulty. The range is split between
+/// the pool's threads, so each nonce is tried exactly once. Works on its own
+/// copy of everything, so no lock needs to be held meanwhile. Blocks are
+/// timed by `clock` from `time`.
+pub fn mine(
+    template: &BlockTemplate,
+    id: u16,
+    nonces: Range<u64>,
+    clock: &Clock,
+    time: SystemTime,
+    abort: &AtomicBool,
+) -> Option<Block> {
+    let target = "0".repeat(template.difficulty);
+
+    nonces
+        .into_par_iter()
+        .find_map_any(move |nonce| {
+            // the rest of the round is skipped once the work goes stale
+            if abort.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let mut block = Block::new(
+                id,
+                template.prev.clone(),
+                template.txs.clone(),
+                nonce,
+                clock.elapsed_ms(time),
+            );
+            block.coinbase = Some(template.coinbase.clone());
+            block.evidence = template.evidence.clone();
+
+            let hash = block.generate_hash(template.pow);
+            if hash.starts_with(&target) {
+                block.hash = hash;
+                return Some(block);
+            }
+
+            None
+        })
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -113,6 +464,27 @@ pub struct Block {
     pub prev: String,
     pub txs: Vec<Tx>,
     pub ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase: Option<Coinbase>,
+
+    // proof of authority and stake only; not part of the hash it signs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seal: Option<Seal>,
+
+    // double signing that gets the signer's stake slashed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evidence: Vec<Evidence>,
+}
+
+/// Pays the block reward and the fees of the block's txs to the miner.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Coinbase {
+    pub to: char,
+    pub amount: f64,
+
+    // changes the header once every nonce has been tried
+    #[serde(default)]
+    pub extra_nonce: u64,
 }
 
 impl Block {
@@ -124,20 +496,38 @@ impl Block {
             prev,
             txs,
             ms,
+            coinbase: None,
+            seal: None,
+            evidence: vec![],
         }
     }
 
-    pub fn generate_hash(&self) -> String {
-        let mut block = self.clone();
-        block.hash = "".to_string();
+    pub fn generate_hash(&self, pow: Pow) -> String {
+        hex::encode(pow.algorithm().hash(self))
+    }
+
+    /// The fields the proof of work commits to, in a fixed binary layout:
+    /// id, prev, digests of the txs and coinbase, nonce and ms, then a
+    /// digest of the evidence if there's any.
+    pub fn header(&self) -> Vec<u8> {
+        let prev = match hex::decode(&self.prev) {
+            Ok(bytes) if bytes.len() == 32 => bytes,
+            _ => Sha256::digest(self.prev.as_bytes()).to_vec(),
+        };
+        let txs = serde_json::to_vec(&self.txs).unwrap_or_default();
+        let coinbase = serde_json::to_vec(&self.coinbase).unwrap_or_default();
 
-        if let Ok(serialized) = serde_json::to_string(&block) {
-            let mut hasher = Sha256::default();
-            hasher.input(serialized);
-            return hex::encode(hasher.result())
+        let mut header = Vec::with_capacity(114);
+        header.extend_from_slice(&self.id.to_le_bytes());
+        header.extend_from_slice(&prev);
+        header.extend_from_slice(&Sha256::digest(&txs));
+        header.extend_from_slice(&Sha256::digest(&coinbase));
+        header.extend_from_slice(&self.nonce.to_le_bytes());
+        header.extend_from_slice(&self.ms.to_le_bytes());
+        if !self.evidence.is_empty() {
+            header.extend_from_slice(&Sha256::digest(serde_json::to_vec(&self.evidence).unwrap_or_default()));
         }
-
-        String::new()
+        header
     }
 
     pub fn get_all(&self) -> &Vec<Tx> {
@@ -149,18 +539,125 @@ impl Block {
     }
 }
 
+/// Short id of `tx` within the block `block_hash`. Salting with the block
+/// hash keeps anyone from grinding txs that collide in every block.
+pub fn short_id(block_hash: &str, tx: &Tx) -> String {
+    let mut hasher = Sha256::default();
+    hasher.update(block_hash);
+    hasher.update(tx.id());
+    hex::encode(&hasher.finalize()[..6])
+}
+
+impl Block {
+    pub fn to_compact(&self) -> CompactBlock {
+        CompactBlock {
+            id: self.id,
+            nonce: self.nonce,
+            hash: self.hash.clone(),
+            prev: self.prev.clone(),
+            ms: self.ms,
+            coinbase: self.coinbase.clone(),
+            seal: self.seal.clone(),
+            evidence: self.evidence.clone(),
+            short_ids: self.txs.iter().map(|tx| short_id(&self.hash, tx)).collect(),
+        }
+    }
+}
+
+/// A block header plus short tx ids, which peers rebuild from their own
+/// mempool instead of receiving every tx again.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CompactBlock {
+    pub id: u16,
+    pub nonce: u64,
+    pub hash: String,
+    pub prev: String,
+    pub ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase: Option<Coinbase>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seal: Option<Seal>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evidence: Vec<Evidence>,
+    pub short_ids: Vec<String>,
+}
+
+impl CompactBlock {
+    pub fn reconstruct(&self, pool: &[Tx]) -> PartialBlock {
+        let by_short_id: HashMap<String, &Tx> = pool
+            .iter()
+            .map(|tx| (short_id(&self.hash, tx), tx))
+            .collect();
+
+        PartialBlock {
+            txs: self.short_ids
+                .iter()
+                .map(|id| by_short_id.get(id).map(|&tx| tx.clone()))
+                .collect(),
+            compact: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    pub compact: CompactBlock,
+    txs: Vec<Option<Tx>>,
+}
+
+impl PartialBlock {
+    pub fn missing(&self) -> Vec<usize> {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn fill(&mut self, indexes: &[usize], txs: &[Tx]) {
+        for (&i, tx) in indexes.iter().zip(txs) {
+            if let Some(slot) = self.txs.get_mut(i) {
+                *slot = Some(tx.clone());
+            }
+        }
+    }
+
+    /// Assembles the block once every tx is known. Returns `None` if some
+    /// are still missing or the result doesn't hash to the announced hash,
+    /// which means a short id collided with the wrong tx.
+    pub fn to_block(&self, pow: Pow) -> Option<Block> {
+        let txs = self.txs.iter().cloned().collect::<Option<Vec<_>>>()?;
+        let c = &self.compact;
+
+        let mut block = Block::new(c.id, c.prev.clone(), txs, c.nonce, c.ms);
+        block.coinbase = c.coinbase.clone();
+        block.seal = c.seal.clone();
+        block.evidence = c.evidence.clone();
+        if block.generate_hash(pow) != c.hash {
+            return None;
+        }
+
+        block.hash = c.hash.clone();
+        Some(block)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::blockchain::*;
     use crate::mempool::Mempool;
     use crate::config::SETTINGS;
 
+    fn params() -> ChainParams {
+        ChainParams { consensus: Consensus::Work, difficulty: 0, pow: Pow::Sha256, genesis_balance: 1000.0, block_reward: 10.0, regtest: false }
+    }
+
     #[test]
     fn test_blockchain_mine() {
         let mut mp = Mempool::new();
 
         let min_tx_per_block = SETTINGS.get::<usize>("min_tx_per_block").unwrap();
-        let difficulty = SETTINGS.get::<usize>("difficulty").unwrap();
         let concurrent_hashes = SETTINGS.get::<u64>("concurrent_hashes").unwrap();
 
         for i in 0..min_tx_per_block {
@@ -168,27 +665,181 @@ mod tests {
                 from: 'A',
                 to: std::char::from_digit(i as u32, 10).unwrap(),
                 amount: 1,
-                fee: i as f32 * 0.1
-            });
+                fee: i as f32 * 0.1,
+                nonce: i as u64,
+                spends: None,
+                stake: None,
+            }).unwrap();
         }
 
-        let bc = Blockchain::new(min_tx_per_block, difficulty, concurrent_hashes);
+        // a regtest chain, so this takes a few hashes instead of thousands
+        let mut bc = Blockchain::new(params().regtest());
+        let difficulty = bc.params().difficulty;
+        let template = TemplateBuilder::from_settings().build(bc.head(), bc.params(), &mp).unwrap();
         let mut nonce: u64 = 0;
 
         loop {
-            if let Some(block) = bc.mine(1111, nonce, SystemTime::now(), mp.get_all().to_vec()) {
+            if let Some(block) = mine(&template, 1111, nonce..nonce + concurrent_hashes, &Clock::default(), SystemTime::now(), &AtomicBool::new(false)) {
                 assert_eq!(block.hash[..difficulty], "0".repeat(difficulty));
                 assert_eq!(block.len(), min_tx_per_block);
+                assert_eq!(block.coinbase.as_ref(), Some(&template.coinbase));
 
+                let miner = template.coinbase.to;
+                bc.add(block);
+                assert_eq!(bc.state().balance(miner), 1000.0 + 10.0 + template.fees);
                 break;
             }
             nonce += concurrent_hashes;
         }
     }
 
+    #[test]
+    fn test_extra_nonce_changes_hash() {
+        let mut block = Block::new(1111, String::new(), vec![], 0, 0);
+        block.coinbase = Some(Coinbase { to: 'Z', amount: 10.0, extra_nonce: 0 });
+        let hash = block.generate_hash(Pow::Sha256);
+
+        block.coinbase.as_mut().unwrap().extra_nonce += 1;
+        assert_ne!(block.generate_hash(Pow::Sha256), hash);
+    }
+
+    #[test]
+    fn test_mine_aborts() {
+        let template = TemplateBuilder::default().build(Head::default(), &params(), &Mempool::new()).unwrap();
+
+        assert!(mine(&template, 1111, 0..16, &Clock::default(), SystemTime::now(), &AtomicBool::new(false)).is_some());
+        assert!(mine(&template, 1111, 0..16, &Clock::default(), SystemTime::now(), &AtomicBool::new(true)).is_none());
+    }
+
+    #[test]
+    fn test_coinbase_cannot_overpay() {
+        let mut bc = Blockchain::new(params());
+        let tx = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+
+        let mut block = Block::new(1111, bc.tip(), vec![tx], 0, 0);
+        block.coinbase = Some(Coinbase { to: 'Z', amount: 11.0, extra_nonce: 0 });
+        block.hash = block.generate_hash(Pow::Sha256);
+        bc.add(block);
+        assert_eq!(bc.state().balance('Z'), 1000.0);
+
+        let mut block = Block::new(1111, bc.tip(), vec![], 1, 0);
+        block.coinbase = Some(Coinbase { to: 'Z', amount: 10.0, extra_nonce: 0 });
+        block.hash = block.generate_hash(Pow::Sha256);
+        bc.add(block);
+        assert_eq!(bc.state().balance('Z'), 1010.0);
+    }
+
+    #[test]
+    fn test_add_emits_events() {
+        let mut bc = Blockchain::new(params());
+        let events = Events::new(16);
+        let mut rx = events.subscribe().unwrap();
+        bc.set_events(events);
+
+        let mut first = Block::new(1, bc.tip(), vec![], 0, 0);
+        first.hash = first.generate_hash(Pow::Sha256);
+        bc.add(first.clone());
+        assert_eq!(rx.try_recv().unwrap(), Event::NewBlock { hash: first.hash.clone(), height: 1, prev: String::new(), txs: vec![] });
+
+        let mut second = Block::new(2, first.hash.clone(), vec![], 0, 0);
+        second.hash = second.generate_hash(Pow::Sha256);
+        bc.add(second.clone());
+        assert_eq!(rx.try_recv().unwrap(), Event::NewBlock { hash: second.hash.clone(), height: 2, prev: first.hash.clone(), txs: vec![] });
+        assert!(rx.try_recv().is_err());
+
+        // a rival branch says nothing until it overtakes ours
+        let mut rivals = vec![];
+        for i in 0..3 {
+            let prev = rivals.last().map(|b: &Block| b.hash.clone()).unwrap_or_default();
+            let mut rival = Block::new(3, prev, vec![], i, 0);
+            rival.hash = rival.generate_hash(Pow::Sha256);
+            bc.add(rival.clone());
+            rivals.push(rival);
+        }
+        let hashes = |blocks: &[&Block]| blocks.iter().map(|b| b.hash.clone()).collect::<Vec<_>>();
+        assert_eq!(rx.try_recv().unwrap(), Event::Reorg {
+            tip: rivals[2].hash.clone(),
+            height: 3,
+            disconnected: hashes(&[&first, &second]),
+            connected: hashes(&[&rivals[0], &rivals[1], &rivals[2]]),
+        });
+        for (i, rival) in rivals.iter().enumerate() {
+            match rx.try_recv().unwrap() {
+                Event::NewBlock { hash, height, .. } => assert_eq!((hash, height), (rival.hash.clone(), i + 1)),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_double_signing_is_slashed() {
+        let key = ValidatorKey::generate();
+        let consensus = Consensus::Stake {
+            validators: vec![key.public.clone()],
+            genesis_stake: 50.0,
+            interval: std::time::Duration::from_millis(250),
+        };
+        let params = ChainParams { consensus, ..params() };
+        let mut bc = Blockchain::new(params.clone());
+        assert_eq!(bc.state().stake(&key.public), 50.0);
+
+        // each engine seals once per slot, so a second one signs the double
+        let seal = |builder: TemplateBuilder, head: Head| {
+            let template = builder.build(head, &params, &Mempool::new()).unwrap();
+            engine(&params, Some(key.clone())).seal(&template, 1, 0..1, &Clock::default(), SystemTime::now(), &AtomicBool::new(true)).unwrap()
+        };
+        let first = seal(TemplateBuilder::default(), bc.head());
+        let second = seal(TemplateBuilder { extra_nonce: 1, ..Default::default() }, bc.head());
+
+        assert!(bc.verify(&first, true));
+        bc.add(first.clone());
+
+        // the second is a rival at the same height and slot, so the first
+        // one stays the tip
+        assert!(bc.verify(&second, true));
+        assert_eq!(bc.add(second), None);
+        assert_eq!(bc.tip(), first.hash);
+        assert_eq!(bc.head().evidence.len(), 1);
+
+        // the next block carries the evidence, which burns the stake, and
+        // comes in a later slot
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        let next = seal(TemplateBuilder::default(), bc.head());
+        assert_eq!(next.evidence, bc.head().evidence);
+        assert!(bc.verify(&next, true));
+        bc.add(next);
+        assert_eq!(bc.state().stake(&key.public), 0.0);
+        assert!(bc.head().evidence.is_empty());
+    }
+
+    #[test]
+    fn test_compact_block_reconstruct() {
+        let txs: Vec<Tx> = (0..4)
+            .map(|i| Tx { from: 'A', to: 'B', amount: i, fee: 0.1, nonce: i as u64, spends: None, stake: None })
+            .collect();
+
+        let mut block = Block::new(1, String::new(), txs.clone(), 0, 0);
+        block.hash = block.generate_hash(Pow::Sha256);
+        let compact = block.to_compact();
+
+        // everything already in the mempool
+        let partial = compact.reconstruct(&txs);
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.to_block(Pow::Sha256), Some(block.clone()));
+
+        // two txs missing, then delivered
+        let mut partial = compact.reconstruct(&[txs[0].clone(), txs[2].clone()]);
+        assert_eq!(partial.missing(), vec![1, 3]);
+        assert_eq!(partial.to_block(Pow::Sha256), None);
+
+        partial.fill(&[1, 3], &[txs[1].clone(), txs[3].clone()]);
+        assert_eq!(partial.to_block(Pow::Sha256), Some(block));
+    }
+
     #[test]
     fn test_block_generate_hash() {
         let block = Block::new(1, "".to_string(), vec![], 0, 0);
-        assert_eq!(block.generate_hash()[..6], "b9e0e5".to_string());
+        assert_eq!(block.generate_hash(Pow::Sha256)[..6], "b9e0e5".to_string());
     }
 }
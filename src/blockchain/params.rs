@@ -0,0 +1,63 @@
+use std::time::Duration;
+use crate::blockchain::{Consensus, Pow};
+use crate::config::*;
+
+// a regtest block takes a handful of hashes
+const REGTEST_DIFFICULTY: usize = 1;
+
+/// The rules every node on a network has to agree on.
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub consensus: Consensus,
+    pub difficulty: usize,
+    pub pow: Pow,
+    pub genesis_balance: f64,
+    pub block_reward: f64,
+
+    // a private test network, where blocks only come from `generate`
+    pub regtest: bool,
+}
+
+impl ChainParams {
+    pub fn from_settings() -> Self {
+        let pow = SETTINGS.get::<String>("pow_algorithm").unwrap();
+        let consensus = match SETTINGS.get::<String>("consensus").unwrap().as_str() {
+            "pow" => Consensus::Work,
+            "poa" => Consensus::Authority {
+                validators: SETTINGS.get::<Vec<String>>("validators").unwrap(),
+                interval: Duration::from_secs(SETTINGS.get::<u64>("block_interval_secs").unwrap()),
+            },
+            "pos" => Consensus::Stake {
+                validators: SETTINGS.get::<Vec<String>>("validators").unwrap(),
+                genesis_stake: SETTINGS.get::<f64>("genesis_stake").unwrap(),
+                interval: Duration::from_secs(SETTINGS.get::<u64>("block_interval_secs").unwrap()),
+            },
+            other => panic!("unknown consensus {}", other),
+        };
+
+        let params = ChainParams {
+            consensus,
+            difficulty: SETTINGS.get::<usize>("difficulty").unwrap(),
+            pow: Pow::parse(&pow).unwrap_or_else(|| panic!("unknown pow_algorithm {}", pow)),
+            genesis_balance: SETTINGS.get::<f64>("genesis_balance").unwrap(),
+            block_reward: SETTINGS.get::<f64>("block_reward").unwrap(),
+            regtest: false,
+        };
+
+        match SETTINGS.get::<bool>("regtest").unwrap() {
+            true => params.regtest(),
+            false => params,
+        }
+    }
+
+    /// The same chain as a regtest network: proof of work at a trivial
+    /// difficulty, so blocks can be generated on demand.
+    pub fn regtest(self) -> Self {
+        ChainParams {
+            consensus: Consensus::Work,
+            difficulty: REGTEST_DIFFICULTY,
+            regtest: true,
+            ..self
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::blockchain::Block;
+
+/// Hashes a block for its proof of work. A block is valid once the hex
+/// encoded hash starts with `difficulty` zeros.
+pub trait PowAlgorithm: Sync {
+    fn hash(&self, block: &Block) -> Vec<u8>;
+}
+
+/// Sha256 over the block as JSON, what the chain has always used.
+pub struct Sha256Json;
+
+impl PowAlgorithm for Sha256Json {
+    fn hash(&self, block: &Block) -> Vec<u8> {
+        let mut block = block.clone();
+        block.hash = String::new();
+        block.seal = None;
+
+        let mut hasher = Sha256::default();
+        hasher.update(serde_json::to_string(&block).unwrap_or_default());
+        hasher.finalize().to_vec()
+    }
+}
+
+/// Sha256 twice over the binary header, as in bitcoin.
+pub struct Sha256d;
+
+impl PowAlgorithm for Sha256d {
+    fn hash(&self, block: &Block) -> Vec<u8> {
+        Sha256::digest(Sha256::digest(block.header())).to_vec()
+    }
+}
+
+pub struct Blake3;
+
+impl PowAlgorithm for Blake3 {
+    fn hash(&self, block: &Block) -> Vec<u8> {
+        blake3::hash(&block.header()).as_bytes().to_vec()
+    }
+}
+
+/// Scrypt with litecoin's parameters (N=1024, r=1, p=1), which needs
+/// 128KB per hash and so gives GPUs less of an edge.
+pub struct Scrypt;
+
+impl PowAlgorithm for Scrypt {
+    fn hash(&self, block: &Block) -> Vec<u8> {
+        let header = block.header();
+        let params = scrypt::Params::new(10, 1, 1, 32).unwrap();
+        let mut out = vec![0; 32];
+        scrypt::scrypt(&header, &header, &params, &mut out).unwrap();
+        out
+    }
+}
+
+/// Which proof of work a chain uses.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pow {
+    Sha256,
+    Sha256d,
+    Blake3,
+    Scrypt,
+}
+
+impl Pow {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Pow::Sha256),
+            "sha256d" => Some(Pow::Sha256d),
+            "blake3" => Some(Pow::Blake3),
+            "scrypt" => Some(Pow::Scrypt),
+            _ => None,
+        }
+    }
+
+    pub fn algorithm(self) -> &'static dyn PowAlgorithm {
+        match self {
+            Pow::Sha256 => &Sha256Json,
+            Pow::Sha256d => &Sha256d,
+            Pow::Blake3 => &Blake3,
+            Pow::Scrypt => &Scrypt,
+        }
+    }
+}
+
+impl fmt::Display for Pow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pow::Sha256 => write!(f, "sha256"),
+            Pow::Sha256d => write!(f, "sha256d"),
+            Pow::Blake3 => write!(f, "blake3"),
+            Pow::Scrypt => write!(f, "scrypt"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::pow::*;
+    use crate::types::Tx;
+
+    #[test]
+    fn test_algorithms_differ() {
+        let block = Block::new(1, String::new(), vec![], 0, 0);
+        let all = [Pow::Sha256, Pow::Sha256d, Pow::Blake3, Pow::Scrypt];
+
+        let hashes: Vec<String> = all.iter().map(|&pow| block.generate_hash(pow)).collect();
+        for (i, hash) in hashes.iter().enumerate() {
+            assert_eq!(hash.len(), 64);
+            assert!(!hashes[..i].contains(hash));
+        }
+
+        for pow in all {
+            assert_eq!(Pow::parse(&pow.to_string()), Some(pow));
+        }
+        assert_eq!(Pow::parse("md5"), None);
+    }
+
+    #[test]
+    fn test_header_commits_to_every_field() {
+        let mut block = Block::new(1, String::new(), vec![], 0, 0);
+        let header = block.header();
+
+        block.nonce = 1;
+        assert_ne!(block.header(), header);
+        block.nonce = 0;
+        block.ms = 1;
+        assert_ne!(block.header(), header);
+        block.ms = 0;
+        block.prev = "00".repeat(32);
+        assert_ne!(block.header(), header);
+        block.prev = String::new();
+        block.txs.push(Tx { from: 'A', to: 'B', amount: 1, fee: 0.1, nonce: 0, spends: None, stake: None });
+        assert_ne!(block.header(), header);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use crate::types::Tx;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxError {
+    BadAmount,
+    StaleNonce,
+    InsufficientBalance,
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxError::BadAmount => write!(f, "amount and fee must be positive"),
+            TxError::StaleNonce => write!(f, "nonce already used"),
+            TxError::InsufficientBalance => write!(f, "insufficient balance"),
+        }
+    }
+}
+
+fn check(tx: &Tx, balance: f64, next_nonce: u64) -> Result<(), TxError> {
+    if tx.amount <= 0 || tx.fee < 0.0 || !tx.fee.is_finite() {
+        return Err(TxError::BadAmount);
+    }
+    // nonces only have to increase, so a dropped tx doesn't strand the
+    // sender's later ones
+    if tx.nonce < next_nonce {
+        return Err(TxError::StaleNonce);
+    }
+    if balance < cost(tx) {
+        return Err(TxError::InsufficientBalance);
+    }
+    Ok(())
+}
+
+fn cost(tx: &Tx) -> f64 {
+    tx.amount as f64 + tx.fee as f64
+}
+
+/// Account balances and nonces as of some block. Every account starts out
+/// with the genesis balance; fees go to whoever mined the block.
+#[derive(Debug, Clone)]
+pub struct State {
+    height: usize,
+    genesis_balance: f64,
+    balances: HashMap<char, f64>,
+    nonces: HashMap<char, u64>,
+
+    // coins locked up by stake txs, per validator key; ordered so every
+    // node walks it the same way when picking a leader
+    stakes: BTreeMap<String, f64>,
+}
+
+impl State {
+    pub fn new(genesis_balance: f64) -> Self {
+        State {
+            height: 0,
+            genesis_balance,
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+            stakes: BTreeMap::new(),
+        }
+    }
+
+    /// How many blocks have been applied.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn balance(&self, account: char) -> f64 {
+        *self.balances.get(&account).unwrap_or(&self.genesis_balance)
+    }
+
+    pub fn next_nonce(&self, account: char) -> u64 {
+        *self.nonces.get(&account).unwrap_or(&0)
+    }
+
+    pub fn apply(&mut self, tx: &Tx) -> Result<(), TxError> {
+        let mut overlay = self.overlay();
+        overlay.apply(tx)?;
+
+        let Overlay { balances, nonces, stakes, .. } = overlay;
+        self.balances.extend(balances);
+        self.nonces.extend(nonces);
+        self.stakes.extend(stakes);
+        Ok(())
+    }
+
+    /// Pays out a block's coinbase.
+    pub fn credit(&mut self, account: char, amount: f64) {
+        let balance = self.balance(account) + amount;
+        self.balances.insert(account, balance);
+    }
+
+    pub fn stake(&self, validator: &str) -> f64 {
+        *self.stakes.get(validator).unwrap_or(&0.0)
+    }
+
+    /// Every validator with something at stake.
+    pub fn stakes(&self) -> &BTreeMap<String, f64> {
+        &self.stakes
+    }
+
+    /// Locks up stake that no account paid for, as at genesis.
+    pub fn bond(&mut self, validator: &str, amount: f64) {
+        let stake = self.stake(validator) + amount;
+        self.stakes.insert(validator.to_string(), stake);
+    }
+
+    /// Burns a validator's whole stake, for signing two blocks at one height.
+    pub fn slash(&mut self, validator: &str) -> f64 {
+        self.stakes.remove(validator).unwrap_or(0.0)
+    }
+
+    /// Applies a block's txs in order. Two miners can race to include the
+    /// same tx, so whichever copy lands second no longer applies; those are
+    /// skipped and returned.
+    pub fn apply_block<'a>(&mut self, txs: &'a [Tx]) -> Vec<(&'a Tx, TxError)> {
+        self.height += 1;
+        txs.iter()
+            .filter_map(|tx| self.apply(tx).err().map(|e| (tx, e)))
+            .collect()
+    }
+
+    /// A scratch copy for trying out txs, which only stores what changed.
+    pub fn overlay(&self) -> Overlay<'_> {
+        Overlay {
+            base: self,
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+            stakes: HashMap::new(),
+        }
+    }
+}
+
+pub struct Overlay<'a> {
+    base: &'a State,
+    balances: HashMap<char, f64>,
+    nonces: HashMap<char, u64>,
+    stakes: HashMap<String, f64>,
+}
+
+impl Overlay<'_> {
+    fn balance(&self, account: char) -> f64 {
+        self.balances.get(&account).copied().unwrap_or_else(|| self.base.balance(account))
+    }
+
+    fn next_nonce(&self, account: char) -> u64 {
+        self.nonces.get(&account).copied().unwrap_or_else(|| self.base.next_nonce(account))
+    }
+
+    pub fn check(&self, tx: &Tx) -> Result<(), TxError> {
+        check(tx, self.balance(tx.from), self.next_nonce(tx.from))
+    }
+
+    pub fn apply(&mut self, tx: &Tx) -> Result<(), TxError> {
+        self.check(tx)?;
+
+        let from = self.balance(tx.from) - cost(tx);
+        self.balances.insert(tx.from, from);
+        match &tx.stake {
+            Some(validator) => {
+                let stake = self.stakes.get(validator).copied().unwrap_or_else(|| self.base.stake(validator));
+                self.stakes.insert(validator.clone(), stake + tx.amount as f64);
+            },
+            None => {
+                let to = self.balance(tx.to) + tx.amount as f64;
+                self.balances.insert(tx.to, to);
+            },
+        }
+        self.nonces.insert(tx.from, tx.nonce + 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::state::*;
+
+    fn tx(from: char, to: char, amount: i32, nonce: u64) -> Tx {
+        Tx { from, to, amount, fee: 0.5, nonce, spends: None, stake: None }
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut state = State::new(10.0);
+
+        assert_eq!(state.apply(&tx('A', 'B', 4, 0)), Ok(()));
+        assert_eq!(state.balance('A'), 5.5);
+        assert_eq!(state.balance('B'), 14.0);
+        assert_eq!(state.next_nonce('A'), 1);
+
+        assert_eq!(state.apply(&tx('A', 'B', 1, 0)), Err(TxError::StaleNonce));
+        assert_eq!(state.apply(&tx('A', 'B', 6, 1)), Err(TxError::InsufficientBalance));
+        assert_eq!(state.apply(&tx('A', 'B', -1, 1)), Err(TxError::BadAmount));
+
+        // gaps are fine
+        assert_eq!(state.apply(&tx('A', 'B', 1, 5)), Ok(()));
+        assert_eq!(state.next_nonce('A'), 6);
+    }
+
+    #[test]
+    fn test_overlay_leaves_state_alone() {
+        let state = State::new(10.0);
+        let mut overlay = state.overlay();
+
+        assert_eq!(overlay.apply(&tx('A', 'B', 9, 0)), Ok(()));
+        assert_eq!(overlay.check(&tx('A', 'B', 1, 1)), Err(TxError::InsufficientBalance));
+        assert_eq!(overlay.check(&tx('B', 'A', 18, 0)), Ok(()));
+
+        assert_eq!(state.balance('A'), 10.0);
+        assert_eq!(state.next_nonce('A'), 0);
+    }
+
+    #[test]
+    fn test_stake() {
+        let mut state = State::new(10.0);
+        state.bond("v1", 5.0);
+
+        let mut stake = tx('A', 'B', 4, 0);
+        stake.stake = Some("v2".to_string());
+        assert_eq!(state.apply(&stake), Ok(()));
+        assert_eq!(state.balance('A'), 5.5);
+        assert_eq!(state.balance('B'), 10.0);
+        assert_eq!(state.stake("v2"), 4.0);
+
+        assert_eq!(state.slash("v1"), 5.0);
+        assert_eq!(state.stake("v1"), 0.0);
+        assert_eq!(state.stakes().keys().collect::<Vec<_>>(), vec!["v2"]);
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use crate::blockchain::{ChainParams, Coinbase, Evidence, Pow};
+use crate::mempool::Mempool;
+use crate::types::Tx;
+use crate::config::*;
+
+// room left in a block for its header, coinbase and the separators between txs
+const HEADER_RESERVE: usize = 1024;
+
+/// The end of the chain a template builds on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Head {
+    pub hash: String,
+    pub height: usize,
+    pub stakes: BTreeMap<String, f64>,
+
+    // the randomness the tip's seal left for the next proof of stake leader
+    pub seed: String,
+
+    // double signing seen but not yet in a block
+    pub evidence: Vec<Evidence>,
+}
+
+/// Everything a miner needs to hash a block, short of the nonce.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BlockTemplate {
+    pub prev: String,
+    pub height: usize,
+    pub difficulty: usize,
+    pub pow: Pow,
+    pub txs: Vec<Tx>,
+    pub coinbase: Coinbase,
+
+    // who may seal it under proof of stake
+    pub stakes: BTreeMap<String, f64>,
+    #[serde(default)]
+    pub seed: String,
+    pub evidence: Vec<Evidence>,
+
+    pub fees: f64,
+    pub size: usize,
+}
+
+impl BlockTemplate {
+    /// Moves on to a fresh header once the nonces for this one ran out.
+    pub fn bump_extra_nonce(&mut self) {
+        self.coinbase.extra_nonce = self.coinbase.extra_nonce.wrapping_add(1);
+    }
+}
+
+/// Fills blocks from the mempool, best paying packages first, within the
+/// size and tx count peers accept.
+#[derive(Debug, Clone)]
+pub struct TemplateBuilder {
+    pub max_size: usize,
+    pub max_txs: usize,
+    pub min_txs: usize,
+    pub reward: f64,
+    pub miner: char,
+
+    // where this miner's extra nonces start; miners given disjoint ranges
+    // never hash the same header
+    pub extra_nonce: u64,
+}
+
+impl Default for TemplateBuilder {
+    fn default() -> Self {
+        TemplateBuilder {
+            max_size: usize::MAX,
+            max_txs: usize::MAX,
+            min_txs: 0,
+            reward: 0.0,
+            miner: 'Z',
+            extra_nonce: 0,
+        }
+    }
+}
+
+impl TemplateBuilder {
+    pub fn from_settings() -> Self {
+        let max_block_size = SETTINGS.get::<usize>("max_block_size").unwrap();
+        let max_txs = SETTINGS.get::<usize>("max_block_txs").unwrap();
+        let miner = SETTINGS.get::<String>("miner_account").unwrap();
+
+        TemplateBuilder {
+            max_size: max_block_size.saturating_sub(HEADER_RESERVE + max_txs),
+            max_txs,
+            min_txs: SETTINGS.get::<usize>("min_tx_per_block").unwrap(),
+            reward: SETTINGS.get::<f64>("block_reward").unwrap(),
+            miner: miner.chars().next().expect("miner_account is empty"),
+            extra_nonce: 0,
+        }
+    }
+
+    /// A template on top of `head`, or `None` while the mempool can't fill
+    /// a block with at least `min_txs` txs.
+    pub fn build(&self, head: Head, params: &ChainParams, mempool: &Mempool) -> Option<BlockTemplate> {
+        let txs = mempool.select(self.max_txs, self.max_size);
+        if txs.len() < self.min_txs {
+            return None;
+        }
+
+        let fees: f64 = txs.iter().map(|tx| tx.fee as f64).sum();
+        let size = txs.iter().map(|tx| tx.size()).sum();
+
+        Some(BlockTemplate {
+            prev: head.hash,
+            height: head.height + 1,
+            difficulty: params.difficulty,
+            pow: params.pow,
+            coinbase: Coinbase {
+                to: self.miner,
+                amount: self.reward + fees,
+                extra_nonce: self.extra_nonce,
+            },
+            stakes: head.stakes,
+            seed: head.seed,
+            evidence: head.evidence,
+            txs,
+            fees,
+            size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::template::*;
+    use crate::blockchain::Consensus;
+
+    fn tx(from: char, fee: f32) -> Tx {
+        Tx { from, to: 'B', amount: 1, fee, nonce: 0, spends: None, stake: None }
+    }
+
+    fn params() -> ChainParams {
+        ChainParams { consensus: Consensus::Work, difficulty: 4, pow: Pow::Sha256, genesis_balance: 1000.0, block_reward: 10.0, regtest: false }
+    }
+
+    fn mempool(txs: &[Tx]) -> Mempool {
+        let mut mp = Mempool::new();
+        for t in txs {
+            mp.add(t.clone()).unwrap();
+        }
+        mp
+    }
+
+    #[test]
+    fn test_template_takes_best_fees() {
+        let txs = [tx('A', 0.1), tx('C', 0.9), tx('D', 0.5)];
+        let mp = mempool(&txs);
+        let builder = TemplateBuilder { max_txs: 2, reward: 10.0, ..Default::default() };
+
+        let template = builder.build(Head { hash: "prev".to_string(), height: 4, ..Default::default() }, &params(), &mp).unwrap();
+        assert_eq!(template.txs, vec![txs[1].clone(), txs[2].clone()]);
+        assert_eq!(template.fees, 0.9f32 as f64 + 0.5f32 as f64);
+        assert_eq!(template.size, txs[1].size() + txs[2].size());
+        assert_eq!(template.coinbase, Coinbase { to: 'Z', amount: 10.0 + template.fees, extra_nonce: 0 });
+        assert_eq!(template.prev, "prev");
+        assert_eq!(template.height, 5);
+    }
+
+    #[test]
+    fn test_extra_nonce() {
+        let builder = TemplateBuilder { extra_nonce: 4000 << 48, ..Default::default() };
+        let mut template = builder.build(Head::default(), &params(), &Mempool::new()).unwrap();
+        assert_eq!(template.coinbase.extra_nonce, 4000 << 48);
+
+        template.bump_extra_nonce();
+        assert_eq!(template.coinbase.extra_nonce, (4000 << 48) + 1);
+    }
+
+    #[test]
+    fn test_template_respects_size() {
+        let txs = [tx('A', 0.1), tx('C', 0.9), tx('D', 0.5)];
+        let mp = mempool(&txs);
+        let builder = TemplateBuilder { max_size: txs[0].size() * 2, ..Default::default() };
+
+        let template = builder.build(Head::default(), &params(), &mp).unwrap();
+        assert_eq!(template.txs.len(), 2);
+        assert!(template.size <= builder.max_size);
+    }
+
+    #[test]
+    fn test_template_waits_for_min_txs() {
+        let mp = mempool(&[tx('A', 0.1)]);
+        let builder = TemplateBuilder { min_txs: 2, ..Default::default() };
+        assert_eq!(builder.build(Head::default(), &params(), &mp), None);
+
+        // an empty block still pays the reward
+        let builder = TemplateBuilder { reward: 10.0, ..Default::default() };
+        let template = builder.build(Head::default(), &params(), &Mempool::new()).unwrap();
+        assert!(template.txs.is_empty());
+        assert_eq!(template.coinbase.amount, 10.0);
+    }
+}
@@ -0,0 +1,201 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::types::*;
+use crate::network::*;
+use crate::blockchain::Block;
+
+/// Runs `f` on its own event loop with the first live peer and every live
+/// peer, or says there are none.
+fn with_first_peer<F, Fut, T>(ports: &[u16], f: F) -> Option<T>
+    where F: FnOnce(SocketAddr, Vec<SocketAddr>) -> Fut, Fut: Future<Output = T>
+{
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("could not start event loop");
+
+    runtime.block_on(async {
+        let peers = get_live_peers(ports, 0).await;
+        match peers.first() {
+            Some(&peer) => Some(f(peer, peers).await),
+            None => {
+                println!("no live peers");
+                None
+            },
+        }
+    })
+}
+
+/// Makes one request of the first live peer, returning the peer along with
+/// its answer.
+fn request_first_peer<Req, Resp>(
+    ports: &[u16],
+    action: ActionType,
+    payload: &Req,
+    expect: ActionType,
+) -> Option<(SocketAddr, io::Result<Resp>)>
+    where Req: Serialize, Resp: DeserializeOwned
+{
+    with_first_peer(ports, |peer, _| async move {
+        (peer, request_from(peer, action, payload, expect).await)
+    })
+}
+
+/// Asks the first live peer what fee rate gets a tx mined within
+/// `target_blocks`.
+pub fn estimate_fee(ports: Vec<u16>, target_blocks: usize) {
+    let (peer, estimate) = match request_first_peer::<EstimateFee, FeeEstimate>(
+        &ports,
+        ActionType::EstimateFee,
+        &EstimateFee { target_blocks },
+        ActionType::FeeEstimate,
+    ) {
+        Some(answer) => answer,
+        None => return,
+    };
+
+    match estimate {
+        Ok(FeeEstimate { target_blocks, fee_rate: Some(rate) }) => {
+            println!("{:.6} per byte to be mined within {} block(s)", rate, target_blocks);
+        },
+        Ok(FeeEstimate { target_blocks, fee_rate: None }) => {
+            println!("{} has no estimate for {} block(s) yet", peer, target_blocks);
+        },
+        Err(e) => println!("could not get an estimate from {}, {}", peer, e),
+    }
+}
+
+/// Asks the first live peer how fast it's mining.
+pub fn mining_info(ports: Vec<u16>) {
+    let (peer, info) = match request_first_peer::<GetMiningInfo, MiningInfo>(
+        &ports,
+        ActionType::GetMiningInfo,
+        &GetMiningInfo {},
+        ActionType::MiningInfo,
+    ) {
+        Some(answer) => answer,
+        None => return,
+    };
+
+    match info {
+        Ok(info) if info.threads == 0 => println!("{} is not mining", peer),
+        Ok(info) => {
+            println!("{} thread(s) at {:.0} H/s", info.threads, info.hashrate);
+            println!(
+                "{} nonce(s) tried on the current block, {} at difficulty {}",
+                info.nonce,
+                info.algorithm,
+                info.difficulty,
+            );
+            match info.expected_secs {
+                Some(secs) => println!("~{:.1}s to a block", secs),
+                None => println!("no hashrate yet"),
+            }
+        },
+        Err(e) => println!("could not get mining info from {}, {}", peer, e),
+    }
+}
+
+/// Has the first live peer, a regtest node, mine `blocks` blocks on the
+/// spot, paying `to` or else its own `miner_account`.
+pub fn generate(ports: Vec<u16>, blocks: usize, to: Option<char>) {
+    let (peer, generated) = match request_first_peer::<Generate, Generated>(
+        &ports,
+        ActionType::Generate,
+        &Generate { blocks, to },
+        ActionType::Generated,
+    ) {
+        Some(answer) => answer,
+        None => return,
+    };
+
+    match generated {
+        Ok(Generated { reason: Some(reason), .. }) => println!("{} would not generate, {}", peer, reason),
+        Ok(Generated { hashes, .. }) => {
+            for hash in hashes {
+                println!("{}", hash);
+            }
+        },
+        Err(e) => println!("could not generate on {}, {}", peer, e),
+    }
+}
+
+/// Stops the first live peer's clock at `unix_ms`, or restarts it with 0.
+pub fn set_mock_time(ports: Vec<u16>, unix_ms: u64) {
+    let (peer, time) = match request_first_peer::<SetMockTime, MockTime>(
+        &ports,
+        ActionType::SetMockTime,
+        &SetMockTime { unix_ms },
+        ActionType::MockTime,
+    ) {
+        Some(answer) => answer,
+        None => return,
+    };
+
+    match time {
+        Ok(MockTime { reason: Some(reason), .. }) => println!("{} kept its clock, {}", peer, reason),
+        Ok(MockTime { unix_ms: Some(ms), .. }) => println!("{} stopped its clock at {}", peer, ms),
+        Ok(MockTime { unix_ms: None, .. }) => println!("{} follows the system clock", peer),
+        Err(e) => println!("could not set the time on {}, {}", peer, e),
+    }
+}
+
+/// Locks `amount` of `account`'s coins up as stake for the `validator` key,
+/// sending the stake tx to every live peer. The nonce follows the account's
+/// last tx in the first peer's chain and mempool.
+pub fn stake(ports: Vec<u16>, account: char, amount: i32, validator: String) {
+    with_first_peer(&ports, |peer, peers| async move {
+        let known = match (sync_from::<Block>(peer, ObjectType::Block).await, sync_from::<Tx>(peer, ObjectType::Tx).await) {
+            (Ok(blocks), Ok(txs)) => blocks.into_iter().flat_map(|b| b.txs).chain(txs).collect::<Vec<_>>(),
+            (Err(e), _) | (_, Err(e)) => {
+                println!("could not sync from {}, {}", peer, e);
+                return;
+            },
+        };
+        let nonce = known.iter().filter(|tx| tx.from == account).map(|tx| tx.nonce + 1).max().unwrap_or(0);
+
+        let tx = Tx {
+            from: account,
+            to: account,
+            amount,
+            fee: 0.1,
+            nonce,
+            spends: None,
+            stake: Some(validator),
+        };
+        let command = serde_json::to_string(&Command::new(ActionType::Broadcast(ObjectType::Tx), &tx)).unwrap();
+        println!("{:?}", tx);
+
+        for peer in peers {
+            let sent = async {
+                let mut conn = Connection::connect(peer).await?;
+                conn.writer.send(&command).await
+            };
+            if let Err(e) = sent.await {
+                println!("could not send to {}, {}", peer, e);
+            }
+        }
+    });
+}
+
+/// Syncs the chain and mempool from the first live peer without ever
+/// accepting a connection, the way a wallet or light client would.
+pub fn sync(ports: Vec<u16>) {
+    with_first_peer(&ports, |peer, _| async move {
+        let blocks = sync_from::<Block>(peer, ObjectType::Block).await;
+        let txs = sync_from::<Tx>(peer, ObjectType::Tx).await;
+
+        match (blocks, txs) {
+            (Ok(blocks), Ok(txs)) => {
+                println!("synced {} block(s) and {} tx(s) from {}", blocks.len(), txs.len(), peer);
+                if let Some(tip) = blocks.last() {
+                    println!("tip is {} from {}", &tip.hash[..8], tip.id);
+                }
+            },
+            (Err(e), _) | (_, Err(e)) => println!("could not sync from {}, {}", peer, e),
+        }
+    });
+}
@@ -0,0 +1,94 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::types::Tx;
+
+/// Why a tx left the mempool without being mined.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictReason {
+    // a tx with the same sender nonce paid more
+    Replaced,
+    // pushed out by better paying txs once the pool was full
+    Full,
+    // waited longer than `mempool_expiry_secs`
+    Expired,
+    // a new block, or a replacement of a tx before it, made it unaffordable
+    Invalid,
+}
+
+/// What the chain and mempool report as they change, for subscribers like
+/// the websocket api. Serializes as `{"event": "newBlock", "data": {...}}`.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(tag = "event", content = "data", rename_all = "camelCase")]
+pub enum Event {
+    NewBlock { hash: String, height: usize, prev: String, txs: Vec<String> },
+    // the chain switched branches: the blocks taken off, oldest first, and
+    // those that replaced them, ending at `tip`; each of those also comes
+    // as a `NewBlock` right after
+    Reorg { tip: String, height: usize, disconnected: Vec<String>, connected: Vec<String> },
+    NewTx { id: String, tx: Tx },
+    MempoolEvict { id: String, reason: EvictReason },
+}
+
+impl Event {
+    /// The name subscribers pick the event by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::NewBlock { .. } => "newBlock",
+            Event::Reorg { .. } => "reorg",
+            Event::NewTx { .. } => "newTx",
+            Event::MempoolEvict { .. } => "mempoolEvict",
+        }
+    }
+}
+
+pub const EVENT_NAMES: [&str; 4] = ["newBlock", "reorg", "newTx", "mempoolEvict"];
+
+/// Fans events out to every subscriber. Emitting never blocks; a subscriber
+/// that falls more than the queue size behind misses events. The default
+/// has no queue and drops everything, so chains and mempools built without
+/// a node cost nothing.
+#[derive(Clone, Default)]
+pub struct Events {
+    sender: Option<broadcast::Sender<Event>>,
+}
+
+impl Events {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Events { sender: Some(sender) }
+    }
+
+    pub fn emit(&self, event: Event) {
+        if let Some(sender) = &self.sender {
+            // no subscribers is fine
+            let _ = sender.send(event);
+        }
+    }
+
+    /// `None` when there's no queue to subscribe to.
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<Event>> {
+        self.sender.as_ref().map(broadcast::Sender::subscribe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::events::*;
+
+    #[test]
+    fn test_emit_and_subscribe() {
+        let events = Events::new(2);
+        let mut rx = events.subscribe().unwrap();
+        let evict = Event::MempoolEvict { id: "a".to_string(), reason: EvictReason::Full };
+        events.emit(evict.clone());
+        assert_eq!(rx.try_recv().unwrap(), evict);
+        assert_eq!(serde_json::to_value(&evict).unwrap(), json!({ "event": "mempoolEvict", "data": { "id": "a", "reason": "full" } }));
+        assert!(EVENT_NAMES.contains(&evict.name()));
+
+        // nothing to subscribe to, and emitting is a no-op
+        Events::default().emit(evict);
+        assert!(Events::default().subscribe().is_none());
+    }
+}
@@ -9,9 +9,22 @@ mod tx;
 mod mempool;
 mod node;
 mod blockchain;
+mod client;
+mod worker;
+mod events;
+
+use network::secure::StaticKey;
+use blockchain::ValidatorKey;
 
 fn main() {
     let args = args::get();
+
+    if let Some(path) = &args.flag_key {
+        let key = StaticKey::load(path).expect("could not load key");
+        println!("> transport key {}", key.public);
+        network::secure::set_local_key(key);
+    }
+
     match args {
         args::Args { cmd_broadcast: true, .. } => {
             let ports: Vec<u16> = args.arg_ports;
@@ -29,7 +42,52 @@ fn main() {
         },
         args::Args { cmd_mine: true, .. } => {
             println!("starting the node:");
-            node::start();
+            let validator = args.flag_validator.as_ref().map(|path| {
+                let key = ValidatorKey::load(path).expect("could not load validator key");
+                println!("> validator key {}", key.public);
+                key
+            });
+            node::start(args.flag_threads, validator);
+        },
+        args::Args { cmd_miner: true, .. } => {
+            let pool = args.flag_pool.unwrap();
+            let addr = network::parse_addr(&pool).expect("could not parse pool address");
+            worker::start(addr, args.flag_threads);
+        },
+        args::Args { cmd_sync: true, .. } => {
+            client::sync(args.arg_ports);
+        },
+        args::Args { cmd_estimatefee: true, .. } => {
+            client::estimate_fee(args.arg_ports, args.arg_blocks.unwrap());
+        },
+        args::Args { cmd_mininginfo: true, .. } => {
+            client::mining_info(args.arg_ports);
+        },
+        args::Args { cmd_stake: true, .. } => {
+            client::stake(
+                args.arg_ports,
+                args.arg_account.unwrap(),
+                args.arg_amount.unwrap(),
+                args.arg_validator.unwrap(),
+            );
+        },
+        args::Args { cmd_generate: true, .. } => {
+            client::generate(args.arg_ports, args.arg_blocks.unwrap(), args.arg_address);
+        },
+        args::Args { cmd_setmocktime: true, .. } => {
+            client::set_mock_time(args.arg_ports, args.arg_ms.unwrap());
+        },
+        args::Args { cmd_keygen: true, .. } => {
+            let path = args.arg_file.unwrap();
+            let key = StaticKey::generate();
+            key.save(&path).expect("could not save key");
+            println!("{}", key.public);
+        },
+        args::Args { cmd_validatorkey: true, .. } => {
+            let path = args.arg_file.unwrap();
+            let key = ValidatorKey::generate();
+            key.save(&path).expect("could not save key");
+            println!("{}", key.public);
         },
         _ => (),
     }
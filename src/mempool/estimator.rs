@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use serde::{Serialize, Deserialize};
+use crate::types::{Tx, FeeRate};
+
+// the furthest out an estimate can be asked for
+pub const MAX_TARGET: usize = 25;
+
+// fee rate buckets grow geometrically from MIN_RATE, covering up to ~15 per byte
+const MIN_RATE: f64 = 0.00001;
+const SPACING: f64 = 1.1;
+const BUCKETS: usize = 150;
+
+// older blocks count for less; after ~350 blocks, half as much
+const DECAY: f64 = 0.998;
+
+// share of txs in a bucket that must have confirmed in time
+const SUCCESS_RATE: f64 = 0.85;
+
+// how much (decayed) data a group of buckets needs before it's trusted
+const MIN_SAMPLES: f64 = 1.0;
+
+fn bucket(rate: FeeRate) -> usize {
+    if rate.0 <= MIN_RATE {
+        return 0;
+    }
+    let b = ((rate.0 / MIN_RATE).ln() / SPACING.ln()).floor() as usize;
+    b.min(BUCKETS - 1)
+}
+
+fn bucket_rate(b: usize) -> FeeRate {
+    FeeRate(MIN_RATE * SPACING.powi(b as i32))
+}
+
+/// Learns what fee rate gets a tx mined within a number of blocks, by
+/// watching how long txs in each fee rate bucket take to confirm.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FeeEstimator {
+    // per bucket, how many txs confirmed within 1..=MAX_TARGET blocks
+    confirmed: Vec<Vec<f64>>,
+
+    // per bucket, every tx that either confirmed or never will
+    total: Vec<f64>,
+
+    best_height: usize,
+
+    // txs still waiting: their bucket and the height they arrived at
+    #[serde(skip)]
+    pending: HashMap<String, (usize, usize)>,
+}
+
+impl FeeEstimator {
+    pub fn new() -> Self {
+        FeeEstimator {
+            confirmed: vec![vec![0.0; MAX_TARGET]; BUCKETS],
+            total: vec![0.0; BUCKETS],
+            best_height: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Starts timing a tx that just arrived with the tip at `height`. Txs
+    /// seen while still catching up on blocks are left out, since there's
+    /// no telling how long they really waited.
+    pub fn track(&mut self, id: String, rate: FeeRate, height: usize) {
+        if height >= self.best_height {
+            self.pending.insert(id, (bucket(rate), height));
+        }
+    }
+
+    fn record(&mut self, b: usize, blocks: Option<usize>) {
+        self.total[b] += 1.0;
+        if let Some(blocks) = blocks {
+            for count in &mut self.confirmed[b][blocks - 1..] {
+                *count += 1.0;
+            }
+        }
+    }
+
+    /// Counts the txs mined at `height`. Blocks at or below one already seen,
+    /// say while syncing after a restart, are skipped.
+    pub fn process_block(&mut self, height: usize, txs: &[Tx]) {
+        if height <= self.best_height {
+            return;
+        }
+        self.best_height = height;
+
+        for count in self.confirmed.iter_mut().flatten().chain(self.total.iter_mut()) {
+            *count *= DECAY;
+        }
+
+        for tx in txs {
+            if let Some((b, entered)) = self.pending.remove(&tx.id()) {
+                let blocks = height.saturating_sub(entered).max(1);
+                self.record(b, Some(blocks).filter(|&n| n <= MAX_TARGET));
+            }
+        }
+
+        // waited longer than any target; count them as misses and move on
+        let stale: Vec<String> = self.pending
+            .iter()
+            .filter(|(_, (_, entered))| height - entered >= MAX_TARGET)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in stale {
+            let (b, _) = self.pending.remove(&id).unwrap();
+            self.record(b, None);
+        }
+    }
+
+    /// Counts the txs that left the mempool without being mined, being
+    /// evicted, replaced or expired, as misses.
+    pub fn sweep(&mut self, in_mempool: impl Fn(&str) -> bool) {
+        let gone: Vec<String> = self.pending
+            .keys()
+            .filter(|id| !in_mempool(id))
+            .cloned()
+            .collect();
+        for id in gone {
+            let (b, _) = self.pending.remove(&id).unwrap();
+            self.record(b, None);
+        }
+    }
+
+    /// The lowest fee rate at which txs have reliably been mined within
+    /// `target_blocks`, or `None` without enough data yet.
+    pub fn estimate_fee(&self, target_blocks: usize) -> Option<FeeRate> {
+        let target = target_blocks.clamp(1, MAX_TARGET);
+        let (mut confirmed, mut total) = (0.0, 0.0);
+        let mut best = None;
+
+        // walk down from the best paying buckets, grouping sparse ones,
+        // until a group stops confirming in time
+        for b in (0..BUCKETS).rev() {
+            confirmed += self.confirmed[b][target - 1];
+            total += self.total[b];
+            if total < MIN_SAMPLES {
+                continue;
+            }
+            if confirmed / total < SUCCESS_RATE {
+                break;
+            }
+            best = Some(b);
+            confirmed = 0.0;
+            total = 0.0;
+        }
+
+        best.map(bucket_rate)
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let estimator: FeeEstimator = serde_json::from_slice(&fs::read(path)?)?;
+        // every count gets indexed by bucket and target, so a file with any
+        // row cut short is no use
+        let rows = estimator.confirmed.iter().all(|row| row.len() == MAX_TARGET);
+        if estimator.total.len() != BUCKETS || estimator.confirmed.len() != BUCKETS || !rows {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bucket layout changed"));
+        }
+        Ok(estimator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mempool::estimator::*;
+
+    fn tx(fee: f32, nonce: u64) -> Tx {
+        Tx { from: 'A', to: 'B', amount: 1, fee, nonce, spends: None, stake: None }
+    }
+
+    // each round, a cheap and a dear tx arrive; the dear one is mined in the
+    // next block, the cheap one only after `slow` blocks
+    fn simulate(estimator: &mut FeeEstimator, rounds: usize, slow: usize) {
+        let mut waiting: Vec<(usize, Tx)> = vec![];
+        for height in 0..rounds {
+            let cheap = tx(0.1, height as u64 * 2);
+            let dear = tx(1.0, height as u64 * 2 + 1);
+            estimator.track(cheap.id(), cheap.fee_rate(), height);
+            estimator.track(dear.id(), dear.fee_rate(), height);
+
+            let mut block = vec![dear];
+            waiting.push((height + slow, cheap));
+            block.extend(waiting.iter().filter(|(due, _)| *due == height + 1).map(|(_, t)| t.clone()));
+            estimator.process_block(height + 1, &block);
+        }
+    }
+
+    #[test]
+    fn test_estimate_follows_confirmation_times() {
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(estimator.estimate_fee(1), None);
+
+        simulate(&mut estimator, 50, 5);
+
+        let dear = tx(1.0, 0).fee_rate();
+        let cheap = tx(0.1, 0).fee_rate();
+
+        // only the dear txs make it into the next block
+        let fast = estimator.estimate_fee(1).unwrap();
+        assert!(fast <= dear && fast > cheap);
+
+        // within 5 blocks, the cheap ones do too
+        let slow = estimator.estimate_fee(5).unwrap();
+        assert!(slow <= cheap);
+        assert!(estimator.estimate_fee(MAX_TARGET + 10).unwrap() <= cheap);
+    }
+
+    #[test]
+    fn test_evicted_txs_count_as_misses() {
+        let mut estimator = FeeEstimator::new();
+        for i in 0..20 {
+            let t = tx(0.1, i);
+            estimator.track(t.id(), t.fee_rate(), 0);
+        }
+        estimator.sweep(|_| false);
+        estimator.process_block(1, &[]);
+
+        assert_eq!(estimator.estimate_fee(MAX_TARGET), None);
+    }
+
+    #[test]
+    fn test_replayed_blocks_are_ignored() {
+        let mut estimator = FeeEstimator::new();
+        simulate(&mut estimator, 30, 1);
+        let before = estimator.estimate_fee(1);
+
+        let t = tx(0.1, 1000);
+        estimator.track(t.id(), t.fee_rate(), 3);
+        estimator.process_block(4, &[t]);
+        assert_eq!(estimator.estimate_fee(1), before);
+        assert_eq!(estimator.best_height, 30);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut estimator = FeeEstimator::new();
+        simulate(&mut estimator, 30, 3);
+
+        let path = std::env::temp_dir().join(format!("fees-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        estimator.save(path).unwrap();
+
+        let loaded = FeeEstimator::load(path).unwrap();
+        for target in 1..=MAX_TARGET {
+            assert_eq!(loaded.estimate_fee(target), estimator.estimate_fee(target));
+        }
+        assert_eq!(loaded.best_height, 30);
+
+        // a truncated row is turned away rather than indexed out of bounds
+        let mut json: serde_json::Value = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        json["confirmed"][7].as_array_mut().unwrap().pop();
+        std::fs::write(path, json.to_string()).unwrap();
+        assert_eq!(FeeEstimator::load(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_restart_skips_blocks_already_seen() {
+        let path = "test_fee_estimates_restart.json";
+        let mut estimator = FeeEstimator::new();
+        simulate(&mut estimator, 30, 1);
+        estimator.save(path).unwrap();
+        let mut loaded = FeeEstimator::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // syncing again from genesis replays blocks the saved estimates counted
+        let t = tx(0.1, 1000);
+        let b = bucket(t.fee_rate());
+        let total = loaded.total[b];
+        loaded.track(t.id(), t.fee_rate(), 0);
+        loaded.process_block(1, std::slice::from_ref(&t));
+        assert_eq!(loaded.best_height, 30);
+        assert_eq!(loaded.total[b], total);
+
+        // past the saved tip, blocks count again
+        loaded.track(t.id(), t.fee_rate(), 30);
+        loaded.process_block(31, &[t]);
+        assert_eq!(loaded.best_height, 31);
+        assert!(loaded.total[b] > total * DECAY);
+    }
+}
@@ -1,63 +1,681 @@
-use std::cmp::Ordering;
-use crate::types::Tx;
+mod policy;
+mod orphans;
+mod persist;
+mod estimator;
 
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::ops::Bound;
+use std::time::SystemTime;
+use crate::blockchain::{Block, Clock, State, TxError};
+use crate::types::{Tx, FeeRate};
+use crate::events::{EvictReason, Event, Events};
+use orphans::Orphans;
+
+pub use policy::{MempoolError, Policy};
+pub use estimator::{FeeEstimator, MAX_TARGET};
+
+// orders by fee rate, highest first, with the txid breaking ties
+type FeeKey = (Reverse<FeeRate>, String);
+
+/// Pending txs, indexed by txid, by sender and by fee rate so that lookups,
+/// inserts, removals and taking the best paying txs are all logarithmic.
+///
+/// A tx depends on the sender's previous pending nonce and on the pending tx
+/// it `spends`, if any. Both links are derived from the indexes, so they stay
+/// right whichever order txs arrive in.
+///
+/// Every tx is valid against the chain state once its pending ancestors are
+/// applied, and is checked again whenever the chain moves.
 pub struct Mempool {
-    txs: Vec<Tx>,
+    txs: HashMap<String, Tx>,
+    by_fee_rate: BTreeSet<FeeKey>,
+    by_sender: HashMap<char, BTreeMap<u64, String>>,
+    by_spends: HashMap<String, HashSet<String>>,
+    by_age: BTreeSet<(SystemTime, String)>,
+    entered: HashMap<String, SystemTime>,
+    orphans: Orphans,
+    state: State,
+    estimator: FeeEstimator,
+    policy: Policy,
+    bytes: usize,
+    events: Events,
+
+    // what expiry and the fee floor's decay are timed by, so stopping the
+    // chain's clock stops them too
+    clock: Clock,
+
+    // raised whenever txs get evicted, then decays back to the policy's floor
+    rolling_min_fee: f64,
+    rolling_since: SystemTime,
 }
 
 impl Mempool {
+    #[cfg(test)]
     pub fn new() -> Self {
+        Mempool::with_policy(Policy::default())
+    }
+
+    /// Starts out accepting any balance, until `update` hands it the chain
+    /// state.
+    pub fn with_policy(policy: Policy) -> Self {
         Mempool {
-            txs: vec![],
+            txs: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            by_sender: HashMap::new(),
+            by_spends: HashMap::new(),
+            by_age: BTreeSet::new(),
+            entered: HashMap::new(),
+            orphans: Orphans::new(policy.max_orphans, policy.orphan_expiry),
+            state: State::new(f64::INFINITY),
+            estimator: FeeEstimator::new(),
+            policy,
+            bytes: 0,
+            events: Events::default(),
+            clock: Clock::default(),
+            rolling_min_fee: 0.0,
+            rolling_since: SystemTime::now(),
         }
     }
 
-    pub fn add(&mut self, tx: Tx) -> bool {
+    /// Reports txs getting in, and leaving without being mined, to `events`.
+    pub fn set_events(&mut self, events: Events) {
+        self.events = events;
+    }
+
+    /// Times txs by `clock`, normally the chain's.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.rolling_since = clock.now();
+        self.clock = clock;
+    }
+
+    /// Adds `tx`, returning any orphans that were waiting on it and got in
+    /// along with it.
+    pub fn add(&mut self, tx: Tx) -> Result<Vec<Tx>, MempoolError> {
+        self.add_at(tx, self.clock.now())
+    }
+
+    fn add_at(&mut self, tx: Tx, now: SystemTime) -> Result<Vec<Tx>, MempoolError> {
         if self.contains(&tx) {
-            return false;
+            return Err(MempoolError::Duplicate);
+        }
+
+        let rate = tx.fee_rate();
+        if rate.0 < self.min_fee_rate_at(now) {
+            return Err(MempoolError::FeeTooLow);
+        }
+
+        if self.is_cyclic(&tx) {
+            return Err(MempoolError::Cycle);
+        }
+
+        if let Err(e) = self.validate(&tx) {
+            // it might be spending a payment we haven't seen yet
+            let unknown_parent = tx.spends.as_ref().is_some_and(|p| !self.txs.contains_key(p));
+            if e == TxError::InsufficientBalance && unknown_parent {
+                self.orphans.add(tx, now);
+                return Err(MempoolError::Orphan);
+            }
+            return Err(MempoolError::Invalid(e));
+        }
+
+        let conflicts = self.conflicts(&tx);
+        if !conflicts.is_empty() {
+            self.check_replacement(&tx, &conflicts)?;
+        }
+        // what's left built on the replaced txs, to check against `tx` instead
+        let rebased: Vec<String> = conflicts.iter()
+            .flat_map(|id| self.descendants(id))
+            .filter(|id| !conflicts.iter().any(|c| c == id))
+            .map(String::from)
+            .collect();
+        let replaced: Vec<(Tx, SystemTime)> = conflicts.iter()
+            .filter_map(|id| self.take(id))
+            .collect();
+
+        if let Err(e) = self.make_room(&tx, now) {
+            for (old, entered) in replaced {
+                self.insert(old.id(), old, entered);
+            }
+            return Err(e);
+        }
+
+        for (old, _) in &replaced {
+            self.events.emit(Event::MempoolEvict { id: old.id(), reason: EvictReason::Replaced });
+        }
+
+        let id = tx.id();
+        self.estimator.track(id.clone(), rate, self.state.height());
+        self.events.emit(Event::NewTx { id: id.clone(), tx: tx.clone() });
+        self.insert(id.clone(), tx, now);
+
+        // making room might have evicted some of them already
+        let rebased = rebased.iter().map(String::as_str).filter(|id| self.txs.contains_key(*id)).collect();
+        let invalid = self.invalid(rebased);
+        for id in invalid {
+            self.evict(&id, EvictReason::Invalid);
+        }
+
+        let mut resolved = vec![];
+        for orphan in self.orphans.take_children(&id) {
+            if let Ok(more) = self.add_at(orphan.clone(), now) {
+                resolved.push(orphan);
+                resolved.extend(more);
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Whether `tx` depends on one of the sender's own pending txs at or past
+    /// its nonce, by spending it or something built on it. Those would in turn
+    /// depend on `tx`, so none of them could ever be mined.
+    fn is_cyclic(&self, tx: &Tx) -> bool {
+        let ancestors = self.walk(self.pending_parents(tx), |id| self.parents(id));
+        ancestors.iter().any(|id| {
+            let ancestor = &self.txs[*id];
+            ancestor.from == tx.from && ancestor.nonce >= tx.nonce
+        })
+    }
+
+    /// Checks `tx` against the chain state with its pending ancestors
+    /// applied first.
+    fn validate(&self, tx: &Tx) -> Result<(), TxError> {
+        let ancestors = self.walk(self.pending_parents(tx), |id| self.parents(id));
+
+        let mut overlay = self.state.overlay();
+        for id in self.in_order(ancestors) {
+            let _ = overlay.apply(&self.txs[id]);
         }
+        overlay.check(tx)
+    }
+
+    /// Brings the pool in line with a new chain tip, `blocks` being the ones
+    /// that just got added: drops the txs that were mined and the ones
+    /// waiting too long, then re-checks the rest against `state`. Returns
+    /// whatever no longer applies.
+    pub fn update(&mut self, blocks: &[Block], state: State) -> Vec<Tx> {
+        self.update_at(blocks, state, self.clock.now())
+    }
 
-        // if fee is higher, replace
-        for (i, t) in self.txs.iter().enumerate() {
-            if t.from == tx.from && t.to == tx.to && t.fee < tx.fee {
-                self.txs[i] = tx;
-                return true;
+    fn update_at(&mut self, blocks: &[Block], state: State, now: SystemTime) -> Vec<Tx> {
+        // a block only takes coins and nonces away from the senders of its
+        // txs, so theirs are the only pending txs it can invalidate
+        let mut senders: HashSet<char> = HashSet::new();
+        let first_height = state.height() + 1 - blocks.len();
+        for (i, block) in blocks.iter().enumerate() {
+            self.estimator.process_block(first_height + i, block.get_all());
+            for tx in block.get_all() {
+                senders.insert(tx.from);
+                self.remove(tx);
             }
         }
+        self.state = state;
 
-        // otherwise, add
-        self.txs.push(tx);
-        true
+        self.orphans.expire(now);
+        let expired: Vec<String> = match now.checked_sub(self.policy.expiry) {
+            Some(cutoff) => self.by_age
+                .iter()
+                .take_while(|(entered, _)| *entered <= cutoff)
+                .map(|(_, id)| id.clone())
+                .collect(),
+            None => vec![],
+        };
+        // these might not apply without the expired txs they build on
+        let orphaned: Vec<String> = expired.iter()
+            .flat_map(|id| self.descendants(id))
+            .map(String::from)
+            .collect();
+        let mut removed: Vec<Tx> = expired.iter().filter_map(|id| self.evict(id, EvictReason::Expired)).collect();
+
+        let stale: Vec<&str> = match blocks.is_empty() {
+            // a whole new state, anything might have changed
+            true => self.txs.keys().map(String::as_str).collect(),
+            false => senders.iter()
+                .filter_map(|from| self.by_sender.get(from))
+                .flat_map(|nonces| nonces.values().map(String::as_str))
+                .chain(orphaned.iter().filter_map(|id| self.txs.get_key_value(id).map(|(id, _)| id.as_str())))
+                .collect(),
+        };
+        let invalid = self.invalid(stale);
+        removed.extend(invalid.iter().filter_map(|id| self.evict(id, EvictReason::Invalid)));
+
+        let txs = &self.txs;
+        self.estimator.sweep(|id| txs.contains_key(id));
+
+        removed
+    }
+
+    /// Brings the pool in line after the chain switched branches: drops the
+    /// txs the `connected` blocks mined, checks the rest against `state`
+    /// all over again, and takes back whatever only the `disconnected` ones
+    /// had mined. Returns whatever no longer applies.
+    pub fn reorg(&mut self, disconnected: &[Block], connected: &[Block], state: State) -> Vec<Tx> {
+        let now = self.clock.now();
+        let mut removed = self.update_at(connected, state, now);
+
+        // coins the old branch paid out might be gone, whoever they went to
+        let invalid = self.invalid(self.txs.keys().map(String::as_str).collect());
+        removed.extend(invalid.iter().filter_map(|id| self.evict(id, EvictReason::Invalid)));
+
+        let mined: HashSet<String> = connected.iter().flat_map(Block::get_all).map(Tx::id).collect();
+        for tx in disconnected.iter().flat_map(Block::get_all) {
+            if !mined.contains(&tx.id()) {
+                let _ = self.add_at(tx.clone(), now);
+            }
+        }
+        removed
+    }
+
+    /// Which of the `stale` txs, and everything built on them, no longer
+    /// apply on top of the ancestors they still count on.
+    fn invalid(&self, stale: Vec<&str>) -> Vec<String> {
+        let checked = self.walk(stale, |id| self.children(id));
+        let parents = checked.iter().flat_map(|id| self.parents(id)).collect();
+        let context = self.walk(parents, |id| self.parents(id));
+
+        let mut overlay = self.state.overlay();
+        self.in_order(checked.iter().chain(&context).copied())
+            .into_iter()
+            .filter(|id| overlay.apply(&self.txs[*id]).is_err() && checked.contains(id))
+            .map(String::from)
+            .collect()
+    }
+
+    /// The fee rate needed to get mined within `target_blocks`.
+    pub fn estimate_fee(&self, target_blocks: usize) -> Option<FeeRate> {
+        self.estimator.estimate_fee(target_blocks)
+    }
+
+    pub fn fee_estimator(&self) -> &FeeEstimator {
+        &self.estimator
+    }
+
+    /// Takes over estimates loaded from disk.
+    pub fn set_fee_estimator(&mut self, estimator: FeeEstimator) {
+        self.estimator = estimator;
+    }
+
+    /// The tx using the same sender nonce, if any, followed by the txs
+    /// spending what it paid out, or what those did in turn, which can't stay
+    /// without it. The sender's later nonces don't need its coins, so they
+    /// only get checked again once it's replaced.
+    fn conflicts(&self, tx: &Tx) -> Vec<String> {
+        let conflict = match self.by_sender.get(&tx.from).and_then(|n| n.get(&tx.nonce)) {
+            Some(id) => id,
+            None => return vec![],
+        };
+
+        let spenders = |id: &str| self.by_spends.get(id)
+            .map(|ids| ids.iter().map(String::as_str).collect())
+            .unwrap_or_default();
+        let mut conflicts = vec![conflict.clone()];
+        conflicts.extend(self.walk(spenders(conflict), spenders).into_iter().map(String::from));
+        conflicts
+    }
+
+    /// A replacement has to pay more than everything it evicts, plus enough
+    /// on top to cover relaying it.
+    fn check_replacement(&self, tx: &Tx, conflicts: &[String]) -> Result<(), MempoolError> {
+        if conflicts.len() - 1 > self.policy.max_replaced_descendants {
+            return Err(MempoolError::TooManyReplacements);
+        }
+
+        let replaced_fees: f64 = conflicts.iter().map(|id| self.txs[id].fee as f64).sum();
+        let increment = self.policy.incremental_fee_rate * tx.size() as f64;
+        let fee = tx.fee as f64;
+        if fee <= replaced_fees || fee - replaced_fees < increment {
+            return Err(MempoolError::ReplacementFeeTooLow);
+        }
+
+        Ok(())
+    }
+
+    /// Evicts the lowest fee rate txs, along with their descendants, until
+    /// `tx` fits. Nothing paying `tx`'s fee rate or more gets evicted, and
+    /// neither does anything `tx` depends on.
+    fn make_room(&mut self, tx: &Tx, now: SystemTime) -> Result<(), MempoolError> {
+        let (size, rate) = (tx.size(), tx.fee_rate());
+        // `validate` counted on these being applied first
+        let ancestors = self.walk(self.pending_parents(tx), |id| self.parents(id));
+        let mut victims: HashSet<&str> = HashSet::new();
+        let mut bytes = self.bytes;
+        let mut evicted_rate: Option<FeeRate> = None;
+
+        for (Reverse(victim_rate), id) in self.by_fee_rate.iter().rev() {
+            if bytes.saturating_add(size) <= self.policy.max_bytes {
+                break;
+            }
+            if *victim_rate >= rate {
+                return Err(MempoolError::MempoolFull);
+            }
+            // a victim's descendants go with it, so leaving out the
+            // ancestors themselves is enough
+            if victims.contains(id.as_str()) || ancestors.contains(id.as_str()) {
+                continue;
+            }
+
+            // children can't stay without their parent, so a parent whose
+            // children pay better than the newcomer is left alone
+            let mut package = self.descendants(id);
+            package.insert(id);
+            package.retain(|d| !victims.contains(d));
+            if package.iter().any(|d| self.txs[*d].fee_rate() >= rate) {
+                continue;
+            }
+
+            let totals = self.totals(package.iter().copied());
+            bytes -= totals.1;
+            evicted_rate = evicted_rate.max(Some(package_rate(totals)));
+            victims.extend(package);
+        }
+
+        if bytes.saturating_add(size) > self.policy.max_bytes {
+            return Err(MempoolError::MempoolFull);
+        }
+        let victims: Vec<String> = victims.into_iter().map(String::from).collect();
+
+        if let Some(evicted_rate) = evicted_rate {
+            self.rolling_min_fee = self.min_fee_rate_at(now)
+                .max(evicted_rate.0 + self.policy.incremental_fee_rate);
+            self.rolling_since = now;
+        }
+
+        for id in victims {
+            self.evict(&id, EvictReason::Full);
+        }
+        Ok(())
+    }
+
+    /// Total fee and size of a set of txs.
+    fn totals<'a>(&'a self, ids: impl Iterator<Item = &'a str>) -> (f64, usize) {
+        ids.map(|id| &self.txs[id])
+            .fold((0.0, 0), |(fee, size), tx| (fee + tx.fee as f64, size + tx.size()))
+    }
+
+    fn parents(&self, id: &str) -> Vec<&str> {
+        self.pending_parents(&self.txs[id])
+    }
+
+    /// The pending txs `tx` depends on, whether or not it's in the pool.
+    fn pending_parents(&self, tx: &Tx) -> Vec<&str> {
+        let mut parents = vec![];
+
+        let previous = self.by_sender.get(&tx.from)
+            .and_then(|nonces| nonces.range(..tx.nonce).next_back());
+        if let Some((_, parent)) = previous {
+            parents.push(parent.as_str());
+        }
+        if let Some((parent, _)) = tx.spends.as_ref().and_then(|p| self.txs.get_key_value(p)) {
+            parents.push(parent.as_str());
+        }
+
+        parents
+    }
+
+    fn children(&self, id: &str) -> Vec<&str> {
+        let tx = &self.txs[id];
+        let mut children = vec![];
+
+        let next = self.by_sender.get(&tx.from)
+            .and_then(|nonces| nonces.range((Bound::Excluded(tx.nonce), Bound::Unbounded)).next());
+        if let Some((_, child)) = next {
+            children.push(child.as_str());
+        }
+        if let Some(spenders) = self.by_spends.get(id) {
+            children.extend(spenders.iter().map(String::as_str));
+        }
+
+        children
+    }
+
+    fn walk<'a>(&'a self, mut queue: Vec<&'a str>, step: impl Fn(&'a str) -> Vec<&'a str>) -> HashSet<&'a str> {
+        let mut found = HashSet::new();
+
+        while let Some(next) = queue.pop() {
+            if found.insert(next) {
+                queue.extend(step(next));
+            }
+        }
+
+        found
+    }
+
+    /// Sorts txs so that parents come before their children, following only
+    /// the links between the txs given, so it takes one pass however long a
+    /// sender's chain of pending txs gets.
+    fn in_order<'a>(&'a self, ids: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+        let ids: HashSet<&str> = ids.into_iter().collect();
+
+        // how many of its parents each tx is still waiting on; a tx spending
+        // the sender's previous tx counts that parent twice, and is counted
+        // twice among its children too
+        let mut waiting: HashMap<&str, usize> = HashMap::new();
+        let mut ready = BinaryHeap::new();
+        for &id in &ids {
+            match self.parents(id).into_iter().filter(|p| ids.contains(p)).count() {
+                0 => ready.push(Reverse(id)),
+                n => { waiting.insert(id, n); },
+            }
+        }
+
+        let mut sorted = Vec::with_capacity(ids.len());
+        while let Some(Reverse(id)) = ready.pop() {
+            sorted.push(id);
+            for child in self.children(id) {
+                if let Some(n) = waiting.get_mut(child) {
+                    *n -= 1;
+                    if *n == 0 {
+                        waiting.remove(child);
+                        ready.push(Reverse(child));
+                    }
+                }
+            }
+        }
+        sorted
+    }
+
+    /// Every pending tx that has to be mined before `id`.
+    pub fn ancestors(&self, id: &str) -> HashSet<&str> {
+        if !self.txs.contains_key(id) {
+            return HashSet::new();
+        }
+        self.walk(self.parents(id), |id| self.parents(id))
+    }
+
+    /// Every pending tx that can only be mined after `id`.
+    pub fn descendants(&self, id: &str) -> HashSet<&str> {
+        if !self.txs.contains_key(id) {
+            return HashSet::new();
+        }
+        self.walk(self.children(id), |id| self.children(id))
+    }
+
+    /// Picks up to `max_txs` txs totalling at most `max_bytes` for a block,
+    /// best ancestor package fee rate first, so a well paying child pulls its
+    /// parents in with it. Parents always come before their children.
+    pub fn select(&self, max_txs: usize, max_bytes: usize) -> Vec<Tx> {
+        // fee and size of each tx plus its ancestors not yet selected
+        let mut packages: HashMap<&str, (f64, usize)> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        for id in self.txs.keys() {
+            let mut package = self.ancestors(id);
+            package.insert(id);
+            let totals = self.totals(package.into_iter());
+            packages.insert(id, totals);
+            heap.push((package_rate(totals), Reverse(id.as_str())));
+        }
+
+        let mut selected: HashSet<&str> = HashSet::new();
+        let mut block = vec![];
+        let mut bytes = 0;
+
+        while let Some((rate, Reverse(id))) = heap.pop() {
+            if block.len() >= max_txs {
+                break;
+            }
+            // stale entry, the package shrank since it was pushed
+            if selected.contains(id) || package_rate(packages[id]) != rate {
+                continue;
+            }
+
+            let mut package: Vec<&str> = self.ancestors(id)
+                .into_iter()
+                .filter(|a| !selected.contains(a))
+                .collect();
+            package.push(id);
+            // a smaller package further down might still fit
+            if block.len() + package.len() > max_txs || bytes + packages[id].1 > max_bytes {
+                continue;
+            }
+            bytes += packages[id].1;
+
+            for id in self.in_order(package) {
+                selected.insert(id);
+                block.push(self.txs[id].clone());
+
+                let tx = &self.txs[id];
+                for d in self.descendants(id) {
+                    if selected.contains(d) {
+                        continue;
+                    }
+                    let (fee, size) = packages[d];
+                    let totals = (fee - tx.fee as f64, size - tx.size());
+                    packages.insert(d, totals);
+                    heap.push((package_rate(totals), Reverse(d)));
+                }
+            }
+        }
+
+        block
+    }
+
+    /// The fee rate a tx currently needs to get in.
+    pub fn min_fee_rate(&self) -> f64 {
+        self.min_fee_rate_at(self.clock.now())
+    }
+
+    fn min_fee_rate_at(&self, now: SystemTime) -> f64 {
+        let elapsed = now.duration_since(self.rolling_since).unwrap_or_default().as_secs_f64();
+        let half_lives = elapsed / self.policy.fee_half_life.as_secs_f64();
+        let decayed = self.rolling_min_fee * 0.5f64.powf(half_lives);
+
+        decayed.max(self.policy.min_relay_fee_rate)
+    }
+
+    fn insert(&mut self, id: String, tx: Tx, entered: SystemTime) {
+        self.by_age.insert((entered, id.clone()));
+        self.entered.insert(id.clone(), entered);
+        self.bytes += tx.size();
+        self.by_fee_rate.insert((Reverse(tx.fee_rate()), id.clone()));
+        self.by_sender.entry(tx.from).or_default().insert(tx.nonce, id.clone());
+        if let Some(parent) = &tx.spends {
+            self.by_spends.entry(parent.clone()).or_default().insert(id.clone());
+        }
+        self.txs.insert(id, tx);
     }
 
     pub fn remove(&mut self, tx: &Tx) -> bool {
-        if let Some(i) = self.txs.iter().position(|t| *t == *tx) {
-            self.txs.remove(i);
-            return true;
+        self.remove_by_id(&tx.id()).is_some()
+    }
+
+    pub fn remove_by_id(&mut self, id: &str) -> Option<Tx> {
+        self.take(id).map(|(tx, _)| tx)
+    }
+
+    // takes a tx out without it being mined
+    fn evict(&mut self, id: &str, reason: EvictReason) -> Option<Tx> {
+        let tx = self.remove_by_id(id)?;
+        self.events.emit(Event::MempoolEvict { id: id.to_string(), reason });
+        Some(tx)
+    }
+
+    fn take(&mut self, id: &str) -> Option<(Tx, SystemTime)> {
+        let tx = self.txs.remove(id)?;
+
+        let entered = self.entered.remove(id)?;
+        self.by_age.remove(&(entered, id.to_string()));
+        self.bytes -= tx.size();
+        self.by_fee_rate.remove(&(Reverse(tx.fee_rate()), id.to_string()));
+        if let Some(ids) = self.by_sender.get_mut(&tx.from) {
+            ids.remove(&tx.nonce);
+            if ids.is_empty() {
+                self.by_sender.remove(&tx.from);
+            }
         }
-        false
+        if let Some(parent) = &tx.spends {
+            if let Some(ids) = self.by_spends.get_mut(parent) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.by_spends.remove(parent);
+                }
+            }
+        }
+
+        Some((tx, entered))
     }
 
-    pub fn get_all(&mut self) -> &Vec<Tx> {
-        self.txs.sort_by(|a, b| {
-            if a.fee < b.fee { Ordering::Greater } else { Ordering::Less }
-        });
-        &self.txs
+    /// The nonce `from`'s next tx takes, after its pending ones.
+    pub fn next_nonce(&self, from: char) -> u64 {
+        match self.by_sender.get(&from).and_then(|nonces| nonces.keys().next_back()) {
+            Some(last) => last + 1,
+            None => self.state.next_nonce(from),
+        }
+    }
+
+    /// The pending tx with id `id`, and when it got in.
+    pub fn get(&self, id: &str) -> Option<(&Tx, SystemTime)> {
+        Some((self.txs.get(id)?, *self.entered.get(id)?))
+    }
+
+    /// The `n` txs paying the highest fee rate, best first.
+    pub fn top(&self, n: usize) -> Vec<Tx> {
+        self.by_fee_rate
+            .iter()
+            .take(n)
+            .map(|(_, id)| self.txs[id].clone())
+            .collect()
+    }
+
+    /// Every tx, best fee rate first.
+    pub fn get_all(&self) -> Vec<Tx> {
+        self.top(self.txs.len())
+    }
+
+    /// A sender's txs, lowest nonce first.
+    #[cfg(test)]
+    pub fn by_sender(&self, from: char) -> Vec<&Tx> {
+        match self.by_sender.get(&from) {
+            Some(ids) => ids.values().map(|id| &self.txs[id]).collect(),
+            None => vec![],
+        }
     }
 
     pub fn len(&self) -> usize {
         self.txs.len()
     }
 
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
     fn contains(&self, tx: &Tx) -> bool {
-        self.txs.contains(tx)
+        self.txs.contains_key(&tx.id())
     }
 }
 
+fn package_rate((fee, size): (f64, usize)) -> FeeRate {
+    FeeRate(fee / size.max(1) as f64)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::mempool::Mempool;
-    use crate::types::Tx;
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant, SystemTime};
+    use crate::mempool::*;
 
     #[test]
     fn test_add_once() {
@@ -68,11 +686,14 @@ mod tests {
             to: 'B',
             amount: 1,
             fee: 0.123,
+            nonce: 0,
+            spends: None,
+            stake: None,
         };
         let tx2 = tx1.clone();
 
-        assert_eq!(mp.add(tx1), true);
-        assert_eq!(mp.add(tx2), false);
+        assert_eq!(mp.add(tx1), Ok(vec![]));
+        assert_eq!(mp.add(tx2), Err(MempoolError::Duplicate));
         assert_eq!(mp.len(), 1);
     }
 
@@ -85,23 +706,507 @@ mod tests {
             to: 'B',
             amount: 1,
             fee: 0.123,
+            nonce: 0,
+            spends: None,
+            stake: None,
         };
         let mut tx2 = tx1.clone();
         tx2.fee = 0.456;
 
-        assert_eq!(mp.add(tx1), true);
-        assert_eq!(mp.add(tx2), true);
+        assert_eq!(mp.add(tx1), Ok(vec![]));
+        assert_eq!(mp.add(tx2), Ok(vec![]));
         assert_eq!(mp.len(), 1);
         assert_eq!(mp.get_all()[0].fee, 0.456);
     }
 
+    #[test]
+    fn test_unrelated_payments_are_not_replaced() {
+        let mut mp = Mempool::new();
+
+        let tx1 = Tx { from: 'A', to: 'B', amount: 1, fee: 0.123, nonce: 0, spends: None, stake: None };
+        let tx2 = Tx { from: 'A', to: 'B', amount: 5, fee: 0.456, nonce: 1, spends: None, stake: None };
+
+        assert_eq!(mp.add(tx1), Ok(vec![]));
+        assert_eq!(mp.add(tx2), Ok(vec![]));
+        assert_eq!(mp.len(), 2);
+    }
+
+    fn rbf_mempool() -> Mempool {
+        Mempool::with_policy(Policy {
+            incremental_fee_rate: 0.001,
+            max_replaced_descendants: 2,
+            ..Policy::default()
+        })
+    }
+
+    #[test]
+    fn test_replacement_needs_higher_fee() {
+        let mut mp = rbf_mempool();
+        let original = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(original.clone()), Ok(vec![]));
+
+        // same nonce, different payment, same or lower fee
+        let cheaper = Tx { from: 'A', to: 'C', amount: 2, fee: 0.4, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(cheaper), Err(MempoolError::ReplacementFeeTooLow));
+        let equal = Tx { from: 'A', to: 'C', amount: 2, fee: 0.5, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(equal), Err(MempoolError::ReplacementFeeTooLow));
+
+        assert_eq!(mp.get_all(), vec![original]);
+    }
+
+    #[test]
+    fn test_replacement_needs_minimum_increment() {
+        let mut mp = rbf_mempool();
+        let original = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(original), Ok(vec![]));
+
+        let nudge = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5001, nonce: 0, spends: None, stake: None };
+        assert!(nudge.fee as f64 - 0.5 < 0.001 * nudge.size() as f64);
+        assert_eq!(mp.add(nudge), Err(MempoolError::ReplacementFeeTooLow));
+
+        let bump = Tx { from: 'A', to: 'B', amount: 1, fee: 0.6, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(bump.clone()), Ok(vec![]));
+        assert_eq!(mp.get_all(), vec![bump]);
+    }
+
+    #[test]
+    fn test_replacement_pays_for_evicted_descendants() {
+        let mut mp = rbf_mempool();
+        let a0 = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let a1 = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 1, spends: None, stake: None };
+        let b0 = Tx { from: 'B', to: 'C', amount: 1, fee: 0.5, nonce: 0, spends: Some(a0.id()), stake: None };
+        let other = Tx { from: 'C', to: 'D', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        for t in [&a0, &a1, &b0, &other] {
+            mp.add(t.clone()).unwrap();
+        }
+
+        // beats the tx it conflicts with, but not the 1.0 it would evict
+        // along with the tx spending it
+        let replacement = Tx { from: 'A', to: 'B', amount: 2, fee: 0.9, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(replacement), Err(MempoolError::ReplacementFeeTooLow));
+        assert_eq!(mp.len(), 4);
+
+        // A's later nonce doesn't count against it, and stays
+        let replacement = Tx { from: 'A', to: 'B', amount: 2, fee: 1.5, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(replacement.clone()), Ok(vec![]));
+        assert_eq!(mp.by_sender('A'), vec![&replacement, &a1]);
+        assert!(!mp.contains(&b0));
+        assert!(mp.contains(&other));
+    }
+
+    #[test]
+    fn test_replacement_limits_evicted_descendants() {
+        let mut mp = rbf_mempool();
+        let mut txs: Vec<Tx> = vec![];
+        for (from, to) in [('A', 'B'), ('B', 'C'), ('C', 'D'), ('D', 'E')] {
+            let t = Tx { from, to, amount: 1, fee: 0.1, nonce: 0, spends: txs.last().map(Tx::id), stake: None };
+            mp.add(t.clone()).unwrap();
+            txs.push(t);
+        }
+        // later nonces aren't evicted, so don't count either
+        for nonce in 1..4 {
+            mp.add(Tx { from: 'A', to: 'B', amount: 1, fee: 0.1, nonce, spends: None, stake: None }).unwrap();
+        }
+
+        // A's first tx has three spending it, one more than allowed
+        let replacement = Tx { from: 'A', to: 'B', amount: 1, fee: 5.0, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(replacement), Err(MempoolError::TooManyReplacements));
+
+        // B's only has two
+        let replacement = Tx { from: 'B', to: 'C', amount: 1, fee: 5.0, nonce: 0, spends: Some(txs[0].id()), stake: None };
+        assert_eq!(mp.add(replacement), Ok(vec![]));
+        assert_eq!(mp.len(), 5);
+    }
+
+    #[test]
+    fn test_replacement_rechecks_later_nonces() {
+        let mut mp = rbf_mempool();
+        mp.update(&[], State::new(10.0));
+        for nonce in 0..3 {
+            mp.add(Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce, spends: None, stake: None }).unwrap();
+        }
+
+        // still leaves enough for nonce 1, but not for nonce 2 after it
+        let replacement = Tx { from: 'A', to: 'B', amount: 6, fee: 2.0, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(replacement.clone()), Ok(vec![]));
+        let kept: Vec<u64> = mp.by_sender('A').iter().map(|t| t.nonce).collect();
+        assert_eq!(kept, vec![0, 1]);
+    }
+
+    fn tx(from: char, to: char, fee: f32, nonce: u64, spends: Option<&Tx>) -> Tx {
+        Tx { from, to, amount: 1, fee, nonce, spends: spends.map(Tx::id), stake: None }
+    }
+
+    #[test]
+    fn test_ancestors_and_descendants() {
+        let mut mp = Mempool::new();
+
+        let a0 = tx('A', 'B', 0.1, 0, None);
+        let a1 = tx('A', 'B', 0.1, 1, None);
+        let b0 = tx('B', 'C', 0.1, 0, Some(&a1));
+        let c0 = tx('C', 'D', 0.1, 0, None);
+
+        // children arriving first get linked once their parents show up
+        for t in [&b0, &a1, &c0, &a0] {
+            mp.add(t.clone()).unwrap();
+        }
+
+        let ids = |txs: &[&Tx]| txs.iter().map(|t| t.id()).collect::<HashSet<_>>();
+        let owned = |set: HashSet<&str>| set.into_iter().map(String::from).collect::<HashSet<_>>();
+
+        assert_eq!(owned(mp.ancestors(&b0.id())), ids(&[&a0, &a1]));
+        assert_eq!(owned(mp.descendants(&a0.id())), ids(&[&a1, &b0]));
+        assert!(mp.ancestors(&c0.id()).is_empty());
+        assert!(mp.descendants(&c0.id()).is_empty());
+
+        mp.remove(&a1);
+        assert!(mp.ancestors(&b0.id()).is_empty());
+        assert!(mp.descendants(&a0.id()).is_empty());
+    }
+
+    #[test]
+    fn test_in_order() {
+        let mut mp = Mempool::new();
+
+        let a0 = tx('A', 'B', 0.1, 0, None);
+        // linked to a0 both by nonce and by spending it
+        let a1 = tx('A', 'B', 0.9, 1, Some(&a0));
+        let b0 = tx('B', 'C', 0.5, 0, Some(&a1));
+        let a2 = tx('A', 'B', 0.1, 2, None);
+        for t in [&b0, &a2, &a1, &a0] {
+            mp.add(t.clone()).unwrap();
+        }
+
+        let (a0, a1, b0, a2) = (a0.id(), a1.id(), b0.id(), a2.id());
+        let sorted = mp.in_order([&*a2, &*b0, &*a1, &*a0]);
+        let at = |id: &str| sorted.iter().position(|s| *s == id).unwrap();
+        assert_eq!(sorted.len(), 4);
+        assert!(at(&a0) < at(&a1) && at(&a1) < at(&b0) && at(&a1) < at(&a2));
+
+        // parents left out don't hold their children back
+        assert_eq!(mp.in_order([&*b0, &*a2]).len(), 2);
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let mut mp = Mempool::new();
+
+        let parent = tx('A', 'B', 0.01, 0, None);
+        let child = tx('B', 'C', 2.0, 0, Some(&parent));
+        let other = tx('D', 'E', 0.5, 0, None);
+        for t in [&parent, &child, &other] {
+            mp.add(t.clone()).unwrap();
+        }
+
+        // the child alone pays best, but needs its parent first
+        assert_eq!(mp.select(2, usize::MAX), vec![parent.clone(), child.clone()]);
+        assert_eq!(mp.select(3, usize::MAX), vec![parent.clone(), child.clone(), other.clone()]);
+
+        // not enough room for the package
+        assert_eq!(mp.select(1, usize::MAX), vec![other.clone()]);
+        assert_eq!(mp.select(3, other.size()), vec![other.clone()]);
+
+        let package = parent.size() + child.size();
+        assert_eq!(mp.select(3, package + other.size() - 1), vec![parent, child]);
+    }
+
+    #[test]
+    fn test_select_is_topological() {
+        let mut mp = Mempool::new();
+        let mut txs: Vec<Tx> = vec![];
+        let wallets: Vec<char> = "ABCDE".chars().collect();
+
+        for i in 0..40 {
+            let from = wallets[i % wallets.len()];
+            let to = wallets[(i + 1) % wallets.len()];
+            let spends = txs.iter().rev().find(|t| t.to == from);
+            let fee = ((i * 7919) % 97) as f32 / 100.0 + 0.01;
+            let t = tx(from, to, fee, (i / wallets.len()) as u64, spends);
+            txs.push(t);
+        }
+        for t in txs.iter().rev() {
+            mp.add(t.clone()).unwrap();
+        }
+
+        for max in [1, 5, 17, 40] {
+            let block = mp.select(max, usize::MAX);
+            assert!(block.len() <= max);
+
+            let mut seen = HashSet::new();
+            for t in &block {
+                let id = t.id();
+                for parent in mp.ancestors(&id) {
+                    assert!(seen.contains(parent), "parent mined after child");
+                }
+                seen.insert(id);
+            }
+        }
+        assert_eq!(mp.select(40, usize::MAX).len(), 40);
+    }
+
+    #[test]
+    fn test_eviction_keeps_parents_of_better_children() {
+        let parent = tx('A', 'B', 0.01, 0, None);
+        let child = tx('B', 'C', 2.0, 0, Some(&parent));
+        let cheap = tx('C', 'D', 0.02, 0, None);
+
+        let mut mp = Mempool::with_policy(Policy {
+            max_bytes: parent.size() + child.size() + cheap.size(),
+            ..Policy::default()
+        });
+        for t in [&parent, &child, &cheap] {
+            mp.add(t.clone()).unwrap();
+        }
+
+        let newcomer = tx('E', 'F', 0.5, 0, None);
+        assert_eq!(mp.add(newcomer.clone()), Ok(vec![]));
+        assert!(mp.contains(&parent));
+        assert!(mp.contains(&child));
+        assert!(!mp.contains(&cheap));
+    }
+
+    #[test]
+    fn test_eviction_keeps_parents_of_newcomer() {
+        let parent = tx('A', 'B', 0.01, 0, None);
+        let cheap = tx('C', 'D', 0.05, 0, None);
+        let child = tx('A', 'B', 0.20, 1, None);
+        let spender = tx('B', 'C', 0.20, 0, Some(&parent));
+
+        let mut mp = Mempool::with_policy(Policy {
+            max_bytes: parent.size() + cheap.size().max(child.size()),
+            ..Policy::default()
+        });
+        for t in [&parent, &cheap] {
+            mp.add(t.clone()).unwrap();
+        }
+
+        // the parent pays the least, but the child can't do without it
+        assert_eq!(mp.add(child.clone()), Ok(vec![]));
+        assert!(mp.contains(&parent));
+        assert!(mp.contains(&child));
+        assert!(!mp.contains(&cheap));
+
+        // the only cheaper tx is the parent, so there's no room for a tx
+        // spending it
+        let dear = tx('E', 'F', 0.50, 0, None);
+        let mut mp = Mempool::with_policy(Policy {
+            max_bytes: parent.size() + dear.size(),
+            ..Policy::default()
+        });
+        for t in [&parent, &dear] {
+            mp.add(t.clone()).unwrap();
+        }
+        assert_eq!(mp.add(spender), Err(MempoolError::MempoolFull));
+        assert!(mp.contains(&parent));
+    }
+
+    fn funded(balance: f64) -> Mempool {
+        let mut mp = Mempool::new();
+        mp.update(&[], State::new(balance));
+        mp
+    }
+
+    #[test]
+    fn test_add_checks_balance_with_pending_ancestors() {
+        let mut mp = funded(10.0);
+
+        let a0 = Tx { from: 'A', to: 'B', amount: 6, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let a1 = Tx { from: 'A', to: 'B', amount: 6, fee: 0.5, nonce: 1, spends: None, stake: None };
+        assert_eq!(mp.add(a0.clone()), Ok(vec![]));
+        assert_eq!(mp.add(a1), Err(MempoolError::Invalid(TxError::InsufficientBalance)));
+
+        // B can spend what A's pending tx pays it
+        let b0 = Tx { from: 'B', to: 'C', amount: 15, fee: 0.5, nonce: 0, spends: Some(a0.id()), stake: None };
+        assert_eq!(mp.add(b0), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_add_rejects_cyclic_spends() {
+        let mut mp = funded(10.0);
+
+        let a0 = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let a1 = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 1, spends: None, stake: None };
+        let b0 = Tx { from: 'B', to: 'A', amount: 1, fee: 0.5, nonce: 0, spends: Some(a1.id()), stake: None };
+        for t in [&a0, &a1, &b0] {
+            mp.add(t.clone()).unwrap();
+        }
+
+        // replacing A's first tx with one spending its own next nonce, or
+        // what that paid out, could never be mined
+        let cyclic = Tx { from: 'A', to: 'C', amount: 1, fee: 5.0, nonce: 0, spends: Some(a1.id()), stake: None };
+        assert_eq!(mp.add(cyclic), Err(MempoolError::Cycle));
+        let cyclic = Tx { from: 'A', to: 'C', amount: 1, fee: 5.0, nonce: 0, spends: Some(b0.id()), stake: None };
+        assert_eq!(mp.add(cyclic), Err(MempoolError::Cycle));
+        assert_eq!(mp.len(), 3);
+    }
+
+    #[test]
+    fn test_reorg_takes_back_disconnected_txs() {
+        let mut mp = funded(10.0);
+
+        // B spends what a block paid it
+        let paid = Tx { from: 'A', to: 'B', amount: 8, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let old = Block::new(1, String::new(), vec![paid.clone()], 0, 0);
+        let mut state = State::new(10.0);
+        assert!(state.apply_block(old.get_all()).is_empty());
+        mp.update(std::slice::from_ref(&old), state);
+        let spender = Tx { from: 'B', to: 'C', amount: 15, fee: 0.5, nonce: 0, spends: None, stake: None };
+        mp.add(spender.clone()).unwrap();
+
+        // the branch that wins never paid B, so the payment is pending again
+        // and B can't afford its tx until it's mined
+        let new = Block::new(2, String::new(), vec![], 0, 0);
+        let mut state = State::new(10.0);
+        state.apply_block(new.get_all());
+        let dropped = mp.reorg(&[old], &[new], state);
+        assert_eq!(dropped, vec![spender]);
+        assert_eq!(mp.get_all(), vec![paid]);
+    }
+
+    #[test]
+    fn test_update_drops_txs_conflicting_with_chain() {
+        let mut mp = funded(10.0);
+
+        let paid = Tx { from: 'A', to: 'B', amount: 5, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let later = Tx { from: 'A', to: 'C', amount: 4, fee: 0.5, nonce: 1, spends: None, stake: None };
+        let other = Tx { from: 'D', to: 'E', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        for t in [&paid, &later, &other] {
+            mp.add(t.clone()).unwrap();
+        }
+
+        // a block spends most of A's balance in a tx we never saw, reusing
+        // the nonce of `paid`
+        let double_spend = Tx { from: 'A', to: 'F', amount: 9, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let block = Block::new(1, String::new(), vec![double_spend], 0, 0);
+        let mut state = State::new(10.0);
+        assert!(state.apply_block(block.get_all()).is_empty());
+
+        let dropped = mp.update(&[block], state);
+        assert_eq!(dropped.len(), 2);
+        assert!(dropped.contains(&paid));
+        assert!(dropped.contains(&later));
+        assert_eq!(mp.get_all(), vec![other]);
+    }
+
+    #[test]
+    fn test_update_rechecks_descendants() {
+        let mut mp = Mempool::with_policy(Policy {
+            expiry: Duration::from_secs(60),
+            ..Policy::default()
+        });
+        mp.update(&[], State::new(10.0));
+        let now = SystemTime::now();
+
+        // B can only afford `spender` with what `paid` gives it
+        let paid = Tx { from: 'A', to: 'B', amount: 5, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let spender = Tx { from: 'B', to: 'C', amount: 14, fee: 0.5, nonce: 0, spends: Some(paid.id()), stake: None };
+        let parent = Tx { from: 'D', to: 'E', amount: 8, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let child = Tx { from: 'E', to: 'F', amount: 15, fee: 0.5, nonce: 0, spends: Some(parent.id()), stake: None };
+        mp.add_at(parent.clone(), now).unwrap();
+        for t in [&paid, &spender, &child] {
+            mp.add_at(t.clone(), now + Duration::from_secs(30)).unwrap();
+        }
+
+        // A's nonce gets used up by a tx we never saw, while `parent` expires
+        let double_spend = Tx { from: 'A', to: 'G', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let block = Block::new(1, String::new(), vec![double_spend], 0, 0);
+        let mut state = State::new(10.0);
+        assert!(state.apply_block(block.get_all()).is_empty());
+
+        let dropped = mp.update_at(&[block], state, now + Duration::from_secs(60));
+        assert_eq!(dropped.len(), 4);
+        for t in [&paid, &spender, &parent, &child] {
+            assert!(dropped.contains(t));
+        }
+        assert_eq!(mp.len(), 0);
+    }
+
+    #[test]
+    fn test_update_expires_old_txs() {
+        let mut mp = Mempool::with_policy(Policy {
+            expiry: Duration::from_secs(60),
+            ..Policy::default()
+        });
+        let now = SystemTime::now();
+
+        let old = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let new = Tx { from: 'C', to: 'D', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        mp.add_at(old.clone(), now).unwrap();
+        mp.add_at(new.clone(), now + Duration::from_secs(30)).unwrap();
+
+        let state = State::new(f64::INFINITY);
+        assert!(mp.update_at(&[], state.clone(), now + Duration::from_secs(59)).is_empty());
+        assert_eq!(mp.update_at(&[], state, now + Duration::from_secs(60)), vec![old]);
+        assert_eq!(mp.get_all(), vec![new]);
+    }
+
+    #[test]
+    fn test_expiry_follows_mock_time() {
+        let mut mp = Mempool::with_policy(Policy {
+            expiry: Duration::from_secs(60),
+            ..Policy::default()
+        });
+        let clock = Clock::default();
+        clock.set_mock_time(1_600_000_000_000);
+        mp.set_clock(clock.clone());
+
+        let old = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+        mp.add(old.clone()).unwrap();
+        assert!(mp.update(&[], State::new(f64::INFINITY)).is_empty());
+
+        clock.set_mock_time(1_600_000_060_000);
+        assert_eq!(mp.update(&[], State::new(f64::INFINITY)), vec![old]);
+    }
+
+    #[test]
+    fn test_orphans_wait_for_their_parent() {
+        let mut mp = funded(10.0);
+
+        let parent = Tx { from: 'A', to: 'B', amount: 8, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let child = Tx { from: 'B', to: 'C', amount: 15, fee: 0.5, nonce: 0, spends: Some(parent.id()), stake: None };
+        let grandchild = Tx { from: 'C', to: 'D', amount: 20, fee: 0.5, nonce: 0, spends: Some(child.id()), stake: None };
+
+        assert_eq!(mp.add(grandchild.clone()), Err(MempoolError::Orphan));
+        assert_eq!(mp.add(child.clone()), Err(MempoolError::Orphan));
+        assert_eq!(mp.orphan_count(), 2);
+        assert_eq!(mp.len(), 0);
+
+        assert_eq!(mp.add(parent), Ok(vec![child, grandchild]));
+        assert_eq!(mp.orphan_count(), 0);
+        assert_eq!(mp.len(), 3);
+
+        // spending more than the parent could ever pay isn't an orphan
+        let greedy = Tx { from: 'E', to: 'F', amount: 50, fee: 0.5, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(greedy), Err(MempoolError::Invalid(TxError::InsufficientBalance)));
+    }
+
+    #[test]
+    fn test_orphan_pool_is_bounded() {
+        let mut mp = Mempool::with_policy(Policy { max_orphans: 3, ..Policy::default() });
+        mp.update(&[], State::new(0.0));
+
+        for i in 0..10 {
+            let orphan = Tx {
+                from: 'A',
+                to: 'B',
+                amount: 1,
+                fee: 0.5,
+                nonce: i,
+                spends: Some(format!("unknown{}", i)),
+                stake: None,
+            };
+            assert_eq!(mp.add(orphan), Err(MempoolError::Orphan));
+        }
+        assert_eq!(mp.orphan_count(), 3);
+    }
+
     #[test]
     fn test_get_all() {
         let mut mp = Mempool::new();
 
-        mp.add(Tx { from: 'A', to: 'B', amount: 1, fee: 0.234 });
-        mp.add(Tx { from: 'B', to: 'C', amount: 1, fee: 0.345 });
-        mp.add(Tx { from: 'C', to: 'D', amount: 1, fee: 0.123 });
+        mp.add(Tx { from: 'A', to: 'B', amount: 1, fee: 0.234, nonce: 0, spends: None, stake: None }).unwrap();
+        mp.add(Tx { from: 'B', to: 'C', amount: 1, fee: 0.345, nonce: 0, spends: None, stake: None }).unwrap();
+        mp.add(Tx { from: 'C', to: 'D', amount: 1, fee: 0.123, nonce: 0, spends: None, stake: None }).unwrap();
 
         let txs = mp.get_all();
         assert_eq!(txs[0].fee, 0.345);
@@ -109,13 +1214,194 @@ mod tests {
         assert_eq!(txs[2].fee, 0.123);
     }
 
+    #[test]
+    fn test_top_and_by_sender() {
+        let mut mp = Mempool::new();
+
+        mp.add(Tx { from: 'A', to: 'B', amount: 1, fee: 0.234, nonce: 0, spends: None, stake: None }).unwrap();
+        mp.add(Tx { from: 'A', to: 'C', amount: 1, fee: 0.345, nonce: 1, spends: None, stake: None }).unwrap();
+        mp.add(Tx { from: 'C', to: 'D', amount: 1, fee: 0.123, nonce: 0, spends: None, stake: None }).unwrap();
+
+        let top = mp.top(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].fee, 0.345);
+        assert_eq!(top[1].fee, 0.234);
+
+        assert_eq!(mp.by_sender('A').len(), 2);
+        assert_eq!(mp.by_sender('B').len(), 0);
+
+        let tx = mp.top(1).remove(0);
+        assert!(mp.contains(&tx));
+        assert!(mp.remove(&tx));
+        assert_eq!(mp.by_sender('A').len(), 1);
+        assert_eq!(mp.top(1)[0].fee, 0.234);
+    }
+
+    fn full_mempool() -> (Mempool, Tx, Tx) {
+        let cheap = Tx { from: 'A', to: 'B', amount: 1, fee: 0.100, nonce: 0, spends: None, stake: None };
+        let dear = Tx { from: 'C', to: 'D', amount: 1, fee: 0.300, nonce: 0, spends: None, stake: None };
+
+        // room for exactly two txs of this size
+        let mut mp = Mempool::with_policy(Policy {
+            max_bytes: cheap.size() * 2,
+            incremental_fee_rate: 0.0001,
+            ..Policy::default()
+        });
+        mp.add(cheap.clone()).unwrap();
+        mp.add(dear.clone()).unwrap();
+
+        (mp, cheap, dear)
+    }
+
+    #[test]
+    fn test_full_mempool_evicts_lowest_fee_rate() {
+        let (mut mp, cheap, dear) = full_mempool();
+
+        let worse = Tx { from: 'E', to: 'F', amount: 1, fee: 0.050, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(worse), Err(MempoolError::MempoolFull));
+
+        let better = Tx { from: 'E', to: 'F', amount: 1, fee: 0.200, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add(better.clone()), Ok(vec![]));
+        assert_eq!(mp.len(), 2);
+        assert!(!mp.contains(&cheap));
+        assert!(mp.contains(&dear));
+        assert!(mp.contains(&better));
+        assert!(mp.bytes() <= cheap.size() * 2);
+    }
+
+    #[test]
+    fn test_next_nonce() {
+        let mut mp = Mempool::new();
+        assert_eq!(mp.next_nonce('A'), 0);
+        mp.add(tx('A', 'B', 0.1, 0, None)).unwrap();
+        mp.add(tx('A', 'B', 0.1, 1, None)).unwrap();
+        assert_eq!(mp.next_nonce('A'), 2);
+        assert_eq!(mp.next_nonce('B'), 0);
+    }
+
+    #[test]
+    fn test_events() {
+        let (mut mp, cheap, _) = full_mempool();
+        let events = Events::new(16);
+        let mut rx = events.subscribe().unwrap();
+        mp.set_events(events);
+
+        let better = tx('E', 'F', 0.200, 0, None);
+        mp.add(better.clone()).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), Event::MempoolEvict { id: cheap.id(), reason: EvictReason::Full });
+        assert_eq!(rx.try_recv().unwrap(), Event::NewTx { id: better.id(), tx: better.clone() });
+
+        let replacement = tx('E', 'F', 0.500, 0, None);
+        mp.add(replacement.clone()).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), Event::MempoolEvict { id: better.id(), reason: EvictReason::Replaced });
+        assert_eq!(rx.try_recv().unwrap(), Event::NewTx { id: replacement.id(), tx: replacement });
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_min_fee_rises_after_eviction_and_decays() {
+        let (mut mp, cheap, _) = full_mempool();
+        assert_eq!(mp.min_fee_rate(), 0.0);
+
+        let now = SystemTime::now();
+        mp.add_at(Tx { from: 'E', to: 'F', amount: 1, fee: 0.200, nonce: 0, spends: None, stake: None }, now).unwrap();
+        let raised = cheap.fee_rate().0 + 0.0001;
+        assert!((mp.min_fee_rate_at(now) - raised).abs() < 1e-9);
+
+        // paying what the evicted tx paid is no longer enough
+        mp.remove(&Tx { from: 'C', to: 'D', amount: 1, fee: 0.300, nonce: 0, spends: None, stake: None });
+        let again = Tx { from: 'G', to: 'H', amount: 1, fee: 0.100, nonce: 0, spends: None, stake: None };
+        assert_eq!(mp.add_at(again.clone(), now), Err(MempoolError::FeeTooLow));
+
+        // one half-life later the minimum has halved
+        let later = now + Duration::from_secs(600);
+        assert!((mp.min_fee_rate_at(later) - raised / 2.0).abs() < 1e-9);
+        assert_eq!(mp.add_at(again, later), Ok(vec![]));
+    }
+
     #[test]
     fn test_remove() {
         let mut mp = Mempool::new();
-        let tx = Tx { from: 'A', to: 'B', amount: 1, fee: 0.234 };
+        let tx = Tx { from: 'A', to: 'B', amount: 1, fee: 0.234, nonce: 0, spends: None, stake: None };
+
+        assert_eq!(mp.add(tx.clone()), Ok(vec![]));
+        assert!(mp.remove(&tx));
+        assert!(!mp.remove(&tx));
+    }
+
+    fn bench(txs: Vec<Tx>) {
+        let count = txs.len();
+        let mut mp = Mempool::new();
+        let start = Instant::now();
+        for tx in &txs {
+            mp.add(tx.clone()).unwrap();
+        }
+        println!("add {} txs: {:?}", count, start.elapsed());
+
+        let start = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(mp.top(1000).len(), 1000);
+        }
+        println!("top 1000 x 100: {:?}", start.elapsed());
 
-        assert_eq!(mp.add(tx.clone()), true);
-        assert_eq!(mp.remove(&tx), true);
-        assert_eq!(mp.remove(&tx), false);
+        let start = Instant::now();
+        assert_eq!(mp.select(5000, usize::MAX).len(), 5000);
+        println!("select 5000: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let mined = Block::new(1, String::new(), txs[..5000].to_vec(), 0, 0);
+        let mut state = State::new(f64::INFINITY);
+        state.apply_block(mined.get_all());
+        assert!(mp.update(&[mined], state).is_empty());
+        println!("update after a 5000 tx block: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        for tx in &txs[5000..] {
+            assert!(mp.contains(tx));
+        }
+        println!("contains {} txs: {:?}", count - 5000, start.elapsed());
+
+        let start = Instant::now();
+        for tx in &txs[5000..] {
+            assert!(mp.remove(tx));
+        }
+        println!("remove {} txs: {:?}", count - 5000, start.elapsed());
+        assert_eq!(mp.len(), 0);
+    }
+
+    // cargo test --release bench_mempool -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_mempool_100k() {
+        let txs = (0..100_000)
+            .map(|i| Tx {
+                from: std::char::from_u32(0x10000 + i as u32).unwrap(),
+                to: 'Z',
+                amount: 1,
+                fee: (i % 1000) as f32 / 1000.0 + 0.001,
+                nonce: 0,
+                spends: None,
+                stake: None,
+            })
+            .collect();
+        bench(txs);
+    }
+
+    // 1000 senders with 100 pending txs each
+    #[test]
+    #[ignore]
+    fn bench_mempool_100k_chains() {
+        let txs = (0..100_000)
+            .map(|i| Tx {
+                from: std::char::from_u32(0x10000 + (i % 1000) as u32).unwrap(),
+                to: 'Z',
+                amount: 1,
+                fee: (i % 997) as f32 / 1000.0 + 0.001,
+                nonce: (i / 1000) as u64,
+                spends: None,
+                stake: None,
+            })
+            .collect();
+        bench(txs);
     }
 }
@@ -0,0 +1,83 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+use crate::types::Tx;
+
+/// Txs that spend a payment we haven't seen yet, held until it shows up.
+/// Bounded, since nothing stops a peer from making up parents.
+pub struct Orphans {
+    txs: HashMap<String, (Tx, SystemTime)>,
+    by_parent: HashMap<String, HashSet<String>>,
+    max: usize,
+    expiry: Duration,
+}
+
+impl Orphans {
+    pub fn new(max: usize, expiry: Duration) -> Self {
+        Orphans {
+            txs: HashMap::new(),
+            by_parent: HashMap::new(),
+            max,
+            expiry,
+        }
+    }
+
+    pub fn add(&mut self, tx: Tx, now: SystemTime) {
+        let parent = match &tx.spends {
+            Some(parent) => parent.clone(),
+            None => return,
+        };
+        let id = tx.id();
+        if self.max == 0 || self.txs.contains_key(&id) {
+            return;
+        }
+
+        // make room by dropping the oldest
+        while self.txs.len() >= self.max {
+            let oldest = self.txs
+                .iter()
+                .min_by_key(|(_, (_, time))| *time)
+                .map(|(id, _)| id.clone());
+            match oldest {
+                Some(id) => self.remove(&id),
+                None => break,
+            };
+        }
+
+        self.by_parent.entry(parent).or_default().insert(id.clone());
+        self.txs.insert(id, (tx, now));
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Tx> {
+        let (tx, _) = self.txs.remove(id)?;
+        if let Some(parent) = &tx.spends {
+            if let Some(ids) = self.by_parent.get_mut(parent) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.by_parent.remove(parent);
+                }
+            }
+        }
+        Some(tx)
+    }
+
+    /// Removes and returns the orphans waiting on `parent`.
+    pub fn take_children(&mut self, parent: &str) -> Vec<Tx> {
+        let ids = self.by_parent.remove(parent).unwrap_or_default();
+        ids.iter().filter_map(|id| self.txs.remove(id).map(|(tx, _)| tx)).collect()
+    }
+
+    pub fn expire(&mut self, now: SystemTime) {
+        let expired: Vec<String> = self.txs
+            .iter()
+            .filter(|(_, (_, time))| now.duration_since(*time).unwrap_or_default() >= self.expiry)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.remove(&id);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use crate::mempool::Mempool;
+use crate::types::Tx;
+
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    tx: Tx,
+    entered_ms: u64,
+}
+
+impl Mempool {
+    /// Writes every tx along with when it entered, oldest first. Goes
+    /// through a temp file so a crash mid-write leaves the last dump intact.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let saved: Vec<Saved> = self.by_age
+            .iter()
+            .map(|(entered, id)| Saved {
+                tx: self.txs[id].clone(),
+                entered_ms: entered.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            })
+            .collect();
+
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, serde_json::to_vec(&saved)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Adds back the txs written by `save`, checking each against the
+    /// current state and keeping its entry time. Returns how many got in.
+    pub fn load(&mut self, path: &str) -> io::Result<usize> {
+        let saved: Vec<Saved> = serde_json::from_slice(&fs::read(path)?)?;
+        let now = self.clock.now();
+
+        let mut loaded = 0;
+        for Saved { tx, entered_ms } in saved {
+            let entered = UNIX_EPOCH + Duration::from_millis(entered_ms);
+            if now.duration_since(entered).unwrap_or_default() >= self.policy.expiry {
+                continue;
+            }
+
+            if let Ok(resolved) = self.add_at(tx, entered) {
+                loaded += 1 + resolved.len();
+            }
+        }
+
+        Ok(loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use crate::blockchain::State;
+    use crate::mempool::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn tx(from: char, amount: i32) -> Tx {
+        Tx { from, to: 'Z', amount, fee: 0.5, nonce: 0, spends: None, stake: None }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = temp_path("mempool-roundtrip");
+        let policy = Policy { expiry: Duration::from_secs(60), ..Policy::default() };
+
+        let mut mp = Mempool::with_policy(policy.clone());
+        let now = SystemTime::now();
+        mp.add_at(tx('A', 1), now - Duration::from_secs(50)).unwrap();
+        mp.add_at(tx('B', 1), now).unwrap();
+        mp.save(&path).unwrap();
+
+        let mut restored = Mempool::with_policy(policy);
+        assert_eq!(restored.load(&path).unwrap(), 2);
+        assert_eq!(restored.len(), 2);
+
+        // the older tx kept its age and expires on time
+        let later = SystemTime::now() + Duration::from_secs(11);
+        let expired = restored.update_at(&[], State::new(f64::INFINITY), later);
+        assert_eq!(expired, vec![tx('A', 1)]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_keeps_ages_longer_than_uptime() {
+        let path = temp_path("mempool-old");
+        let century = Duration::from_secs(100 * 365 * 24 * 3600);
+        let policy = Policy { expiry: century, ..Policy::default() };
+
+        // in since the first second of 1970, before the host booted
+        let mut mp = Mempool::with_policy(policy.clone());
+        let entered = UNIX_EPOCH + Duration::from_secs(1);
+        mp.add_at(tx('A', 1), entered).unwrap();
+        mp.save(&path).unwrap();
+
+        let mut restored = Mempool::with_policy(policy);
+        assert_eq!(restored.load(&path).unwrap(), 1);
+        assert_eq!(restored.get(&tx('A', 1).id()).unwrap().1, entered);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_revalidates() {
+        let path = temp_path("mempool-revalidate");
+
+        let mut mp = Mempool::new();
+        mp.add(tx('A', 5)).unwrap();
+        mp.add(tx('B', 50)).unwrap();
+        mp.save(&path).unwrap();
+
+        let mut restored = Mempool::new();
+        restored.update(&[], State::new(10.0));
+        assert_eq!(restored.load(&path).unwrap(), 1);
+        assert_eq!(restored.get_all(), vec![tx('A', 5)]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::time::Duration;
+use crate::blockchain::TxError;
+use crate::config::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MempoolError {
+    Duplicate,
+    FeeTooLow,
+    MempoolFull,
+    ReplacementFeeTooLow,
+    TooManyReplacements,
+    Invalid(TxError),
+    Orphan,
+    Cycle,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::Duplicate => write!(f, "already in mempool"),
+            MempoolError::FeeTooLow => write!(f, "fee rate below the relay minimum"),
+            MempoolError::MempoolFull => write!(f, "mempool full"),
+            MempoolError::ReplacementFeeTooLow => write!(f, "fee too low to replace the conflicting txs"),
+            MempoolError::TooManyReplacements => write!(f, "replacement would evict too many txs"),
+            MempoolError::Invalid(e) => write!(f, "{}", e),
+            MempoolError::Orphan => write!(f, "spends an unknown tx"),
+            MempoolError::Cycle => write!(f, "spends a tx that builds on it"),
+        }
+    }
+}
+
+/// Limits the mempool enforces on what it accepts. Fee rates are in fee per
+/// serialized byte.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub max_bytes: usize,
+    pub min_relay_fee_rate: f64,
+
+    // added on top of the best evicted fee rate, so the pool has to fill
+    // with strictly better txs before evicting again. replacements must
+    // also add at least this much per byte over the fees they evict
+    pub incremental_fee_rate: f64,
+
+    // how many of a replaced tx's descendants a replacement may take with it
+    pub max_replaced_descendants: usize,
+
+    // how quickly the minimum raised by evictions falls back to the floor
+    pub fee_half_life: Duration,
+
+    // how long a tx may wait to be mined before it's dropped
+    pub expiry: Duration,
+
+    pub max_orphans: usize,
+    pub orphan_expiry: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            max_bytes: usize::MAX,
+            min_relay_fee_rate: 0.0,
+            incremental_fee_rate: 0.0,
+            max_replaced_descendants: 100,
+            fee_half_life: Duration::from_secs(600),
+            expiry: Duration::from_secs(86400),
+            max_orphans: 100,
+            orphan_expiry: Duration::from_secs(1200),
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_settings() -> Self {
+        Policy {
+            max_bytes: SETTINGS.get::<usize>("max_mempool_bytes").unwrap(),
+            min_relay_fee_rate: SETTINGS.get::<f64>("min_relay_fee_rate").unwrap(),
+            incremental_fee_rate: SETTINGS.get::<f64>("incremental_fee_rate").unwrap(),
+            max_replaced_descendants: SETTINGS.get::<usize>("max_replaced_descendants").unwrap(),
+            fee_half_life: Duration::from_secs(SETTINGS.get::<u64>("fee_half_life_secs").unwrap()),
+            expiry: Duration::from_secs(SETTINGS.get::<u64>("mempool_expiry_secs").unwrap()),
+            max_orphans: SETTINGS.get::<usize>("max_orphans").unwrap(),
+            orphan_expiry: Duration::from_secs(SETTINGS.get::<u64>("orphan_expiry_secs").unwrap()),
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use std::net::SocketAddr;
+
+/// Reads a peer address, either `host:port` or just a local port.
+pub fn parse_addr(addr: &str) -> Option<SocketAddr> {
+    match addr.parse::<u16>() {
+        Ok(port) => Some(SocketAddr::from(([127, 0, 0, 1], port))),
+        Err(_) => addr.parse().ok(),
+    }
+}
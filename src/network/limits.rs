@@ -0,0 +1,334 @@
+use std::io;
+use std::fmt;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use serde::ser::Serialize;
+use crate::blockchain::Block;
+use crate::types::Tx;
+use crate::config::*;
+
+// score added for each violation; two strikes with the default `ban_score`
+const VIOLATION_SCORE: u32 = 50;
+
+// the most peers whose scores or relay limits are kept; past that, the ones
+// least worth remembering make way
+pub const MAX_TRACKED_PEERS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    MessageTooLarge(usize),
+    BlockTooLarge(usize),
+    TooManyTxs(usize),
+    TxTooLarge(usize),
+    TooManyItems(usize),
+    MalformedHash,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::MessageTooLarge(n) => write!(f, "message of {} bytes is too large", n),
+            Violation::BlockTooLarge(n) => write!(f, "block of {} bytes is too large", n),
+            Violation::TooManyTxs(n) => write!(f, "block with {} txs has too many", n),
+            Violation::TxTooLarge(n) => write!(f, "tx of {} bytes is too large", n),
+            Violation::TooManyItems(n) => write!(f, "message with {} items has too many", n),
+            Violation::MalformedHash => write!(f, "block hash isn't 64 hex characters"),
+        }
+    }
+}
+
+impl From<Violation> for io::Error {
+    fn from(v: Violation) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, v.to_string())
+    }
+}
+
+pub fn serialized_size<T: Serialize>(item: &T) -> usize {
+    serde_json::to_vec(item).map(|v| v.len()).unwrap_or(usize::MAX)
+}
+
+/// For block hashes a peer hands us before there's a block to check them
+/// against, such as a compact block's.
+pub fn check_hash(hash: &str) -> Result<(), Violation> {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Violation::MalformedHash);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_message_size: usize,
+    pub max_block_size: usize,
+    pub max_block_txs: usize,
+    pub max_tx_size: usize,
+    pub max_sync_items: usize,
+}
+
+impl Limits {
+    pub fn from_settings() -> Self {
+        Limits {
+            max_message_size: SETTINGS.get::<usize>("max_message_size").unwrap(),
+            max_block_size: SETTINGS.get::<usize>("max_block_size").unwrap(),
+            max_block_txs: SETTINGS.get::<usize>("max_block_txs").unwrap(),
+            max_tx_size: SETTINGS.get::<usize>("max_tx_size").unwrap(),
+            max_sync_items: SETTINGS.get::<usize>("max_sync_items").unwrap(),
+        }
+    }
+
+    pub fn check_tx(&self, tx: &Tx) -> Result<(), Violation> {
+        let size = serialized_size(tx);
+        if size > self.max_tx_size {
+            return Err(Violation::TxTooLarge(size));
+        }
+        Ok(())
+    }
+
+    pub fn check_block(&self, block: &Block) -> Result<(), Violation> {
+        self.check_block_txs(block.get_all())?;
+
+        let size = serialized_size(block);
+        if size > self.max_block_size {
+            return Err(Violation::BlockTooLarge(size));
+        }
+
+        for tx in block.get_all() {
+            self.check_tx(tx)?;
+        }
+        Ok(())
+    }
+
+    /// For anything listing a block's txs, such as short ids or indexes.
+    pub fn check_block_txs<T>(&self, items: &[T]) -> Result<(), Violation> {
+        if items.len() > self.max_block_txs {
+            return Err(Violation::TooManyTxs(items.len()));
+        }
+        Ok(())
+    }
+
+    pub fn check_items<T>(&self, items: &[T]) -> Result<(), Violation> {
+        if items.len() > self.max_sync_items {
+            return Err(Violation::TooManyItems(items.len()));
+        }
+        Ok(())
+    }
+
+    /// Splits `items` into batches that each stay within both the item and
+    /// the message size limits, leaving some room for the envelope.
+    pub fn batches<T: Serialize + Clone>(&self, items: &[T]) -> Vec<Vec<T>> {
+        let budget = self.max_message_size / 2;
+        let mut batches = vec![];
+        let mut batch = vec![];
+        let mut size = 0;
+
+        for item in items {
+            let item_size = serialized_size(item);
+            if !batch.is_empty() && (batch.len() >= self.max_sync_items || size + item_size > budget) {
+                batches.push(batch);
+                batch = vec![];
+                size = 0;
+            }
+            size += item_size;
+            batch.push(item.clone());
+        }
+
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+        batches
+    }
+}
+
+struct Score {
+    score: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Misbehaviour scores, keyed by peer id. Peers reaching `ban_score` are
+/// refused for `ban_secs`.
+pub struct PeerScores {
+    scores: HashMap<String, Score>,
+    ban_score: u32,
+    ban_duration: Duration,
+}
+
+impl PeerScores {
+    pub fn new(ban_score: u32, ban_duration: Duration) -> Self {
+        PeerScores {
+            scores: HashMap::new(),
+            ban_score,
+            ban_duration,
+        }
+    }
+
+    pub fn from_settings() -> Self {
+        PeerScores::new(
+            SETTINGS.get::<u32>("ban_score").unwrap(),
+            Duration::from_secs(SETTINGS.get::<u64>("ban_secs").unwrap()),
+        )
+    }
+
+    /// Records a violation, returning true if the peer is now banned.
+    pub fn penalize(&mut self, peer: &str) -> bool {
+        if self.scores.len() >= MAX_TRACKED_PEERS && !self.scores.contains_key(peer) {
+            self.forget_one();
+        }
+        let entry = self.scores
+            .entry(peer.to_string())
+            .or_insert(Score { score: 0, banned_until: None });
+
+        entry.score += VIOLATION_SCORE;
+        if entry.score >= self.ban_score {
+            entry.score = 0;
+            entry.banned_until = Some(Instant::now() + self.ban_duration);
+            return true;
+        }
+        false
+    }
+
+    pub fn is_banned(&mut self, peer: &str) -> bool {
+        match self.scores.get_mut(peer) {
+            Some(Score { banned_until: Some(until), .. }) if *until > Instant::now() => true,
+            Some(entry) => {
+                entry.banned_until = None;
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// Drops the lowest scoring peer that isn't banned, or failing that the
+    /// ban that runs out first.
+    fn forget_one(&mut self) {
+        let now = Instant::now();
+        let least = self.scores
+            .iter()
+            .min_by_key(|(_, s)| match s.banned_until {
+                Some(until) if until > now => (1, s.score, until),
+                _ => (0, s.score, now),
+            })
+            .map(|(peer, _)| peer.clone());
+        if let Some(peer) = least {
+            self.scores.remove(&peer);
+        }
+    }
+}
+
+/// Token bucket allowing `rate` items per second with bursts up to `burst`.
+pub struct RateLimiter {
+    tokens: f64,
+    rate: f64,
+    burst: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            tokens: burst,
+            rate,
+            burst,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::limits::*;
+
+    fn limits() -> Limits {
+        Limits {
+            max_message_size: 1000,
+            max_block_size: 400,
+            max_block_txs: 3,
+            max_tx_size: 100,
+            max_sync_items: 4,
+        }
+    }
+
+    fn tx() -> Tx {
+        Tx { from: 'A', to: 'B', amount: 1, fee: 0.1, nonce: 0, spends: None, stake: None }
+    }
+
+    #[test]
+    fn test_check_block() {
+        let limits = limits();
+        let block = Block::new(1, String::new(), vec![tx(); 3], 0, 0);
+        assert_eq!(limits.check_block(&block), Ok(()));
+
+        let block = Block::new(1, String::new(), vec![tx(); 4], 0, 0);
+        assert_eq!(limits.check_block(&block), Err(Violation::TooManyTxs(4)));
+
+        let block = Block::new(1, "x".repeat(500), vec![], 0, 0);
+        assert!(matches!(limits.check_block(&block), Err(Violation::BlockTooLarge(_))));
+    }
+
+    #[test]
+    fn test_check_hash() {
+        assert_eq!(check_hash(&"0a".repeat(32)), Ok(()));
+        assert_eq!(check_hash("0a"), Err(Violation::MalformedHash));
+        assert_eq!(check_hash(&"0g".repeat(32)), Err(Violation::MalformedHash));
+        assert_eq!(check_hash(&"é".repeat(32)), Err(Violation::MalformedHash));
+    }
+
+    #[test]
+    fn test_batches() {
+        let limits = limits();
+        let txs = vec![tx(); 10];
+
+        let batches = limits.batches(&txs);
+        assert_eq!(batches.iter().map(|b| b.len()).sum::<usize>(), 10);
+        for batch in &batches {
+            assert_eq!(limits.check_items(batch), Ok(()));
+            assert!(serialized_size(batch) <= limits.max_message_size);
+        }
+    }
+
+    #[test]
+    fn test_ban_after_repeated_violations() {
+        let mut scores = PeerScores::new(100, Duration::from_secs(60));
+
+        assert!(!scores.penalize("peer"));
+        assert!(!scores.is_banned("peer"));
+        assert!(scores.penalize("peer"));
+        assert!(scores.is_banned("peer"));
+        assert!(!scores.is_banned("other"));
+    }
+
+    #[test]
+    fn test_bans_survive_a_full_table() {
+        let mut scores = PeerScores::new(100, Duration::from_secs(60));
+        scores.penalize("banned");
+        assert!(scores.penalize("banned"));
+
+        // a flood of new peers pushes out the others, never the ban
+        for i in 0..MAX_TRACKED_PEERS * 2 {
+            scores.penalize(&i.to_string());
+        }
+        assert_eq!(scores.scores.len(), MAX_TRACKED_PEERS);
+        assert!(scores.is_banned("banned"));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(0.0, 2.0);
+
+        assert!(limiter.allow());
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+    }
+}
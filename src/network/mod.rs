@@ -1,67 +1,619 @@
-use std::io::prelude::*;
-use std::net::{TcpStream, SocketAddr};
-use crate::types::*;
-use serde_json::Result;
+pub mod secure;
+pub mod limits;
+mod addr;
+
+use std::io;
+use std::time::Duration;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::clone::Clone;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
+pub use addr::parse_addr;
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use crate::types::*;
 use crate::config::*;
 
+lazy_static! {
+    // peers added while running, on top of the default ports
+    static ref ADDED_PEERS: Mutex<Vec<SocketAddr>> = Mutex::new(vec![]);
+}
+
 pub fn get_all_peers(ports: &[u16], local_port: u16) -> Vec<SocketAddr> {
     let mut pool: Vec<SocketAddr> = vec![];
 
-    let default_ports = &[4000 as u16, 4001, 4002, 4003, 4004];
+    let default_ports = &[4000_u16, 4001, 4002, 4003, 4004];
     let range = if ports.is_empty() { default_ports } else { ports };
 
     for port in range.iter().filter(|&p| *p != local_port) {
         pool.push(SocketAddr::from(([127, 0, 0, 1], *port)));
     }
+    if ports.is_empty() {
+        let local = SocketAddr::from(([127, 0, 0, 1], local_port));
+        for &addr in ADDED_PEERS.lock().unwrap().iter() {
+            if addr != local && !pool.contains(&addr) {
+                pool.push(addr);
+            }
+        }
+    }
 
     pool
 }
 
-pub fn get_live_peers(ports: &[u16], local_port: u16) -> Vec<SocketAddr> {
-    let mut peers: Vec<SocketAddr> = vec![];
+/// Adds a peer to talk to along with the default ports, returning false if
+/// it was already known.
+pub fn add_peer(addr: SocketAddr) -> bool {
+    let mut added = ADDED_PEERS.lock().unwrap();
+    if added.contains(&addr) {
+        return false;
+    }
+    added.push(addr);
+    true
+}
+
+/// Probes all peers at once, so a firewalled or half-open port costs a
+/// single connect timeout rather than stalling the others.
+pub async fn get_live_peers(ports: &[u16], local_port: u16) -> Vec<SocketAddr> {
+    let connect_timeout = setting_ms("connect_timeout_ms");
+    let mut probes = JoinSet::new();
 
     for addr in get_all_peers(ports, local_port) {
-        if TcpStream::connect(&addr).is_ok() {
+        probes.spawn(async move {
+            (addr, timeout(connect_timeout, TcpStream::connect(addr)).await)
+        });
+    }
+
+    let mut peers: Vec<SocketAddr> = vec![];
+    while let Some(probe) = probes.join_next().await {
+        if let Ok((addr, Ok(Ok(_)))) = probe {
             peers.push(addr);
         }
     }
 
+    peers.sort();
     peers
 }
 
-pub fn broadcast<T>(
-    action: ActionType,
-    payload: &T,
-    peers: &[u16],
-    local_port: u16,
-) -> Result<()>
-    where T: Clone + Serialize
-{
-    let pool = get_live_peers(peers, local_port);
-    if pool.is_empty() {
-        return Ok(())
+pub fn setting_ms(key: &str) -> Duration {
+    Duration::from_millis(SETTINGS.get::<u64>(key).unwrap())
+}
+
+fn timed_out(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", what))
+}
+
+/// A single peer connection; either newline-delimited plaintext json or a
+/// noise session, depending on `encrypt_transport`. The halves are split so
+/// reads and writes can run in separate tasks.
+pub struct Connection {
+    pub reader: MessageReader,
+    pub writer: MessageWriter,
+    // the remote's static key when encrypted; plaintext peers can only be
+    // told apart by IP, as they get a new port with every connection
+    pub peer_id: String,
+}
+
+impl Connection {
+    pub async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let connecting = async {
+            let stream = TcpStream::connect(addr).await?;
+            Connection::establish(stream, true).await
+        };
+
+        timeout(setting_ms("connect_timeout_ms"), connecting)
+            .await
+            .map_err(|_| timed_out("connect"))?
+    }
+
+    pub async fn accept(stream: TcpStream) -> io::Result<Self> {
+        timeout(setting_ms("connect_timeout_ms"), Connection::establish(stream, false))
+            .await
+            .map_err(|_| timed_out("handshake"))?
+    }
+
+    async fn establish(mut stream: TcpStream, initiator: bool) -> io::Result<Self> {
+        let mut peer_id = stream.peer_addr()?.ip().to_string();
+
+        let secure = if SETTINGS.get::<bool>("encrypt_transport").unwrap() {
+            let key = secure::local_key();
+            let allowed = SETTINGS.get::<Vec<String>>("allowed_peers").unwrap();
+
+            let (rd, wr, remote) = if initiator {
+                secure::initiate(&mut stream, &key, &allowed).await?
+            } else {
+                secure::respond(&mut stream, &key, &allowed).await?
+            };
+
+            peer_id = remote;
+            Some((rd, wr))
+        } else {
+            None
+        };
+
+        let (rd, wr) = stream.into_split();
+        let (secure_rd, secure_wr) = match secure {
+            Some((rd, wr)) => (Some(rd), Some(wr)),
+            None => (None, None),
+        };
+
+        Ok(Connection {
+            reader: MessageReader {
+                inner: BufReader::new(rd),
+                secure: secure_rd,
+                max_size: SETTINGS.get::<usize>("max_message_size").unwrap(),
+            },
+            writer: MessageWriter { inner: wr, secure: secure_wr },
+            peer_id,
+        })
+    }
+}
+
+pub struct MessageReader {
+    inner: BufReader<OwnedReadHalf>,
+    secure: Option<secure::SecureReader>,
+    max_size: usize,
+}
+
+impl MessageReader {
+    /// Returns `None` once the peer has closed the connection. Messages over
+    /// `max_message_size` fail with `InvalidData` without being buffered.
+    pub async fn recv(&mut self) -> io::Result<Option<String>> {
+        match &mut self.secure {
+            None => {
+                let mut text = String::new();
+                let limit = self.max_size as u64 + 1;
+                if (&mut self.inner).take(limit).read_line(&mut text).await? == 0 {
+                    return Ok(None);
+                }
+                if text.len() > self.max_size {
+                    return Err(limits::Violation::MessageTooLarge(text.len()).into());
+                }
+                Ok(Some(text))
+            },
+            Some(channel) => match channel.recv(&mut self.inner, self.max_size).await {
+                Ok(msg) => String::from_utf8(msg)
+                    .map(Some)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+pub struct MessageWriter {
+    inner: OwnedWriteHalf,
+    secure: Option<secure::SecureWriter>,
+}
+
+impl MessageWriter {
+    pub async fn send(&mut self, msg: &str) -> io::Result<()> {
+        match &mut self.secure {
+            None => self.inner.write_all(format!("{}\n", msg).as_bytes()).await,
+            Some(channel) => channel.send(&mut self.inner, msg.as_bytes()).await,
+        }
+    }
+}
+
+/// Drains `queue` into `writer` until the queue closes or a write fails or
+/// times out.
+pub async fn write_loop(mut writer: MessageWriter, mut queue: mpsc::Receiver<String>) {
+    let write_timeout = setting_ms("write_timeout_ms");
+
+    while let Some(msg) = queue.recv().await {
+        match timeout(write_timeout, writer.send(&msg)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => {
+                println!("write failed, {}", e);
+                break;
+            },
+            Err(_) => {
+                println!("write timed out");
+                break;
+            },
+        }
+    }
+}
+
+/// A message read off any session, inbound or outbound. Replies go back on
+/// the same connection through `reply`; the session waits on `done` before
+/// reading the next message, and closes if it carries an error.
+pub struct Incoming {
+    pub text: String,
+    pub peer_id: String,
+    pub reply: mpsc::Sender<String>,
+    pub done: oneshot::Sender<io::Result<()>>,
+}
+
+/// Runs a session until the peer hangs up, goes idle or has a message
+/// rejected. Incoming messages are handed to `inbox` one at a time, while
+/// anything queued on `queue` is written out.
+pub async fn run_session(
+    conn: Connection,
+    queue: mpsc::Receiver<String>,
+    reply: mpsc::Sender<String>,
+    inbox: Option<mpsc::Sender<Incoming>>,
+) -> io::Result<()> {
+    let Connection { mut reader, writer, peer_id } = conn;
+    let idle_timeout = setting_ms("idle_timeout_ms");
+
+    let reading = async {
+        loop {
+            let text = match timeout(idle_timeout, reader.recv()).await {
+                Ok(Ok(Some(text))) => text,
+                Ok(Ok(None)) | Err(_) => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+            };
+
+            // nobody to hand messages to; reading just tells us when the
+            // peer goes away
+            let inbox = match &inbox {
+                Some(inbox) => inbox,
+                None => continue,
+            };
+
+            let (done, result) = oneshot::channel();
+            let incoming = Incoming { text, peer_id: peer_id.clone(), reply: reply.clone(), done };
+            if inbox.send(incoming).await.is_err() {
+                return Ok(());
+            }
+            result.await.unwrap_or(Ok(()))?;
+        }
+    };
+
+    tokio::select! {
+        _ = write_loop(writer, queue) => Ok(()),
+        result = reading => result,
+    }
+}
+
+#[derive(Debug)]
+pub struct Outgoing {
+    msg: String,
+    ports: Vec<u16>,
+}
+
+/// Cheap handle for queueing messages to peers; the actual connections are
+/// owned by `dispatch` on the event loop.
+#[derive(Clone)]
+pub struct Network {
+    queue: mpsc::Sender<Outgoing>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Network {
+    pub fn new() -> (Self, mpsc::Receiver<Outgoing>) {
+        let size = SETTINGS.get::<usize>("outgoing_queue_size").unwrap();
+        let (queue, outgoing) = mpsc::channel(size);
+        let next_id = Arc::new(AtomicU64::new(1));
+        (Network { queue, next_id }, outgoing)
+    }
+
+    fn outgoing<T>(command: &Command<T>, ports: &[u16]) -> serde_json::Result<Outgoing>
+        where T: Serialize
+    {
+        Ok(Outgoing {
+            msg: serde_json::to_string::<Command<T>>(command)?,
+            ports: ports.to_vec(),
+        })
     }
 
+    /// Queues a message for `ports`, or for every peer if empty. Blocks while
+    /// the outgoing queue is full, so it must not be called from the event loop.
+    pub fn broadcast<T>(
+        &self,
+        action: ActionType,
+        payload: &T,
+        ports: &[u16],
+    ) -> serde_json::Result<()>
+        where T: Clone + Serialize
+    {
+        let command = Command::<T>::new(action, (*payload).clone());
+        let outgoing = Network::outgoing(&command, ports)?;
+        if self.queue.blocking_send(outgoing).is_err() {
+            println!("network is down, message dropped");
+        }
+        Ok(())
+    }
+
+    /// Same as `broadcast`, for use from within the event loop.
+    pub async fn send<T>(
+        &self,
+        action: ActionType,
+        payload: &T,
+        ports: &[u16],
+    ) -> serde_json::Result<()>
+        where T: Clone + Serialize
+    {
+        let command = Command::<T>::new(action, (*payload).clone());
+        self.queue_async(Network::outgoing(&command, ports)?).await;
+        Ok(())
+    }
+
+    /// Sends a request and returns its id; the responses arrive on the same
+    /// connection carrying that id.
+    pub async fn request<T>(
+        &self,
+        action: ActionType,
+        payload: &T,
+        ports: &[u16],
+    ) -> serde_json::Result<u64>
+        where T: Clone + Serialize
+    {
+        let id = self.next_id();
+        let command = Command::<T>::with_id(action, (*payload).clone(), id);
+        self.queue_async(Network::outgoing(&command, ports)?).await;
+        Ok(id)
+    }
+
+    /// A fresh id for a request, which its responses will carry.
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, AtomicOrdering::Relaxed)
+    }
+
+    async fn queue_async(&self, outgoing: Outgoing) {
+        if self.queue.send(outgoing).await.is_err() {
+            println!("network is down, message dropped");
+        }
+    }
+}
+
+/// Fans queued messages out to per-peer sessions. Each peer has its own
+/// bounded queue, so a slow or unreachable peer only loses its own messages
+/// instead of holding up everyone else. Whatever peers send back on these
+/// connections goes to `inbox`.
+pub async fn dispatch(
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    local_port: u16,
+    inbox: Option<mpsc::Sender<Incoming>>,
+) {
     let debug_broadcast = SETTINGS.get::<bool>("debug_broadcast").unwrap();
-    if debug_broadcast {
-        let pool_str = pool
-            .iter()
-            .map(|&sa| sa.port().to_string())
-            .collect::<Vec<_>>()
-            .join(",");
+    let mut sessions: HashMap<SocketAddr, mpsc::Sender<String>> = HashMap::new();
+
+    while let Some(out) = outgoing.recv().await {
+        let pool = get_all_peers(&out.ports, local_port);
+
+        if debug_broadcast {
+            let pool_str = pool
+                .iter()
+                .map(|&sa| sa.port().to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+
+            println!("broadcasting to {}...", pool_str);
+        }
 
-        println!("broadcasting to {}...", pool_str);
+        for peer in pool {
+            let session = sessions
+                .entry(peer)
+                .or_insert_with(|| open_session(peer, inbox.clone(), debug_broadcast));
+
+            match session.try_send(out.msg.clone()) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    if debug_broadcast {
+                        println!("queue to {} is full, dropping message", peer);
+                    }
+                },
+                Err(TrySendError::Closed(msg)) => {
+                    // the previous session died; start over with a new one
+                    let session = open_session(peer, inbox.clone(), debug_broadcast);
+                    let _ = session.try_send(msg);
+                    sessions.insert(peer, session);
+                },
+            }
+        }
     }
+}
+
+fn open_session(
+    peer: SocketAddr,
+    inbox: Option<mpsc::Sender<Incoming>>,
+    debug_broadcast: bool,
+) -> mpsc::Sender<String> {
+    let size = SETTINGS.get::<usize>("peer_queue_size").unwrap();
+    let (session, queue) = mpsc::channel(size);
+    let reply = session.clone();
+
+    tokio::spawn(async move {
+        let result = match Connection::connect(peer).await {
+            Ok(conn) => run_session(conn, queue, reply, inbox).await,
+            Err(e) => Err(e),
+        };
 
-    for peer in pool {
-        if let Ok(mut stream) = TcpStream::connect(&peer) {
-            let command = Command::<T>::new(action.clone(), (*payload).clone());
-            let msg = serde_json::to_string::<Command<T>>(&command)?;
-            writeln!(stream, "{}", msg).expect("could not broadcast");
+        if let Err(e) = result {
+            if debug_broadcast {
+                println!("session with {} failed, {}", peer, e);
+            }
+        }
+    });
+
+    session
+}
+
+/// Waits for the next `action` message answering request `id`, or `None`
+/// if the peer hangs up first.
+async fn next_response<T>(conn: &mut Connection, id: u64, action: &ActionType) -> io::Result<Option<T>>
+    where T: DeserializeOwned
+{
+    let idle_timeout = setting_ms("idle_timeout_ms");
+
+    loop {
+        let text = match timeout(idle_timeout, conn.reader.recv()).await {
+            Ok(Ok(Some(text))) => text,
+            Ok(Ok(None)) => return Ok(None),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(timed_out("response")),
+        };
+
+        // the peer may interleave other traffic; only take our responses
+        match serde_json::from_str::<Command<T>>(&text) {
+            Ok(command) if command.id == Some(id) && command.action == *action => {
+                return Ok(Some(command.payload));
+            },
+            _ => continue,
         }
     }
+}
 
-    Ok(())
+/// Fetches everything a peer has of `object` over a single outbound
+/// connection. Meant for wallets and light clients, which never accept
+/// connections and so can't be called back.
+pub async fn sync_from<T>(peer: SocketAddr, object: ObjectType) -> io::Result<Vec<T>>
+    where T: DeserializeOwned
+{
+    let mut conn = Connection::connect(peer).await?;
+
+    let id = 1;
+    let command = Command::with_id(ActionType::SyncRequest(object.clone()), SyncRequest {}, id);
+    conn.writer.send(&serde_json::to_string(&command)?).await?;
+
+    let mut data = vec![];
+    let action = ActionType::SyncResponse(object);
+    while let Some(response) = next_response::<SyncResponse<T>>(&mut conn, id, &action).await? {
+        data.extend(response.data);
+        if !response.more {
+            break;
+        }
+    }
+
+    Ok(data)
+}
+
+/// Sends a single request to `peer` and waits for its `expect` response,
+/// the same way `sync_from` does.
+pub async fn request_from<Req, Resp>(
+    peer: SocketAddr,
+    action: ActionType,
+    payload: &Req,
+    expect: ActionType,
+) -> io::Result<Resp>
+    where Req: Serialize, Resp: DeserializeOwned
+{
+    let mut conn = Connection::connect(peer).await?;
+
+    let id = 1;
+    conn.writer.send(&serde_json::to_string(&Command::with_id(action, payload, id))?).await?;
+
+    next_response(&mut conn, id, &expect).await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use crate::network::*;
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(parse_addr("4000"), Some(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        assert_eq!(parse_addr("10.0.0.1:4001"), Some(SocketAddr::from(([10, 0, 0, 1], 4001))));
+        assert_eq!(parse_addr("nowhere"), None);
+    }
+
+    #[test]
+    fn test_add_peer() {
+        // refuses connections straight away, should other tests probe it
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4999));
+        let added = (add_peer(addr), add_peer(addr));
+        let (all, chosen) = (get_all_peers(&[], 4000), get_all_peers(&[4001], 4000));
+
+        // the list is global, so leave it as the other tests expect before
+        // anything can fail
+        ADDED_PEERS.lock().unwrap().retain(|&a| a != addr);
+
+        assert_eq!(added, (true, false));
+        assert!(all.contains(&addr));
+        assert!(!chosen.contains(&addr));
+        assert!(!get_all_peers(&[], 4000).contains(&addr));
+    }
+
+    #[tokio::test]
+    async fn test_connection_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = Connection::accept(stream).await.unwrap();
+            let msg = conn.reader.recv().await.unwrap().unwrap();
+            conn.writer.send(msg.trim()).await.unwrap();
+        });
+
+        let mut conn = Connection::connect(addr).await.unwrap();
+        conn.writer.send("hello").await.unwrap();
+        assert_eq!(conn.reader.recv().await.unwrap().unwrap().trim(), "hello");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let max_size = SETTINGS.get::<usize>("max_message_size").unwrap();
+
+        tokio::spawn(async move {
+            let mut conn = Connection::connect(addr).await.unwrap();
+            let _ = conn.writer.send(&"x".repeat(max_size + 1)).await;
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = Connection::accept(stream).await.unwrap();
+        let err = conn.reader.recv().await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_collects_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = Connection::accept(stream).await.unwrap();
+            let text = conn.reader.recv().await.unwrap().unwrap();
+            let request: Command<SyncRequest> = serde_json::from_str(&text).unwrap();
+            let id = request.id.unwrap();
+
+            let action = ActionType::SyncResponse(ObjectType::Tx);
+            let responses = vec![
+                Command::with_id(action.clone(), SyncResponse { data: vec![1, 2], more: true }, id),
+                // not ours, so it must be skipped
+                Command::with_id(action.clone(), SyncResponse { data: vec![9], more: false }, id + 1),
+                Command::with_id(action, SyncResponse { data: vec![3], more: false }, id),
+            ];
+            for response in responses {
+                conn.writer.send(&serde_json::to_string(&response).unwrap()).await.unwrap();
+            }
+        });
+
+        let data: Vec<u32> = sync_from(addr, ObjectType::Tx).await.unwrap();
+        assert_eq!(data, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_skips_dead_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap().port();
+
+        // bind and drop to get a port nobody is listening on
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+
+        let (net, outgoing) = Network::new();
+        tokio::spawn(dispatch(outgoing, 0, None));
+        net.send(ActionType::Broadcast(ObjectType::Tx), &"ping", &[dead, live]).await.unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = Connection::accept(stream).await.unwrap();
+        let msg = timeout(Duration::from_secs(5), conn.reader.recv()).await.unwrap();
+        assert!(msg.unwrap().unwrap().contains("ping"));
+    }
 }
@@ -0,0 +1,315 @@
+use std::io;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::convert::TryInto;
+use serde::{Serialize, Deserialize};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use crate::network::limits::Violation;
+
+// XX lets both sides learn each other's static key during the handshake,
+// so neither side needs to know the other's key in advance
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+// the noise spec caps every message at 64k, including the auth tag
+const MAX_NOISE_MSG: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_CHUNK: usize = MAX_NOISE_MSG - TAG_LEN;
+
+lazy_static! {
+    static ref LOCAL_KEY: Mutex<Option<StaticKey>> = Mutex::new(None);
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StaticKey {
+    pub private: String,
+    pub public: String,
+}
+
+impl StaticKey {
+    pub fn generate() -> Self {
+        let keypair = Builder::new(params())
+            .generate_keypair()
+            .expect("could not generate keypair");
+
+        StaticKey {
+            private: hex::encode(keypair.private),
+            public: hex::encode(keypair.public),
+        }
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let text = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, text)
+    }
+
+    fn private_bytes(&self) -> Vec<u8> {
+        hex::decode(&self.private).expect("invalid private key")
+    }
+}
+
+/// Sets the static key this process uses for all encrypted connections.
+pub fn set_local_key(key: StaticKey) {
+    *LOCAL_KEY.lock().unwrap() = Some(key);
+}
+
+/// Returns the local static key, generating a throwaway one on first use
+/// if none was configured.
+pub fn local_key() -> StaticKey {
+    let mut key = LOCAL_KEY.lock().unwrap();
+    if key.is_none() {
+        let generated = StaticKey::generate();
+        println!("> using ephemeral transport key {}", generated.public);
+        *key = Some(generated);
+    }
+    key.clone().unwrap()
+}
+
+fn params() -> snow::params::NoiseParams {
+    NOISE_PARAMS.parse().unwrap()
+}
+
+fn noise_err(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+async fn write_frame<W>(stream: &mut W, frame: &[u8]) -> io::Result<()>
+    where W: AsyncWrite + Unpin
+{
+    let len: u16 = frame.len().try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(frame).await
+}
+
+async fn read_frame<R>(stream: &mut R) -> io::Result<Vec<u8>>
+    where R: AsyncRead + Unpin
+{
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+fn check_allowed(remote: &[u8], allowed: &[String]) -> io::Result<String> {
+    let remote = hex::encode(remote);
+    if !allowed.is_empty() && !allowed.contains(&remote) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("peer {} is not allowed", remote),
+        ));
+    }
+    Ok(remote)
+}
+
+fn finish(
+    hs: HandshakeState,
+    allowed: &[String],
+) -> io::Result<(SecureReader, SecureWriter, String)> {
+    let remote = hs.get_remote_static()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no remote key"))?
+        .to_vec();
+    let remote = check_allowed(&remote, allowed)?;
+
+    // the stateless variant takes explicit nonces, which lets the read and
+    // write halves of a connection live in separate tasks
+    let state = Arc::new(hs.into_stateless_transport_mode().map_err(noise_err)?);
+
+    Ok((
+        SecureReader { state: Arc::clone(&state), nonce: 0 },
+        SecureWriter { state, nonce: 0 },
+        remote,
+    ))
+}
+
+/// Runs the initiator side of the XX handshake, returning both halves of
+/// the channel and the remote's public key.
+pub async fn initiate<S>(
+    stream: &mut S,
+    key: &StaticKey,
+    allowed: &[String],
+) -> io::Result<(SecureReader, SecureWriter, String)>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let private = key.private_bytes();
+    let mut hs = Builder::new(params())
+        .local_private_key(&private)
+        .build_initiator()
+        .map_err(noise_err)?;
+    let mut buf = vec![0u8; MAX_NOISE_MSG];
+
+    // -> e
+    let len = hs.write_message(&[], &mut buf).map_err(noise_err)?;
+    write_frame(stream, &buf[..len]).await?;
+
+    // <- e, ee, s, es
+    hs.read_message(&read_frame(stream).await?, &mut buf).map_err(noise_err)?;
+
+    // -> s, se
+    let len = hs.write_message(&[], &mut buf).map_err(noise_err)?;
+    write_frame(stream, &buf[..len]).await?;
+
+    finish(hs, allowed)
+}
+
+/// Runs the responder side of the XX handshake, returning both halves of
+/// the channel and the remote's public key.
+pub async fn respond<S>(
+    stream: &mut S,
+    key: &StaticKey,
+    allowed: &[String],
+) -> io::Result<(SecureReader, SecureWriter, String)>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let private = key.private_bytes();
+    let mut hs = Builder::new(params())
+        .local_private_key(&private)
+        .build_responder()
+        .map_err(noise_err)?;
+    let mut buf = vec![0u8; MAX_NOISE_MSG];
+
+    // -> e
+    hs.read_message(&read_frame(stream).await?, &mut buf).map_err(noise_err)?;
+
+    // <- e, ee, s, es
+    let len = hs.write_message(&[], &mut buf).map_err(noise_err)?;
+    write_frame(stream, &buf[..len]).await?;
+
+    // -> s, se
+    hs.read_message(&read_frame(stream).await?, &mut buf).map_err(noise_err)?;
+
+    finish(hs, allowed)
+}
+
+pub struct SecureWriter {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl SecureWriter {
+    fn encrypt(&mut self, payload: &[u8], buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.state.write_message(self.nonce, payload, buf).map_err(noise_err)?;
+        self.nonce += 1;
+        Ok(len)
+    }
+
+    /// Sends a message of any length. An encrypted length header goes first,
+    /// followed by as many noise-sized chunks as needed.
+    pub async fn send<W>(&mut self, stream: &mut W, msg: &[u8]) -> io::Result<()>
+        where W: AsyncWrite + Unpin
+    {
+        let mut buf = vec![0u8; MAX_NOISE_MSG];
+        let header = (msg.len() as u32).to_be_bytes();
+
+        let len = self.encrypt(&header, &mut buf)?;
+        write_frame(stream, &buf[..len]).await?;
+
+        for chunk in msg.chunks(MAX_CHUNK) {
+            let len = self.encrypt(chunk, &mut buf)?;
+            write_frame(stream, &buf[..len]).await?;
+        }
+
+        stream.flush().await
+    }
+}
+
+pub struct SecureReader {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl SecureReader {
+    fn decrypt(&mut self, frame: &[u8], buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.state.read_message(self.nonce, frame, buf).map_err(noise_err)?;
+        self.nonce += 1;
+        Ok(len)
+    }
+
+    /// Reads one message, refusing anything announced as longer than
+    /// `max_size` before allocating for it.
+    pub async fn recv<R>(&mut self, stream: &mut R, max_size: usize) -> io::Result<Vec<u8>>
+        where R: AsyncRead + Unpin
+    {
+        let mut buf = vec![0u8; MAX_NOISE_MSG];
+
+        let len = self.decrypt(&read_frame(stream).await?, &mut buf)?;
+        let header: [u8; 4] = buf[..len].try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad length header"))?;
+        let total = u32::from_be_bytes(header) as usize;
+        if total > max_size {
+            return Err(Violation::MessageTooLarge(total).into());
+        }
+
+        let mut msg = Vec::with_capacity(total);
+        while msg.len() < total {
+            let len = self.decrypt(&read_frame(stream).await?, &mut buf)?;
+            msg.extend_from_slice(&buf[..len]);
+        }
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::network::secure::*;
+
+    type Side = io::Result<(SecureReader, SecureWriter, String, TcpStream)>;
+
+    async fn pair(server_allowed: Vec<String>) -> (Side, Side, StaticKey, StaticKey) {
+        let server_key = StaticKey::generate();
+        let client_key = StaticKey::generate();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let key = server_key.clone();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let (rd, wr, remote) = respond(&mut stream, &key, &server_allowed).await?;
+            Ok((rd, wr, remote, stream))
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let client = initiate(&mut stream, &client_key, &[]).await
+            .map(|(rd, wr, remote)| (rd, wr, remote, stream));
+
+        (server.await.unwrap(), client, server_key, client_key)
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_large_message() {
+        let (server, client, server_key, client_key) = pair(vec![]).await;
+
+        let (mut c_rd, mut c_wr, c_remote, mut c_stream) = client.unwrap();
+        let (mut s_rd, mut s_wr, s_remote, mut s_stream) = server.unwrap();
+        assert_eq!(c_remote, server_key.public);
+        assert_eq!(s_remote, client_key.public);
+
+        // larger than a single noise message
+        let msg = "x".repeat(200_000);
+        c_wr.send(&mut c_stream, msg.as_bytes()).await.unwrap();
+        assert_eq!(s_rd.recv(&mut s_stream, msg.len()).await.unwrap(), msg.as_bytes());
+
+        s_wr.send(&mut s_stream, b"pong").await.unwrap();
+        assert_eq!(c_rd.recv(&mut c_stream, 3).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_allow_list_rejects_unknown_peer() {
+        let stranger = StaticKey::generate();
+        let (server, _, _, _) = pair(vec![stranger.public]).await;
+
+        let err = server.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,210 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+use crate::network::setting_ms;
+use crate::config::*;
+use super::server::Context;
+use super::rpc::{self, Auth};
+use super::rest;
+
+// the request line plus headers; bodies are capped by `max_message_size`
+const MAX_HEAD_BYTES: usize = 8192;
+
+#[derive(Debug, PartialEq)]
+pub(super) struct Head {
+    pub(super) method: String,
+    pub(super) path: String,
+    pub(super) content_length: usize,
+    pub(super) authorization: Option<String>,
+
+    // set when the client asks to upgrade to a websocket
+    pub(super) websocket_key: Option<String>,
+}
+
+fn bad_request(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// Reads the request line and headers, up to but not including the blank
+/// line that ends them.
+fn parse_head(head: &str) -> io::Result<Head> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method, path),
+        _ => return Err(bad_request("no request line")),
+    };
+
+    let mut parsed = Head {
+        method: method.to_string(),
+        path: path.to_string(),
+        content_length: 0,
+        authorization: None,
+        websocket_key: None,
+    };
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(|| bad_request("malformed header"))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                parsed.content_length = value.parse().map_err(|_| bad_request("bad content length"))?;
+            },
+            "authorization" => parsed.authorization = Some(value.to_string()),
+            "sec-websocket-key" => parsed.websocket_key = Some(value.to_string()),
+            _ => (),
+        }
+    }
+    Ok(parsed)
+}
+
+async fn read_request(stream: &mut TcpStream, max_body: usize) -> io::Result<(Head, Vec<u8>)> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 1024];
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_HEAD_BYTES {
+            return Err(bad_request("headers too large"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| bad_request("headers not utf-8"))?;
+    let head = parse_head(head)?;
+    if head.content_length > max_body {
+        return Err(bad_request("body too large"));
+    }
+
+    let mut body = buf.split_off(end + 4);
+    body.truncate(head.content_length);
+    if body.len() < head.content_length {
+        let mut rest = vec![0; head.content_length - body.len()];
+        stream.read_exact(&mut rest).await?;
+        body.extend(rest);
+    }
+    Ok((head, body))
+}
+
+pub(super) fn http_response(status: &str, body: &str) -> String {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    if status.starts_with("401") {
+        response += "WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n";
+    }
+    if !body.is_empty() {
+        response += "Content-Type: application/json\r\n";
+    }
+    response + &format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+/// Answers one request: POSTs are json-rpc calls, GETs go to the read-only
+/// rest api, or become a websocket when they ask to upgrade.
+async fn serve_client(mut stream: TcpStream, ctx: Context, port: u16, auth: Arc<Auth>, rest: bool) -> io::Result<()> {
+    let max_body = SETTINGS.get::<usize>("max_message_size").unwrap();
+    let (head, body) = timeout(setting_ms("idle_timeout_ms"), read_request(&mut stream, max_body))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "http request timed out"))??;
+
+    let (response, stopping) = match head.method.as_str() {
+        "POST" => rpc::respond(&ctx, port, &auth, &head, &body).await,
+        "GET" if rest => match &head.websocket_key {
+            Some(key) => return rest::subscribe(stream, key, &head.path, ctx).await,
+            None => (rest::respond(&ctx, &head.path), false),
+        },
+        _ => (http_response("405 Method Not Allowed", ""), false),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    if stopping {
+        println!("> stopping, as asked over rpc");
+        ctx.stop.notify_one();
+    }
+    Ok(())
+}
+
+/// Serves json-rpc, and with `rest` on the rest and websocket api, over
+/// http on localhost, `rpc_port_offset` above the p2p `port`. An offset of
+/// 0 turns it all off.
+pub(super) async fn start(ctx: &Context, port: u16) {
+    let offset = SETTINGS.get::<u16>("rpc_port_offset").unwrap();
+    if offset == 0 {
+        return;
+    }
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port.saturating_add(offset)));
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("could not start rpc server on {}, {}", addr, e);
+            return;
+        },
+    };
+    let auth = match Auth::from_settings(port) {
+        Ok(auth) => Arc::new(auth),
+        Err(e) => {
+            println!("could not write rpc cookie, {}", e);
+            return;
+        },
+    };
+
+    let rest = SETTINGS.get::<bool>("rest").unwrap();
+    println!("> rpc on {}...", addr);
+    if rest {
+        println!("> rest api on http://{}/, events on ws://{}/ws", addr, addr);
+    }
+
+    let ctx = ctx.clone();
+    let slots = Arc::new(Semaphore::new(SETTINGS.get::<usize>("max_inbound").unwrap()));
+    tokio::spawn(async move {
+        loop {
+            let permit = Arc::clone(&slots).acquire_owned().await.unwrap();
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    println!("rpc accept failed, {}", e);
+                    continue;
+                },
+            };
+            let (ctx, auth) = (ctx.clone(), Arc::clone(&auth));
+            tokio::spawn(async move {
+                match serve_client(stream, ctx, port, auth, rest).await {
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+                    Err(e) => println!("http client failed, {}", e),
+                    Ok(()) => (),
+                }
+                drop(permit);
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::http::*;
+
+    #[test]
+    fn test_parse_head() {
+        let head = parse_head("POST / HTTP/1.1\r\nHost: localhost\r\ncontent-length: 42\r\nAuthorization: Basic dTpw").unwrap();
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.content_length, 42);
+        assert_eq!(head.authorization, Some("Basic dTpw".to_string()));
+
+        let head = parse_head("GET /ws?events=newTx HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: abc==").unwrap();
+        assert_eq!((head.path.as_str(), head.websocket_key), ("/ws?events=newTx", Some("abc==".to_string())));
+
+        assert!(parse_head("POST / HTTP/1.1\r\nContent-Length: lots").is_err());
+        assert!(parse_head("POST / HTTP/1.1\r\nno colon").is_err());
+        assert!(parse_head("POST").is_err());
+    }
+}
@@ -1,89 +1,320 @@
-use std::time::SystemTime;
+use std::time::{Duration, Instant};
 use std::thread;
 use std::sync::{Mutex, Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
 use crate::mempool::Mempool;
 use crate::blockchain::*;
 use crate::network::*;
 use crate::types::*;
+use crate::config::*;
+
+pub enum MinerEvent {
+    NewTip,
+    NewTxs,
+}
+
+/// Tells the miner what changed, so it stops hashing on stale work. A new
+/// tip also aborts the search in flight.
+#[derive(Clone)]
+pub struct MinerSignal {
+    events: mpsc::Sender<MinerEvent>,
+    abort: Arc<AtomicBool>,
+}
+
+impl MinerSignal {
+    pub fn new() -> (Self, mpsc::Receiver<MinerEvent>) {
+        let (events, rx) = mpsc::channel();
+        (MinerSignal { events, abort: Arc::new(AtomicBool::new(false)) }, rx)
+    }
+
+    pub fn new_tip(&self) {
+        self.abort.store(true, Ordering::Relaxed);
+        self.events.send(MinerEvent::NewTip).ok();
+    }
+
+    pub fn new_txs(&self) {
+        self.events.send(MinerEvent::NewTxs).ok();
+    }
+}
+
+/// Hashrate and progress, shared with the server so peers can ask for it.
+pub struct MiningStats {
+    threads: usize,
+    since: Instant,
+    hashes: u64,
+    hashrate: f64,
+    nonce: u64,
+    difficulty: usize,
+    pow: Pow,
+}
+
+impl MiningStats {
+    pub fn new(threads: usize, params: &ChainParams) -> Self {
+        MiningStats {
+            threads,
+            since: Instant::now(),
+            hashes: 0,
+            hashrate: 0.0,
+            nonce: 0,
+            difficulty: params.difficulty,
+            pow: params.pow,
+        }
+    }
+
+    fn record(&mut self, hashes: u64, nonce: u64) {
+        self.hashes += hashes;
+        self.nonce = nonce;
+    }
+
+    /// Nothing to mine, so nothing is being hashed.
+    fn idle(&mut self) {
+        self.hashes = 0;
+        self.hashrate = 0.0;
+        self.nonce = 0;
+        self.since = Instant::now();
+    }
+
+    /// Turns the hashes counted so far into a rate, once `interval` has
+    /// passed since the last one.
+    fn roll(&mut self, now: Instant, interval: Duration) -> Option<f64> {
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed < interval {
+            return None;
+        }
+        self.hashrate = self.hashes as f64 / elapsed.as_secs_f64();
+        self.hashes = 0;
+        self.since = now;
+        Some(self.hashrate)
+    }
+
+    pub fn info(&self) -> MiningInfo {
+        // each hex digit of the target has a 1 in 16 chance of being zero
+        let expected_hashes = 16f64.powi(self.difficulty as i32);
+        MiningInfo {
+            threads: self.threads,
+            hashrate: self.hashrate,
+            nonce: self.nonce,
+            difficulty: self.difficulty,
+            algorithm: self.pow.to_string(),
+            expected_secs: Some(expected_hashes / self.hashrate).filter(|s| s.is_finite()),
+        }
+    }
+}
+
+/// Whether `candidate` is worth restarting the search for: it pays at
+/// least `gain` (a fraction) more in fees than the template being mined.
+fn improves(current: &Option<BlockTemplate>, candidate: &Option<BlockTemplate>, gain: f64) -> bool {
+    match (current, candidate) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(current), Some(candidate)) => candidate.fees > current.fees * (1.0 + gain),
+    }
+}
+
+/// Everything the mining thread owns besides the shared chain and mempool.
+pub struct Miner {
+    pub engine: Box<dyn Engine>,
+    pub signal: MinerSignal,
+    pub events: mpsc::Receiver<MinerEvent>,
+    pub stats: Arc<Mutex<MiningStats>>,
+}
 
 pub fn start(
-    rx: mpsc::Receiver<u16>,
+    rx: mpsc::Receiver<(u16, u64)>,
+    miner: Miner,
+    net: Network,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        println!("> miner started mining...");
-        let local_port: u16 = rx.recv().unwrap();
+        let Miner { engine, signal, events, stats } = miner;
+        let threads = stats.lock().unwrap().threads;
+        println!("> miner started mining on {} thread(s)...", threads);
+        let (local_port, extra_nonces): (u16, u64) = rx.recv().unwrap();
 
-        let mut time = SystemTime::now();
+        // kept apart from the global pool, so nothing else competes with
+        // or borrows the mining threads
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("miner-{}", i))
+            .build()
+            .expect("could not start mining threads");
+
+        let clock = blockchain.lock().unwrap().clock().clone();
+        let mut time = clock.now();
         let mut nonce: u64 = 0;
-        let mut txs;
 
-        let concurrent_hashes = {
-            let bc = blockchain.lock().unwrap();
-            bc.get_concurrent_hashes()
-        };
+        let concurrent_hashes = SETTINGS.get::<u64>("concurrent_hashes").unwrap();
 
-        loop {
-            // fetch transactions from mempool
-            txs = {
-                let mut mp = mempool.lock().unwrap();
-                mp.get_all().to_vec()
-            };
+        // counting up from the bottom of the node's own range keeps its
+        // search space apart from every other node's
+        let mut builder = TemplateBuilder::from_settings();
+        builder.extra_nonce = extra_nonces;
+        let fee_gain = SETTINGS.get::<f64>("template_fee_gain").unwrap();
+        let report = Duration::from_secs(SETTINGS.get::<u64>("hashrate_report_secs").unwrap());
 
-            let output = {
+        // only proof of work spends the round on hashing
+        let hashing = matches!(blockchain.lock().unwrap().params().consensus, Consensus::Work);
+
+        // fill a block from the mempool
+        let build = || {
+            let (head, params) = {
                 let bc = blockchain.lock().unwrap();
-                if bc.ok_to_mine(&txs) {
-                    // reset timer if starting over
-                    if nonce == 0 {
-                        time = SystemTime::now();
-                    }
+                (bc.head(), bc.params().clone())
+            };
+            let mp = mempool.lock().unwrap();
+            builder.build(head, &params, &mp)
+        };
+        let mut template = build();
 
-                    // attempt to mine a block
-                    let ret = bc.mine(local_port, nonce, time, txs);
+        loop {
+            // clear the abort before reading events, so one raised after
+            // this point still stops the next round
+            signal.abort.store(false, Ordering::Relaxed);
 
-                    // bump nonce
-                    nonce = if nonce >= u64::max_value() - concurrent_hashes {
-                        1
-                    } else {
-                        nonce + concurrent_hashes
-                    };
+            let (mut new_tip, mut new_txs) = (false, false);
+            for event in events.try_iter() {
+                match event {
+                    MinerEvent::NewTip => new_tip = true,
+                    MinerEvent::NewTxs => new_txs = true,
+                }
+            }
 
-                    ret
-                } else {
-                    // another node might've mined faster; reset timer
+            if new_tip {
+                template = build();
+                nonce = 0;
+            } else if new_txs {
+                let candidate = build();
+                if improves(&template, &candidate, fee_gain) {
+                    template = candidate;
                     nonce = 0;
-
-                    None
                 }
+            }
+
+            let current = match &template {
+                Some(template) => template,
+                None => {
+                    // nothing worth mining; wait until something changes
+                    stats.lock().unwrap().idle();
+                    if events.recv().is_err() {
+                        return;
+                    }
+                    template = build();
+                    continue;
+                },
             };
 
-            if let Some(block) = output {
-                // remove mined txs from mempool
-                {
-                    let mut mp = mempool.lock().unwrap();
-                    for tx in block.get_all() {
-                        mp.remove(&tx);
+            // reset timer if starting over
+            if nonce == 0 {
+                time = clock.now();
+            }
+
+            // attempt to mine a block; the template is our own copy, so
+            // peers' blocks and sync requests don't wait on the search
+            let output = pool.install(|| {
+                engine.seal(current, local_port, nonce..nonce + concurrent_hashes, &clock, time, &signal.abort)
+            });
+
+            // an aborted round stopped partway; leave it out of the count
+            if hashing {
+                let mut stats = stats.lock().unwrap();
+                if !signal.abort.load(Ordering::Relaxed) {
+                    stats.record(concurrent_hashes, nonce + concurrent_hashes);
+                }
+                if let Some(rate) = stats.roll(Instant::now(), report) {
+                    let info = stats.info();
+                    match info.expected_secs {
+                        Some(secs) => println!("> hashrate {:.0} H/s, ~{:.1}s per block", rate, secs),
+                        None => println!("> hashrate {:.0} H/s", rate),
                     }
                 }
+            }
+
+            if let Some(block) = output {
+                // add new block to chain first, so we can serve its txs
+                // to peers that can't rebuild it from their mempool
+                if let Err(e) = super::submit_block(&blockchain, &mempool, &signal, block.clone()) {
+                    println!("> discarding mined block: {}", e);
+                    continue;
+                }
+
+                println!(
+                    "> mined {} tx(s), {} bytes, {:.2} in fees",
+                    current.txs.len(),
+                    current.size,
+                    current.fees,
+                );
 
-                // broadcast the new block
-                broadcast::<Block>(
-                    ActionType::Broadcast(ObjectType::Block),
-                    &block,
+                // announce the new block
+                net.broadcast::<CompactBlock>(
+                    ActionType::Broadcast(ObjectType::CompactBlock),
+                    &block.to_compact(),
                     &[],
-                    local_port,
                 ).unwrap();
 
-                // add new block to chain
-                {
-                    let mut bc = blockchain.lock().unwrap();
-                    bc.add(block);
-                }
-
                 // reset on successful block (easier to debug)
                 nonce = 0;
+            } else if nonce.checked_add(2 * concurrent_hashes).is_some() {
+                nonce += concurrent_hashes;
+            } else if let Some(template) = &mut template {
+                // every nonce has been tried; change the header instead of
+                // hashing the same ones again
+                template.bump_extra_nonce();
+                nonce = 0;
             }
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::node::miner::*;
+
+    fn template(fees: f64) -> Option<BlockTemplate> {
+        Some(BlockTemplate {
+            prev: String::new(),
+            height: 1,
+            difficulty: 0,
+            pow: Pow::Sha256,
+            txs: vec![],
+            coinbase: Coinbase { to: 'Z', amount: fees, extra_nonce: 0 },
+            stakes: Default::default(),
+            seed: String::new(),
+            evidence: vec![],
+            fees,
+            size: 0,
+        })
+    }
+
+    #[test]
+    fn test_mining_stats() {
+        let params = ChainParams { consensus: Consensus::Work, difficulty: 2, pow: Pow::Blake3, genesis_balance: 0.0, block_reward: 0.0, regtest: false };
+        let mut stats = MiningStats::new(4, &params);
+        let start = stats.since;
+        assert_eq!(stats.info().expected_secs, None);
+
+        stats.record(5000, 5000);
+        assert_eq!(stats.roll(start + Duration::from_secs(1), Duration::from_secs(10)), None);
+        stats.record(5000, 10000);
+        assert_eq!(stats.roll(start + Duration::from_secs(10), Duration::from_secs(10)), Some(1000.0));
+
+        let info = stats.info();
+        assert_eq!(info.threads, 4);
+        assert_eq!(info.nonce, 10000);
+        assert_eq!(info.algorithm, "blake3");
+        assert_eq!(info.expected_secs, Some(256.0 / 1000.0));
+
+        stats.idle();
+        assert_eq!(stats.info().hashrate, 0.0);
+        assert_eq!(stats.info().expected_secs, None);
+    }
+
+    #[test]
+    fn test_improves() {
+        assert!(improves(&None, &template(0.0), 0.05));
+        assert!(!improves(&template(1.0), &None, 0.05));
+        assert!(!improves(&template(1.0), &template(1.04), 0.05));
+        assert!(improves(&template(1.0), &template(1.06), 0.05));
+        assert!(improves(&template(0.0), &template(0.01), 0.05));
+    }
+}
@@ -1,26 +1,359 @@
 mod server;
 mod miner;
+mod work;
+mod rpc;
+mod rest;
+mod http;
 
+use std::fmt;
 use std::sync::{Mutex, Arc, mpsc};
-use crate::blockchain::Blockchain;
-use crate::mempool::Mempool;
+use std::sync::atomic::AtomicBool;
+use crate::blockchain::{engine, mine, Block, Blockchain, ChainParams, Consensus, State, TemplateBuilder, ValidatorKey};
+use crate::mempool::{Mempool, Policy};
+use crate::network::Network;
+use crate::events::Events;
 use crate::config::*;
+use miner::{Miner, MinerSignal, MiningStats};
 
-pub fn start() {
-    let min_tx_per_block = SETTINGS.get::<usize>("min_tx_per_block").unwrap();
-    let difficulty = SETTINGS.get::<usize>("difficulty").unwrap();
-    let concurrent_hashes = SETTINGS.get::<u64>("concurrent_hashes").unwrap();
+/// Adds blocks to the chain, switching branches whenever one overtakes
+/// ours, then brings the mempool in line with the new tip. The two locks are
+/// never held at once. Blocks already known are skipped, since sync responses
+/// start from genesis and peers relay blocks back; it stops at the first
+/// block whose parent we don't have or that isn't properly sealed. `live`
+/// blocks were just relayed, not synced.
+fn connect_blocks(
+    blockchain: &Mutex<Blockchain>,
+    mempool: &Mutex<Mempool>,
+    miner: &MinerSignal,
+    blocks: Vec<Block>,
+    live: bool,
+) -> Result<(), Rejected> {
+    let (mut connected, mut disconnected): (Vec<Block>, Vec<Block>) = (vec![], vec![]);
+    let mut result = Ok(());
+    let state = {
+        let mut bc = blockchain.lock().unwrap();
+        for block in blocks {
+            if bc.contains(&block.hash) {
+                continue;
+            }
+            if !bc.verify(&block, live) {
+                let e = match block.prev.is_empty() || bc.contains(&block.prev) {
+                    true => Rejected::BadSeal,
+                    false => Rejected::UnknownParent,
+                };
+                println!("rejecting block {} from {}: {}", &block.hash[..8.min(block.hash.len())], block.id, e);
+                result = Err(e);
+                break;
+            }
 
-    let blockchain = Arc::new(Mutex::new(Blockchain::new(min_tx_per_block, difficulty, concurrent_hashes)));
-    let mempool = Arc::new(Mutex::new(Mempool::new()));
+            let tip = bc.tip();
+            match bc.add(block.clone()) {
+                Some(reorg) => {
+                    // blocks connected earlier in this batch may be off again
+                    for block in reorg.disconnected {
+                        match connected.iter().position(|b| b.hash == block.hash) {
+                            Some(i) => { connected.remove(i); },
+                            None => disconnected.push(block),
+                        }
+                    }
+                    connected.extend(reorg.connected);
+                },
+                None if bc.tip() != tip => connected.push(block),
+                None => (),
+            }
+        }
+        bc.state().clone()
+    };
+
+    update_mempool(mempool, miner, &disconnected, &connected, state);
+    result
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Rejected {
+    StaleTip,
+    UnknownParent,
+    BadSeal,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejected::StaleTip => write!(f, "it doesn't build on the tip"),
+            Rejected::UnknownParent => write!(f, "it builds on a block we don't have"),
+            Rejected::BadSeal => write!(f, "bad seal"),
+        }
+    }
+}
+
+/// Connects a block mined here or by an external miner, unless the chain
+/// moved on while it was being hashed.
+fn submit_block(
+    blockchain: &Mutex<Blockchain>,
+    mempool: &Mutex<Mempool>,
+    miner: &MinerSignal,
+    block: Block,
+) -> Result<(), Rejected> {
+    let state = {
+        let mut bc = blockchain.lock().unwrap();
+        if bc.tip() != block.prev {
+            return Err(Rejected::StaleTip);
+        }
+        if !bc.verify(&block, true) {
+            return Err(Rejected::BadSeal);
+        }
+        bc.add(block.clone());
+        bc.state().clone()
+    };
+
+    update_mempool(mempool, miner, &[], &[block], state);
+    Ok(())
+}
+
+/// Mines `blocks` blocks on the spot, however few txs the mempool holds,
+/// and returns them. Nonces are tried one at a time, so with the chain's
+/// clock stopped the same chain and mempool always give the same blocks.
+/// Only quick at regtest's difficulty.
+fn generate(
+    blockchain: &Mutex<Blockchain>,
+    mempool: &Mutex<Mempool>,
+    miner: &MinerSignal,
+    builder: &TemplateBuilder,
+    id: u16,
+    blocks: usize,
+) -> Vec<Block> {
+    let abort = AtomicBool::new(false);
+    let builder = TemplateBuilder { min_txs: 0, ..builder.clone() };
+    let mut generated = vec![];
+
+    for _ in 0..blocks {
+        let (head, params, clock) = {
+            let bc = blockchain.lock().unwrap();
+            (bc.head(), bc.params().clone(), bc.clock().clone())
+        };
+        let template = match builder.build(head, &params, &mempool.lock().unwrap()) {
+            Some(template) => template,
+            None => break,
+        };
+
+        let time = clock.now();
+        let block = (0..u64::MAX)
+            .find_map(|nonce| mine(&template, id, nonce..nonce + 1, &clock, time, &abort))
+            .expect("every nonce tried");
+        if let Err(e) = submit_block(blockchain, mempool, miner, block.clone()) {
+            println!("> discarding generated block: {}", e);
+            break;
+        }
+        generated.push(block);
+    }
+
+    generated
+}
+
+/// Tells the mempool and the miner about the new tip, `connected` having
+/// just been added, after `disconnected` came off if the chain switched
+/// branches.
+fn update_mempool(
+    mempool: &Mutex<Mempool>,
+    miner: &MinerSignal,
+    disconnected: &[Block],
+    connected: &[Block],
+    state: State,
+) {
+    let dropped = match disconnected.is_empty() {
+        true => mempool.lock().unwrap().update(connected, state),
+        false => mempool.lock().unwrap().reorg(disconnected, connected, state),
+    };
+    for tx in dropped {
+        println!("dropped {:?} from mempool", tx);
+    }
+
+    if !connected.is_empty() {
+        miner.new_tip();
+    }
+}
+
+/// Whether this node gets to add blocks: with proof of work anyone can,
+/// with proof of authority only a listed validator, and with proof of stake
+/// anyone with a key, once it has stake.
+fn can_seal(params: &ChainParams, validator: &Option<ValidatorKey>) -> bool {
+    match (&params.consensus, validator) {
+        (Consensus::Work, _) => true,
+        (Consensus::Authority { validators, .. }, Some(key)) => validators.contains(&key.public),
+        (Consensus::Stake { .. }, Some(_)) => true,
+        (_, None) => false,
+    }
+}
+
+/// Runs a node, mining on `threads` threads (all cores by default) or only
+/// relaying with 0. Proof of authority and stake networks also need this
+/// node's `validator` key.
+pub fn start(threads: Option<usize>, validator: Option<ValidatorKey>) {
+    let params = ChainParams::from_settings();
+    match &params.consensus {
+        Consensus::Work => println!("> {} proof of work at difficulty {}", params.pow, params.difficulty),
+        Consensus::Authority { validators, interval } => {
+            println!("> proof of authority, {} validator(s) every {}s", validators.len(), interval.as_secs());
+        },
+        Consensus::Stake { validators, interval, .. } => {
+            println!("> proof of stake, {} genesis validator(s), a block every {}s", validators.len(), interval.as_secs());
+        },
+    }
+
+    // the chain and mempool report changes here, for websocket subscribers
+    let events = Events::new(SETTINGS.get::<usize>("event_queue_size").unwrap());
+    let mut blockchain = Blockchain::new(params.clone());
+    blockchain.set_events(events.clone());
+    let mut mempool = Mempool::with_policy(Policy::from_settings());
+    mempool.set_events(events);
+    mempool.set_clock(blockchain.clock().clone());
+
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = Arc::new(Mutex::new(mempool));
+    let (signal, events) = MinerSignal::new();
+    let _ = connect_blocks(&blockchain, &mempool, &signal, vec![], false);
+
+    // regtest nodes never mine by themselves
+    let mining_threads = match params.regtest {
+        true => 0,
+        false => threads.unwrap_or_else(rayon::current_num_threads),
+    };
+    let stats = Arc::new(Mutex::new(MiningStats::new(mining_threads, &params)));
 
     let mut threads = vec![];
     let (tx, rx) = mpsc::channel();
+    let (net, outgoing) = Network::new();
 
-    threads.push(server::start(tx, Arc::clone(&blockchain), Arc::clone(&mempool)));
-    threads.push(miner::start(rx, Arc::clone(&blockchain), Arc::clone(&mempool)));
+    threads.push(server::start(
+        tx,
+        net.clone(),
+        outgoing,
+        signal.clone(),
+        Arc::clone(&blockchain),
+        Arc::clone(&mempool),
+        Arc::clone(&stats),
+    ));
+    if params.regtest {
+        println!("> regtest, blocks only come from generate");
+    } else if mining_threads == 0 {
+        println!("> mining disabled, only relaying");
+    } else if !can_seal(&params, &validator) {
+        println!("> not a validator, only relaying");
+    } else {
+        let miner = Miner { engine: engine(&params, validator), signal, events, stats };
+        threads.push(miner::start(rx, miner, net, Arc::clone(&blockchain), Arc::clone(&mempool)));
+    }
 
     for t in threads {
         t.join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::node::*;
+    use crate::types::Tx;
+
+    #[test]
+    fn test_connect_switches_to_the_longest_branch() {
+        let params = ChainParams {
+            consensus: Consensus::Work,
+            difficulty: 0,
+            pow: crate::blockchain::Pow::Sha256,
+            genesis_balance: 1000.0,
+            block_reward: 10.0,
+            regtest: false,
+        };
+        let blockchain = Mutex::new(Blockchain::new(params));
+        let mempool = Mutex::new(Mempool::new());
+        let (signal, _events) = MinerSignal::new();
+
+        let block = |prev: &str, tx: Tx| {
+            let mut block = Block::new(1, prev.to_string(), vec![tx], 0, 0);
+            block.hash = block.generate_hash(crate::blockchain::Pow::Sha256);
+            block
+        };
+        let first = block("", Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None });
+        let second = block(&first.hash, Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 1, spends: None, stake: None });
+        assert_eq!(connect_blocks(&blockchain, &mempool, &signal, vec![first.clone(), second.clone()], true), Ok(()));
+        let balance = |bc: &Blockchain| (bc.get_all().len(), bc.state().balance('A'), bc.state().next_nonce('A'));
+        let before = balance(&blockchain.lock().unwrap());
+
+        // a sync response repeats what we have, and a peer relays a block back
+        assert_eq!(connect_blocks(&blockchain, &mempool, &signal, vec![first.clone(), second.clone()], true), Ok(()));
+        assert_eq!(connect_blocks(&blockchain, &mempool, &signal, vec![second.clone()], true), Ok(()));
+
+        // a block on an older parent is kept, but no more work than ours
+        // leaves the tip where it is
+        let rival = block(&first.hash, Tx { from: 'C', to: 'D', amount: 5, fee: 0.5, nonce: 0, spends: None, stake: None });
+        assert_eq!(connect_blocks(&blockchain, &mempool, &signal, vec![rival.clone()], true), Ok(()));
+        assert_eq!(balance(&blockchain.lock().unwrap()), before);
+        assert_eq!(blockchain.lock().unwrap().tip(), second.hash);
+        assert!(blockchain.lock().unwrap().contains(&rival.hash));
+
+        // one more block and the rival branch has more work; the tx only
+        // the old branch mined goes back to the mempool
+        let heavier = block(&rival.hash, Tx { from: 'C', to: 'D', amount: 5, fee: 0.5, nonce: 1, spends: None, stake: None });
+        assert_eq!(connect_blocks(&blockchain, &mempool, &signal, vec![heavier.clone()], true), Ok(()));
+        {
+            let bc = blockchain.lock().unwrap();
+            assert_eq!(bc.tip(), heavier.hash);
+            assert_eq!(bc.get_all(), &vec![first.clone(), rival.clone(), heavier.clone()]);
+            assert_eq!(bc.state().balance('C'), 1000.0 - 11.0);
+            assert_eq!(bc.state().next_nonce('A'), 1);
+        }
+        assert!(mempool.lock().unwrap().get(&second.txs[0].id()).is_some());
+
+        // a block on one we've never seen can't be placed
+        let orphan = block(&"0".repeat(64), Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 5, spends: None, stake: None });
+        assert_eq!(connect_blocks(&blockchain, &mempool, &signal, vec![orphan], true), Err(Rejected::UnknownParent));
+
+        // nor one whose hash doesn't match its contents
+        let mut forged = block(&heavier.hash, Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 1, spends: None, stake: None });
+        forged.nonce = 1;
+        assert_eq!(connect_blocks(&blockchain, &mempool, &signal, vec![forged], true), Err(Rejected::BadSeal));
+        assert_eq!(blockchain.lock().unwrap().tip(), heavier.hash);
+    }
+
+    #[test]
+    fn test_generate() {
+        let params = ChainParams {
+            consensus: Consensus::Work,
+            difficulty: 4,
+            pow: crate::blockchain::Pow::Sha256,
+            genesis_balance: 1000.0,
+            block_reward: 10.0,
+            regtest: false,
+        }.regtest();
+        let tx = Tx { from: 'A', to: 'B', amount: 1, fee: 0.5, nonce: 0, spends: None, stake: None };
+
+        // each chain has its own clock, stopped at the same time
+        let chain = || {
+            let blockchain = Mutex::new(Blockchain::new(params.clone()));
+            blockchain.lock().unwrap().clock().set_mock_time(1_600_000_000_000);
+            let mempool = Mutex::new(Mempool::new());
+            mempool.lock().unwrap().add(tx.clone()).unwrap();
+            let (signal, _events) = MinerSignal::new();
+
+            // far more txs than the mempool has
+            let builder = TemplateBuilder { min_txs: 5, reward: 10.0, miner: 'Y', ..Default::default() };
+            let blocks = generate(&blockchain, &mempool, &signal, &builder, 4000, 10);
+            let balance = blockchain.lock().unwrap().state().balance('Y');
+            let pending = mempool.lock().unwrap().len();
+            (blocks, balance, pending)
+        };
+
+        let (blocks, balance, pending) = chain();
+        let (again, _, _) = chain();
+
+        assert_eq!(blocks.len(), 10);
+        assert_eq!(blocks[0].txs, vec![tx.clone()]);
+        assert!(blocks[1..].iter().all(|block| block.txs.is_empty()));
+        assert!(blocks.iter().all(|block| block.ms == 0 && block.hash.starts_with('0')));
+        assert_eq!(balance, 1000.0 + 100.0 + tx.fee as f64);
+        assert_eq!(pending, 0);
+
+        // the clock was stopped, so it's the same chain both times
+        assert_eq!(again, blocks);
+    }
+}
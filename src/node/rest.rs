@@ -0,0 +1,236 @@
+use std::io;
+use std::collections::BTreeSet;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+use crate::types::Tx;
+use crate::blockchain::Block;
+use crate::events::EVENT_NAMES;
+use super::server::Context;
+use super::http::http_response;
+
+/// A block as json, with its height and how many blocks sit on top of it
+/// counting itself.
+pub(super) fn block_json(chain: &[Block], index: usize) -> Value {
+    let mut block = json!(chain[index]);
+    block["height"] = json!(index + 1);
+    block["confirmations"] = json!(chain.len() - index);
+    block
+}
+
+/// A tx as json, looked up in the mempool first and then in every block,
+/// newest first, along with where it was mined.
+pub(super) fn tx_json(ctx: &Context, id: &str) -> Option<Value> {
+    if let Some((tx, entered)) = ctx.mempool.lock().unwrap().get(id) {
+        return Some(json!({ "id": id, "tx": tx, "confirmed": false, "age": entered.elapsed().unwrap_or_default().as_secs() }));
+    }
+
+    let bc = ctx.blockchain.lock().unwrap();
+    let chain = bc.get_all();
+    chain.iter().enumerate().rev().find_map(|(index, block)| {
+        let tx = block.txs.iter().find(|tx| tx.id() == id)?;
+        Some(json!({
+            "id": id,
+            "tx": tx,
+            "confirmed": true,
+            "block": block.hash,
+            "height": index + 1,
+            "confirmations": chain.len() - index,
+        }))
+    })
+}
+
+fn found(value: Value) -> String {
+    http_response("200 OK", &value.to_string())
+}
+
+fn not_found(what: &str) -> String {
+    http_response("404 Not Found", &json!({ "error": format!("{} not found", what) }).to_string())
+}
+
+/// Answers a GET for `/chain/tip`, `/block/<hash>`, `/tx/<id>` or
+/// `/mempool`.
+pub(super) fn respond(ctx: &Context, path: &str) -> String {
+    let path = path.split('?').next().unwrap_or_default();
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match parts.as_slice() {
+        ["chain", "tip"] => {
+            let bc = ctx.blockchain.lock().unwrap();
+            found(json!({ "hash": bc.tip(), "height": bc.state().height() }))
+        },
+
+        ["block", hash] => {
+            let bc = ctx.blockchain.lock().unwrap();
+            let chain = bc.get_all();
+            match chain.iter().position(|block| block.hash == *hash) {
+                Some(index) => found(block_json(chain, index)),
+                None => not_found("block"),
+            }
+        },
+
+        ["tx", id] => match tx_json(ctx, id) {
+            Some(tx) => found(tx),
+            None => not_found("tx"),
+        },
+
+        ["mempool"] => {
+            let mp = ctx.mempool.lock().unwrap();
+            let ids: Vec<String> = mp.get_all().iter().map(Tx::id).collect();
+            found(json!({ "size": mp.len(), "bytes": mp.bytes(), "txs": ids }))
+        },
+
+        _ => not_found("path"),
+    }
+}
+
+/// Event names asked for in a `?events=newBlock,newTx` query.
+fn query_topics(path: &str) -> Result<BTreeSet<&'static str>, String> {
+    let query = path.split_once('?').map(|(_, query)| query).unwrap_or_default();
+    let names = query
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix("events="))
+        .flat_map(|names| names.split(','))
+        .filter(|name| !name.is_empty());
+    names.map(topic).collect()
+}
+
+fn topic(name: &str) -> Result<&'static str, String> {
+    EVENT_NAMES.iter().find(|&&n| n == name).copied().ok_or_else(|| format!("unknown event {}", name))
+}
+
+/// Applies a `{"subscribe": [...]}` or `{"unsubscribe": [...]}` message,
+/// answering with everything now subscribed to, or what was wrong with it.
+fn update_topics(topics: &mut BTreeSet<&'static str>, text: &str) -> Value {
+    let message: Value = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(_) => return json!({ "error": "not json" }),
+    };
+
+    for (key, subscribe) in &[("subscribe", true), ("unsubscribe", false)] {
+        let names = match message.get(*key) {
+            Some(Value::Array(names)) => names,
+            Some(_) => return json!({ "error": format!("{} takes a list of events", key) }),
+            None => continue,
+        };
+        for name in names {
+            let name = match name.as_str().map(topic) {
+                Some(Ok(name)) => name,
+                Some(Err(e)) => return json!({ "error": e }),
+                None => return json!({ "error": "events are named by strings" }),
+            };
+            match subscribe {
+                true => topics.insert(name),
+                false => topics.remove(name),
+            };
+        }
+    }
+    json!({ "subscribed": topics })
+}
+
+fn ws_error(e: tungstenite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Upgrades `/ws` to a websocket and pushes the events the client picks,
+/// either up front with `?events=newBlock,newTx` or at any time by sending
+/// `{"subscribe": [...]}` or `{"unsubscribe": [...]}`. A client too slow to
+/// keep up is told how many events it missed.
+pub(super) async fn subscribe(mut stream: TcpStream, key: &str, path: &str, ctx: Context) -> io::Result<()> {
+    let topics = match path.split('?').next() {
+        Some("/ws") => query_topics(path),
+        _ => Err("path not found".to_string()),
+    };
+    let (mut topics, mut events) = match (topics, ctx.events.subscribe()) {
+        (Ok(topics), Some(events)) => (topics, events),
+        (Err(e), _) => {
+            let response = http_response("400 Bad Request", &json!({ "error": e }).to_string());
+            return stream.write_all(response.as_bytes()).await;
+        },
+        (_, None) => return stream.write_all(http_response("503 Service Unavailable", "").as_bytes()).await,
+    };
+
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes()),
+    );
+    stream.write_all(handshake.as_bytes()).await?;
+    let mut ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let text = match event {
+                    Ok(event) if topics.contains(event.name()) => serde_json::to_string(&event)?,
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => json!({ "event": "lagged", "data": { "missed": missed } }).to_string(),
+                    Err(RecvError::Closed) => break,
+                };
+                ws.send(Message::Text(text)).await.map_err(ws_error)?;
+            },
+            message = ws.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = update_topics(&mut topics, &text);
+                    ws.send(Message::Text(reply.to_string())).await.map_err(ws_error)?;
+                },
+                Some(Ok(Message::Close(_))) | None => break,
+                // pings are answered for us
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(ws_error(e)),
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::rest::*;
+
+    // the body of a response, as json
+    fn body(response: &str) -> Value {
+        serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_respond() {
+        let ctx = Context::regtest();
+        let tx = Tx { from: 'A', to: 'B', amount: 5, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let mut block = Block::new(1, String::new(), vec![tx.clone()], 0, 0);
+        block.hash = block.generate_hash(crate::blockchain::Pow::Sha256);
+        ctx.blockchain.lock().unwrap().add(block.clone());
+
+        assert_eq!(body(&respond(&ctx, "/chain/tip")), json!({ "hash": block.hash, "height": 1 }));
+        assert_eq!(body(&respond(&ctx, &format!("/block/{}", block.hash)))["height"], json!(1));
+        assert_eq!(body(&respond(&ctx, &format!("/tx/{}", tx.id())))["block"], json!(block.hash));
+
+        let pending = Tx { nonce: 1, ..tx };
+        ctx.mempool.lock().unwrap().add(pending.clone()).unwrap();
+        assert_eq!(body(&respond(&ctx, &format!("/tx/{}", pending.id())))["confirmed"], json!(false));
+        assert_eq!(body(&respond(&ctx, "/mempool"))["txs"], json!([pending.id()]));
+
+        assert!(respond(&ctx, "/block/nope").starts_with("HTTP/1.1 404"));
+        assert!(respond(&ctx, "/nowhere").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_topics() {
+        assert_eq!(query_topics("/ws?events=newBlock,newTx").unwrap().len(), 2);
+        assert_eq!(query_topics("/ws?events=reorg").unwrap().len(), 1);
+        assert!(query_topics("/ws").unwrap().is_empty());
+        assert!(query_topics("/ws?events=everything").is_err());
+
+        let mut topics = BTreeSet::new();
+        assert_eq!(update_topics(&mut topics, r#"{"subscribe":["newTx","newBlock"]}"#), json!({ "subscribed": ["newBlock", "newTx"] }));
+        assert_eq!(update_topics(&mut topics, r#"{"unsubscribe":["newTx"]}"#), json!({ "subscribed": ["newBlock"] }));
+        assert!(update_topics(&mut topics, r#"{"subscribe":["nope"]}"#).get("error").is_some());
+        assert!(update_topics(&mut topics, "nope").get("error").is_some());
+    }
+}
@@ -0,0 +1,490 @@
+use std::io;
+use std::fs;
+use rand::prelude::*;
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+use crate::types::*;
+use crate::network::*;
+use crate::mempool::{Mempool, MempoolError};
+use crate::blockchain::*;
+use crate::config::*;
+use super::server::{accept_tx, generate_blocks, Context};
+use super::http::{http_response, Head};
+use super::rest::{block_json, tx_json};
+
+// the json-rpc 2.0 error codes, then bitcoind's for the app level errors
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const NOT_FOUND: i64 = -5;
+const OUT_OF_RANGE: i64 = -8;
+const MISSING_INPUTS: i64 = -25;
+const REJECTED: i64 = -26;
+const MISC_ERROR: i64 = -1;
+
+// blocks `sendtoaddress` aims to get a tx mined within when no fee is given
+const DEFAULT_FEE_TARGET: usize = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        RpcError { code, message: message.to_string() }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "code": self.code, "message": self.message })
+    }
+}
+
+/// Who may call: either `rpc_user` and `rpc_password`, or, with those left
+/// empty, whoever can read the cookie file written at startup.
+pub struct Auth {
+    credentials: String,
+}
+
+impl Auth {
+    pub fn new(user: &str, password: &str) -> Self {
+        Auth { credentials: format!("{}:{}", user, password) }
+    }
+
+    /// `rpc_user` and `rpc_password` when both are set, otherwise a fresh
+    /// cookie for the node on `port`.
+    pub fn from_settings(port: u16) -> io::Result<Self> {
+        let user = SETTINGS.get::<String>("rpc_user").unwrap();
+        let password = SETTINGS.get::<String>("rpc_password").unwrap();
+        if !user.is_empty() && !password.is_empty() {
+            return Ok(Auth::new(&user, &password));
+        }
+
+        let (auth, path) = Auth::cookie(port)?;
+        println!("> rpc cookie in {}", path);
+        Ok(auth)
+    }
+
+    /// A fresh cookie for the node on `port`, readable only by its owner.
+    fn cookie(port: u16) -> io::Result<(Self, String)> {
+        let dir = SETTINGS.get::<String>("data_dir").unwrap();
+        fs::create_dir_all(&dir)?;
+        let path = format!("{}/.cookie-{}", dir, port);
+
+        let secret: [u8; 32] = rand::thread_rng().gen();
+        let auth = Auth::new("__cookie__", &hex::encode(secret));
+        fs::write(&path, &auth.credentials)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok((auth, path))
+    }
+
+    /// Whether an `Authorization` header carries the right credentials.
+    pub fn allows(&self, header: Option<&str>) -> bool {
+        let encoded = match header.and_then(|h| h.strip_prefix("Basic ")) {
+            Some(encoded) => encoded.trim(),
+            None => return false,
+        };
+        match BASE64.decode(encoded) {
+            Ok(decoded) => decoded == self.credentials.as_bytes(),
+            Err(_) => false,
+        }
+    }
+}
+
+fn success(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "result": result, "id": id })
+}
+
+fn failure(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error.to_json(), "id": id })
+}
+
+// a positional or named param, with null the same as left out
+fn param<'a>(params: &'a Value, index: usize, name: &str) -> Option<&'a Value> {
+    let value = match params {
+        Value::Array(params) => params.get(index),
+        Value::Object(params) => params.get(name),
+        _ => None,
+    };
+    value.filter(|v| !v.is_null())
+}
+
+fn optional<T: DeserializeOwned>(params: &Value, index: usize, name: &str) -> Result<Option<T>, RpcError> {
+    match param(params, index, name) {
+        Some(value) => serde_json::from_value(value.clone())
+            .map(Some)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, &format!("bad {}: {}", name, e))),
+        None => Ok(None),
+    }
+}
+
+fn required<T: DeserializeOwned>(params: &Value, index: usize, name: &str) -> Result<T, RpcError> {
+    optional(params, index, name)?.ok_or_else(|| RpcError::new(INVALID_PARAMS, &format!("missing {}", name)))
+}
+
+fn consensus_name(consensus: &Consensus) -> &'static str {
+    match consensus {
+        Consensus::Work => "pow",
+        Consensus::Authority { .. } => "poa",
+        Consensus::Stake { .. } => "pos",
+    }
+}
+
+fn mempool_entry(mp: &Mempool, id: &str) -> Option<Value> {
+    let (tx, entered) = mp.get(id)?;
+    Some(json!({
+        "size": tx.size(),
+        "fee": tx.fee,
+        "feerate": tx.fee_rate().0,
+        "age": entered.elapsed().unwrap_or_default().as_secs(),
+        "ancestorcount": mp.ancestors(id).len(),
+        "descendantcount": mp.descendants(id).len(),
+        "tx": tx,
+    }))
+}
+
+/// Adds `tx` to the mempool and relays it, answering with its id.
+async fn send(ctx: &Context, tx: Tx) -> Result<Value, RpcError> {
+    ctx.limits.check_tx(&tx).map_err(|e| RpcError::new(REJECTED, &e.to_string()))?;
+    let id = tx.id();
+    match accept_tx(ctx, tx).await {
+        Ok(Ok(())) | Ok(Err(MempoolError::Duplicate)) => Ok(json!(id)),
+        Ok(Err(MempoolError::Orphan)) => Err(RpcError::new(MISSING_INPUTS, &MempoolError::Orphan.to_string())),
+        Ok(Err(e)) => Err(RpcError::new(REJECTED, &e.to_string())),
+        Err(e) => Err(RpcError::new(INTERNAL_ERROR, &format!("could not relay: {}", e))),
+    }
+}
+
+/// What a tx of `size` bytes should pay to get mined within a few blocks,
+/// or the mempool's floor while there's nothing to estimate from.
+fn default_fee(mp: &Mempool, size: usize) -> f32 {
+    let rate = mp.estimate_fee(DEFAULT_FEE_TARGET).map_or(0.0, |rate| rate.0).max(mp.min_fee_rate());
+
+    // the fee itself takes a few more bytes to write out
+    (rate * (size + 16) as f64) as f32
+}
+
+/// One http request's worth of calls, against the node on `port`.
+struct Session<'a> {
+    ctx: &'a Context,
+    port: u16,
+
+    // set by `stop`, which only takes effect once the response is sent
+    stopping: bool,
+}
+
+impl<'a> Session<'a> {
+    fn new(ctx: &'a Context, port: u16) -> Self {
+        Session { ctx, port, stopping: false }
+    }
+
+    /// The response to a request body, single or batched; `None` when it
+    /// was all notifications.
+    async fn handle(&mut self, body: &[u8]) -> Option<Value> {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(_) => return Some(failure(Value::Null, RpcError::new(PARSE_ERROR, "parse error"))),
+        };
+
+        match request {
+            Value::Array(calls) if calls.is_empty() => {
+                Some(failure(Value::Null, RpcError::new(INVALID_REQUEST, "empty batch")))
+            },
+            Value::Array(calls) => {
+                let mut responses = vec![];
+                for call in calls {
+                    responses.extend(self.handle_call(call).await);
+                }
+                match responses.is_empty() {
+                    true => None,
+                    false => Some(Value::Array(responses)),
+                }
+            },
+            call => self.handle_call(call).await,
+        }
+    }
+
+    async fn handle_call(&mut self, call: Value) -> Option<Value> {
+        let id = call.get("id").cloned();
+        let valid_id = match &id {
+            None | Some(Value::Null) | Some(Value::Number(_)) | Some(Value::String(_)) => true,
+            Some(_) => false,
+        };
+        let params = call.get("params").cloned().unwrap_or(Value::Null);
+        let method = match call.get("method") {
+            Some(Value::String(method))
+                if call.get("jsonrpc") == Some(&json!("2.0"))
+                    && valid_id
+                    && matches!(params, Value::Null | Value::Array(_) | Value::Object(_)) => method,
+            _ => return Some(failure(Value::Null, RpcError::new(INVALID_REQUEST, "invalid request"))),
+        };
+
+        let result = self.call(method, &params).await;
+
+        // notifications get no response, not even errors
+        let id = id?;
+        Some(match result {
+            Ok(result) => success(id, result),
+            Err(e) => failure(id, e),
+        })
+    }
+
+    async fn call(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        let ctx = self.ctx;
+        match method {
+            "getblockchaininfo" => {
+                let bc = ctx.blockchain.lock().unwrap();
+                let params = bc.params();
+                Ok(json!({
+                    "chain": if params.regtest { "regtest" } else { "main" },
+                    "blocks": bc.state().height(),
+                    "bestblockhash": bc.tip(),
+                    "consensus": consensus_name(&params.consensus),
+                    "pow": params.pow.to_string(),
+                    "difficulty": params.difficulty,
+                }))
+            },
+
+            "getblock" => {
+                let hash: String = required(params, 0, "blockhash")?;
+                let bc = ctx.blockchain.lock().unwrap();
+                let chain = bc.get_all();
+                let index = chain
+                    .iter()
+                    .position(|block| block.hash == hash)
+                    .ok_or_else(|| RpcError::new(NOT_FOUND, "block not found"))?;
+
+                Ok(block_json(chain, index))
+            },
+
+            "getblockhash" => {
+                let height: usize = required(params, 0, "height")?;
+                let bc = ctx.blockchain.lock().unwrap();
+                match height.checked_sub(1).and_then(|i| bc.get_all().get(i)) {
+                    Some(block) => Ok(json!(block.hash)),
+                    None => Err(RpcError::new(OUT_OF_RANGE, "block height out of range")),
+                }
+            },
+
+            "getrawmempool" => {
+                let verbose = optional::<bool>(params, 0, "verbose")?.unwrap_or(false);
+                let mp = ctx.mempool.lock().unwrap();
+                let ids = mp.get_all().iter().map(Tx::id).collect::<Vec<_>>();
+                if !verbose {
+                    return Ok(json!(ids));
+                }
+                let entries: Map<String, Value> = ids
+                    .into_iter()
+                    .filter_map(|id| Some((id.clone(), mempool_entry(&mp, &id)?)))
+                    .collect();
+                Ok(Value::Object(entries))
+            },
+
+            "getmempoolentry" => {
+                let id: String = required(params, 0, "txid")?;
+                let mp = ctx.mempool.lock().unwrap();
+                mempool_entry(&mp, &id).ok_or_else(|| RpcError::new(NOT_FOUND, "tx not in mempool"))
+            },
+
+            "sendrawtransaction" => {
+                // the tx itself, or its json as a string
+                let tx: Tx = match param(params, 0, "tx") {
+                    Some(Value::String(raw)) => serde_json::from_str(raw),
+                    Some(value) => serde_json::from_value(value.clone()),
+                    None => return Err(RpcError::new(INVALID_PARAMS, "missing tx")),
+                }.map_err(|e| RpcError::new(INVALID_PARAMS, &format!("bad tx: {}", e)))?;
+
+                send(ctx, tx).await
+            },
+
+            // builds the tx here, from the node's own account by default
+            "sendtoaddress" => {
+                let to: char = required(params, 0, "address")?;
+                let amount: i32 = required(params, 1, "amount")?;
+                if amount <= 0 {
+                    return Err(RpcError::new(INVALID_PARAMS, "amount must be positive"));
+                }
+                let from = match optional::<char>(params, 3, "from")? {
+                    Some(from) => from,
+                    None => TemplateBuilder::from_settings().miner,
+                };
+
+                let tx = {
+                    let mp = ctx.mempool.lock().unwrap();
+                    let tx = Tx { from, to, amount, fee: 0.0, nonce: mp.next_nonce(from), spends: None, stake: None };
+                    match optional::<f32>(params, 2, "fee")? {
+                        Some(fee) => Tx { fee, ..tx },
+                        None => Tx { fee: default_fee(&mp, tx.size()), ..tx },
+                    }
+                };
+                send(ctx, tx).await
+            },
+
+            "gettransaction" => {
+                let id: String = required(params, 0, "txid")?;
+                tx_json(ctx, &id).ok_or_else(|| RpcError::new(NOT_FOUND, "tx not found"))
+            },
+
+            "getpeerinfo" => {
+                let peers = get_live_peers(&[], self.port).await;
+                Ok(json!(peers.iter().map(|addr| json!({ "addr": addr.to_string() })).collect::<Vec<_>>()))
+            },
+
+            "addpeer" => {
+                let addr: String = required(params, 0, "addr")?;
+                let addr = parse_addr(&addr).ok_or_else(|| RpcError::new(INVALID_PARAMS, "bad addr"))?;
+                Ok(json!({ "addr": addr.to_string(), "added": add_peer(addr) }))
+            },
+
+            "generate" => {
+                let blocks: usize = required(params, 0, "blocks")?;
+                let to: Option<char> = optional(params, 1, "address")?;
+                if !ctx.params.regtest {
+                    return Err(RpcError::new(MISC_ERROR, "not a regtest node"));
+                }
+                generate_blocks(ctx, blocks, to)
+                    .await
+                    .map(|hashes| json!(hashes))
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, &format!("could not relay: {}", e)))
+            },
+
+            "getmininginfo" => {
+                let mut info = json!(ctx.stats.lock().unwrap().info());
+                info["blocks"] = json!(ctx.blockchain.lock().unwrap().state().height());
+                info["pooledtx"] = json!(ctx.mempool.lock().unwrap().len());
+                Ok(info)
+            },
+
+            "stop" => {
+                self.stopping = true;
+                Ok(json!("stopping"))
+            },
+
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+        }
+    }
+}
+
+/// Answers a POST of json-rpc calls, and whether one of them was `stop`,
+/// which should only happen once the response is out.
+pub(super) async fn respond(ctx: &Context, port: u16, auth: &Auth, head: &Head, body: &[u8]) -> (String, bool) {
+    if !auth.allows(head.authorization.as_deref()) {
+        return (http_response("401 Unauthorized", ""), false);
+    }
+
+    let mut session = Session::new(ctx, port);
+    let response = match session.handle(body).await {
+        Some(response) => http_response("200 OK", &response.to_string()),
+        None => http_response("204 No Content", ""),
+    };
+    (response, session.stopping)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::rpc::*;
+
+    fn handle(ctx: &Context, body: &str) -> (Option<Value>, bool) {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let mut session = Session::new(ctx, 4000);
+        let response = runtime.block_on(session.handle(body.as_bytes()));
+        (response, session.stopping)
+    }
+
+    #[test]
+    fn test_auth() {
+        let auth = Auth::new("user", "secret");
+        let header = format!("Basic {}", BASE64.encode("user:secret"));
+        assert!(auth.allows(Some(&header)));
+        assert!(!auth.allows(Some(&format!("Basic {}", BASE64.encode("user:wrong")))));
+        assert!(!auth.allows(Some("Bearer user:secret")));
+        assert!(!auth.allows(Some("Basic not base64!")));
+        assert!(!auth.allows(None));
+    }
+
+    #[test]
+    fn test_calls() {
+        let ctx = Context::regtest();
+        let mut block = Block::new(1, String::new(), vec![], 0, 0);
+        block.hash = block.generate_hash(Pow::Sha256);
+        let hash = block.hash.clone();
+        ctx.blockchain.lock().unwrap().add(block);
+
+        let (response, _) = handle(&ctx, r#"{"jsonrpc":"2.0","method":"getblockhash","params":[1],"id":1}"#);
+        assert_eq!(response, Some(json!({ "jsonrpc": "2.0", "result": hash, "id": 1 })));
+
+        let (response, _) = handle(&ctx, &format!(r#"{{"jsonrpc":"2.0","method":"getblock","params":{{"blockhash":"{}"}},"id":"a"}}"#, hash));
+        assert_eq!(response.unwrap()["result"]["height"], json!(1));
+
+        let (response, _) = handle(&ctx, r#"{"jsonrpc":"2.0","method":"getblockchaininfo","id":2}"#);
+        let info = &response.unwrap()["result"];
+        assert_eq!((&info["chain"], &info["blocks"]), (&json!("regtest"), &json!(1)));
+
+        let (response, _) = handle(&ctx, r#"{"jsonrpc":"2.0","method":"getblockhash","params":[2],"id":3}"#);
+        assert_eq!(response.unwrap()["error"]["code"], json!(OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn test_mempool_calls() {
+        let ctx = Context::regtest();
+        let tx = Tx { from: 'A', to: 'B', amount: 5, fee: 0.5, nonce: 0, spends: None, stake: None };
+        let send = json!({ "jsonrpc": "2.0", "method": "sendrawtransaction", "params": [tx], "id": 1 });
+        let (response, _) = handle(&ctx, &send.to_string());
+        assert_eq!(response.unwrap()["result"], json!(tx.id()));
+
+        let (response, _) = handle(&ctx, r#"{"jsonrpc":"2.0","method":"getrawmempool","id":2}"#);
+        assert_eq!(response.unwrap()["result"], json!([tx.id()]));
+
+        let entry = json!({ "jsonrpc": "2.0", "method": "getmempoolentry", "params": [tx.id()], "id": 3 });
+        let (response, _) = handle(&ctx, &entry.to_string());
+        assert_eq!(response.unwrap()["result"]["fee"], json!(0.5));
+
+        let (response, _) = handle(&ctx, r#"{"jsonrpc":"2.0","method":"getmempoolentry","params":["nope"],"id":4}"#);
+        assert_eq!(response.unwrap()["error"]["code"], json!(NOT_FOUND));
+
+        // the node picks the nonce, after A's pending tx
+        let (response, _) = handle(&ctx, r#"{"jsonrpc":"2.0","method":"sendtoaddress","params":{"address":"C","amount":2,"fee":0.1,"from":"A"},"id":5}"#);
+        let id = response.unwrap()["result"].as_str().unwrap().to_string();
+        let (pending, _) = ctx.mempool.lock().unwrap().get(&id).map(|(tx, at)| (tx.clone(), at)).unwrap();
+        assert_eq!((pending.to, pending.nonce), ('C', 1));
+
+        let (response, _) = handle(&ctx, r#"{"jsonrpc":"2.0","method":"generate","params":[1],"id":6}"#);
+        assert_eq!(response.unwrap()["result"].as_array().unwrap().len(), 1);
+        let get = json!({ "jsonrpc": "2.0", "method": "gettransaction", "params": [id], "id": 7 });
+        let (response, _) = handle(&ctx, &get.to_string());
+        assert_eq!(response.unwrap()["result"]["height"], json!(1));
+    }
+
+    #[test]
+    fn test_envelopes() {
+        let ctx = Context::regtest();
+        let error_code = |body: &str| handle(&ctx, body).0.unwrap()["error"]["code"].clone();
+
+        assert_eq!(error_code("{not json"), json!(PARSE_ERROR));
+        assert_eq!(error_code("[]"), json!(INVALID_REQUEST));
+        assert_eq!(error_code(r#"{"method":"stop","id":1}"#), json!(INVALID_REQUEST));
+        assert_eq!(error_code(r#"{"jsonrpc":"2.0","method":"nope","id":1}"#), json!(METHOD_NOT_FOUND));
+        assert_eq!(error_code(r#"{"jsonrpc":"2.0","method":"getblock","params":[],"id":1}"#), json!(INVALID_PARAMS));
+
+        // notifications run but get no response, and a batch answers the rest
+        let (response, stopping) = handle(&ctx, r#"{"jsonrpc":"2.0","method":"stop"}"#);
+        assert_eq!((response, stopping), (None, true));
+
+        let batch = r#"[{"jsonrpc":"2.0","method":"getblockhash","params":[1],"id":1},
+                        {"jsonrpc":"2.0","method":"getmininginfo"},
+                        {"jsonrpc":"2.0","method":"nope","id":2}]"#;
+        let response = handle(&ctx, batch).0.unwrap();
+        let ids: Vec<&Value> = response.as_array().unwrap().iter().map(|r| &r["id"]).collect();
+        assert_eq!(ids, vec![&json!(1), &json!(2)]);
+    }
+}
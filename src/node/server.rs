@@ -1,180 +1,717 @@
 use std::thread;
-use std::net::{TcpListener, TcpStream};
-use std::io::{prelude::*, BufReader};
 use std::io;
+use std::fs;
+use std::time::{Duration, Instant};
 use std::sync::{Mutex, Arc, mpsc};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc as queue, Notify, Semaphore};
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 
 use crate::types::*;
 use crate::network::*;
-use crate::mempool::Mempool;
+use crate::network::limits::*;
+use crate::mempool::{FeeEstimator, Mempool, MempoolError, MAX_TARGET};
 use crate::blockchain::*;
+use crate::config::*;
+use crate::events::Events;
+use super::miner::{MinerSignal, MiningStats};
+use super::work::WorkServer;
+use super::http;
 
-fn handler(
-    stream: TcpStream,
-    local_port: u16,
-    blockchain: &Arc<Mutex<Blockchain>>,
-    mempool: &Arc<Mutex<Mempool>>,
+// compact blocks we're still collecting txs for; a handful is plenty since
+// only the tip is ever being rebuilt
+const MAX_PENDING_BLOCKS: usize = 16;
+
+// the most blocks a single generate request mines
+const MAX_GENERATE: usize = 1000;
+
+#[derive(Clone)]
+pub(super) struct Context {
+    pub(super) net: Network,
+    pub(super) limits: Limits,
+    pub(super) scores: Arc<Mutex<PeerScores>>,
+    pub(super) blockchain: Arc<Mutex<Blockchain>>,
+    pub(super) mempool: Arc<Mutex<Mempool>>,
+    pub(super) miner: MinerSignal,
+    pub(super) stats: Arc<Mutex<MiningStats>>,
+    pub(super) work: Arc<Mutex<WorkServer>>,
+    pub(super) params: ChainParams,
+
+    // wakes `persist` to save the mempool and exit, like Ctrl-C does
+    pub(super) stop: Arc<Notify>,
+
+    // what the chain and mempool report, for the websocket api
+    pub(super) events: Events,
+
+    // the chain's clock, which regtest can stop
+    pub(super) clock: Clock,
+
+    // compact blocks waiting on txs, keyed by block hash
+    pending: Arc<Mutex<HashMap<String, PartialBlock>>>,
+
+    // ids of our sync requests still waiting on responses; anything else
+    // claiming to be a sync response is ignored
+    requests: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl Context {
+    pub(super) fn new(
+        net: Network,
+        miner: MinerSignal,
+        blockchain: Arc<Mutex<Blockchain>>,
+        mempool: Arc<Mutex<Mempool>>,
+        stats: Arc<Mutex<MiningStats>>,
+    ) -> Self {
+        let (params, events, clock) = {
+            let bc = blockchain.lock().unwrap();
+            (bc.params().clone(), bc.events().clone(), bc.clock().clone())
+        };
+        Context {
+            net,
+            limits: Limits::from_settings(),
+            scores: Arc::new(Mutex::new(PeerScores::from_settings())),
+            blockchain,
+            mempool,
+            miner,
+            stats,
+            work: Arc::new(Mutex::new(WorkServer::new(TemplateBuilder::from_settings()))),
+            params,
+            stop: Arc::new(Notify::new()),
+            events,
+            clock,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            requests: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// A regtest node's context, with events on and nobody listening.
+    #[cfg(test)]
+    pub(super) fn regtest() -> Self {
+        let params = ChainParams {
+            consensus: Consensus::Work,
+            difficulty: 1,
+            pow: Pow::Sha256,
+            genesis_balance: 1000.0,
+            block_reward: 10.0,
+            regtest: true,
+        };
+        let events = Events::new(16);
+        let mut blockchain = Blockchain::new(params.clone());
+        blockchain.set_events(events.clone());
+        let mut mempool = Mempool::new();
+        mempool.set_events(events);
+        mempool.set_clock(blockchain.clock().clone());
+
+        let (net, _) = Network::new();
+        let (miner, _) = MinerSignal::new();
+        Context::new(
+            net,
+            miner,
+            Arc::new(Mutex::new(blockchain)),
+            Arc::new(Mutex::new(mempool)),
+            Arc::new(Mutex::new(MiningStats::new(0, &params))),
+        )
+    }
+}
+
+// state kept for the peer on the other end
+struct Peer {
+    tx_relay: RateLimiter,
+    last_seen: Instant,
+}
+
+impl Peer {
+    fn new() -> Self {
+        Peer {
+            tx_relay: RateLimiter::new(
+                SETTINGS.get::<f64>("tx_relay_per_sec").unwrap(),
+                SETTINGS.get::<f64>("tx_relay_burst").unwrap(),
+            ),
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+fn payload<T: DeserializeOwned>(value: Value) -> io::Result<T> {
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Queues a message back to whoever sent the one being handled. A full
+/// queue means the peer isn't reading, so the message is dropped.
+fn respond<T: Serialize>(
+    reply: &queue::Sender<String>,
+    action: ActionType,
+    payload: T,
+    id: Option<u64>,
+) -> io::Result<()> {
+    let command = Command { action, payload, id };
+    if reply.try_send(serde_json::to_string(&command)?).is_err() {
+        println!("reply queue full, dropping response");
+    }
+    Ok(())
+}
+
+/// Takes a sync response only if it answers one of our requests, and
+/// forgets the request once its last batch arrives.
+fn expected<T>(ctx: &Context, id: Option<u64>, response: &SyncResponse<T>) -> bool {
+    let mut requests = ctx.requests.lock().unwrap();
+    match id {
+        Some(id) if requests.contains(&id) => {
+            if !response.more {
+                requests.remove(&id);
+            }
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Connects a block a peer relayed. One building on a block we don't have
+/// means the peer is on a branch we've missed, so we ask it for its chain.
+fn accept_block(ctx: &Context, block: Block, reply: &queue::Sender<String>) -> io::Result<()> {
+    let connected = super::connect_blocks(&ctx.blockchain, &ctx.mempool, &ctx.miner, vec![block], true);
+    if let Err(super::Rejected::UnknownParent) = connected {
+        let id = ctx.net.next_id();
+        ctx.requests.lock().unwrap().insert(id);
+        respond(reply, ActionType::SyncRequest(ObjectType::Block), SyncRequest {}, Some(id))?;
+    }
+    Ok(())
+}
+
+/// Sends a sync response as one or more batches, flagging all but the last.
+fn respond_batches<T: Serialize + Clone>(
+    reply: &queue::Sender<String>,
+    object: ObjectType,
+    batches: Vec<Vec<T>>,
+    id: Option<u64>,
+) -> io::Result<()> {
+    let count = batches.len();
+    if count == 0 {
+        let empty: SyncResponse<T> = SyncResponse { data: vec![], more: false };
+        return respond(reply, ActionType::SyncResponse(object), empty, id);
+    }
+
+    for (i, data) in batches.into_iter().enumerate() {
+        let response = SyncResponse { data, more: i + 1 < count };
+        respond(reply, ActionType::SyncResponse(object.clone()), response, id)?;
+    }
+    Ok(())
+}
+
+/// Handles a single message. Anything breaking the limits comes back as an
+/// `InvalidData` error, which closes the session and counts against the peer.
+async fn handler(
+    text: &str,
+    ctx: &Context,
+    peer: &mut Peer,
+    reply: &queue::Sender<String>,
 ) -> io::Result<()> {
-    let mut rdr = BufReader::new(stream);
-    let mut text = String::new();
-    rdr.read_line(&mut text)?;
     if text.trim().is_empty() {
         return Ok(())
     }
 
-    // received a request to sync either blocks or txs
-    if let Ok(command) = serde_json::from_str::<Command<SyncRequest>>(&text) {
-        let payload = &command.payload;
-
-        match &command.action {
-            ActionType::SyncRequest(ObjectType::Block) => {
-                let blocks = {
-                    let bc = blockchain.lock().unwrap();
-                    bc.get_all().to_vec()
-                };
-
-                broadcast::<SyncResponse<Block>>(
-                    ActionType::SyncResponse(ObjectType::Block),
-                    &SyncResponse::<Block> { data: blocks },
-                    &[payload.port],
-                    0,
-                ).unwrap();
-            },
-            ActionType::SyncRequest(ObjectType::Tx) => {
-                let txs = {
-                    let mut mp = mempool.lock().unwrap();
-                    mp.get_all().to_vec()
-                };
-
-                broadcast::<SyncResponse<Tx>>(
-                    ActionType::SyncResponse(ObjectType::Tx),
-                    &SyncResponse::<Tx> { data: txs },
-                    &[payload.port],
-                    0,
-                ).unwrap();
-            },
-            _ => (),
-        }
+    // unknown messages are ignored rather than held against the peer
+    let command = match serde_json::from_str::<Command<Value>>(text) {
+        Ok(command) => command,
+        Err(_) => return Ok(()),
+    };
 
-        return Ok(());
+    match command.action {
+        // received a request to sync either blocks or txs
+        ActionType::SyncRequest(ObjectType::Block) => {
+            let _: SyncRequest = payload(command.payload)?;
+            let batches = {
+                let bc = ctx.blockchain.lock().unwrap();
+                ctx.limits.batches(bc.get_all())
+            };
+
+            respond_batches(reply, ObjectType::Block, batches, command.id)?;
+        },
+        ActionType::SyncRequest(ObjectType::Tx) => {
+            let _: SyncRequest = payload(command.payload)?;
+            let batches = {
+                let mp = ctx.mempool.lock().unwrap();
+                ctx.limits.batches(&mp.get_all())
+            };
+
+            respond_batches(reply, ObjectType::Tx, batches, command.id)?;
+        },
+
+        // received a blocks response from a requested sync
+        ActionType::SyncResponse(ObjectType::Block) => {
+            let response: SyncResponse<Block> = payload(command.payload)?;
+            ctx.limits.check_items(&response.data)?;
+            if !expected(ctx, command.id, &response) {
+                return Ok(());
+            }
+            for block in &response.data {
+                ctx.limits.check_block(block)?;
+            }
+
+            // a peer on another branch isn't misbehaving; its blocks are kept,
+            // and switched to if they beat ours
+            if let Err(super::Rejected::BadSeal) = super::connect_blocks(&ctx.blockchain, &ctx.mempool, &ctx.miner, response.data, false) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad block seal"));
+            }
+        },
+
+        // received a txs response from a requested sync
+        ActionType::SyncResponse(ObjectType::Tx) => {
+            let response: SyncResponse<Tx> = payload(command.payload)?;
+            ctx.limits.check_items(&response.data)?;
+            if !expected(ctx, command.id, &response) {
+                return Ok(());
+            }
+            for tx in &response.data {
+                ctx.limits.check_tx(tx)?;
+            }
+
+            let mut mp = ctx.mempool.lock().unwrap();
+            for tx in response.data {
+                let _ = mp.add(tx);
+            }
+        },
+
+        // received a block
+        ActionType::Broadcast(ObjectType::Block) => {
+            let block: Block = payload(command.payload)?;
+            ctx.limits.check_block(&block)?;
+            accept_block(ctx, block, reply)?;
+        },
+
+        // received a compact block; rebuild it from the mempool and only ask
+        // the sender for whatever is missing
+        ActionType::Broadcast(ObjectType::CompactBlock) => {
+            let compact: CompactBlock = payload(command.payload)?;
+            check_hash(&compact.hash)?;
+            ctx.limits.check_block_txs(&compact.short_ids)?;
+
+            let partial = {
+                let mp = ctx.mempool.lock().unwrap();
+                compact.reconstruct(&mp.get_all())
+            };
+
+            let missing = partial.missing();
+            if missing.is_empty() {
+                if let Some(block) = partial.to_block(ctx.params.pow) {
+                    return accept_block(ctx, block, reply);
+                }
+            }
+
+            // either txs are missing or a short id matched the wrong tx, in
+            // which case we start from scratch and fetch all of them
+            let indexes = if missing.is_empty() {
+                (0..compact.short_ids.len()).collect()
+            } else {
+                missing
+            };
+
+            {
+                let mut pending = ctx.pending.lock().unwrap();
+                if pending.len() >= MAX_PENDING_BLOCKS {
+                    pending.clear();
+                }
+                pending.insert(compact.hash.clone(), partial);
+            }
+            respond(
+                reply,
+                ActionType::GetBlockTxs,
+                GetBlockTxs { hash: compact.hash, indexes },
+                None,
+            )?;
+        },
+
+        // a peer is rebuilding one of our blocks and needs some of its txs
+        ActionType::GetBlockTxs => {
+            let request: GetBlockTxs = payload(command.payload)?;
+            ctx.limits.check_block_txs(&request.indexes)?;
+
+            let txs = {
+                let bc = ctx.blockchain.lock().unwrap();
+                bc.get_all()
+                    .iter()
+                    .find(|b| b.hash == request.hash)
+                    .map(|b| request.indexes.iter().filter_map(|&i| b.txs.get(i).cloned()).collect())
+            };
+
+            if let Some(txs) = txs {
+                respond(
+                    reply,
+                    ActionType::BlockTxs,
+                    BlockTxs { hash: request.hash, indexes: request.indexes, txs },
+                    command.id,
+                )?;
+            }
+        },
+
+        ActionType::BlockTxs => {
+            let response: BlockTxs = payload(command.payload)?;
+            ctx.limits.check_block_txs(&response.txs)?;
+            for tx in &response.txs {
+                ctx.limits.check_tx(tx)?;
+            }
+
+            let partial = ctx.pending.lock().unwrap().remove(&response.hash);
+            if let Some(mut partial) = partial {
+                partial.fill(&response.indexes, &response.txs);
+                match partial.to_block(ctx.params.pow) {
+                    Some(block) => accept_block(ctx, block, reply)?,
+                    None => println!("could not rebuild block {}", &response.hash[..8.min(response.hash.len())]),
+                }
+            }
+        },
+
+        // a wallet asking what fee to pay
+        ActionType::EstimateFee => {
+            let request: EstimateFee = payload(command.payload)?;
+            let target_blocks = request.target_blocks.clamp(1, MAX_TARGET);
+            let fee_rate = {
+                let mp = ctx.mempool.lock().unwrap();
+                mp.estimate_fee(target_blocks).map(|rate| rate.0)
+            };
+
+            respond(
+                reply,
+                ActionType::FeeEstimate,
+                FeeEstimate { target_blocks, fee_rate },
+                command.id,
+            )?;
+        },
+
+        // someone checking on our miner
+        ActionType::GetMiningInfo => {
+            let info = ctx.stats.lock().unwrap().info();
+            respond(reply, ActionType::MiningInfo, info, command.id)?;
+        },
+
+        // an external miner asking for a job
+        ActionType::GetWork => {
+            let _: GetWork = payload(command.payload)?;
+            let work = {
+                let mut work = ctx.work.lock().unwrap();
+                Work { node: work.port(), template: work.job(&ctx.blockchain, &ctx.mempool) }
+            };
+            respond(reply, ActionType::Work, work, command.id)?;
+        },
+
+        // an external miner handing back a solved job
+        ActionType::SubmitWork => {
+            let SubmitWork { block } = payload(command.payload)?;
+            ctx.limits.check_block(&block)?;
+
+            let compact = block.to_compact();
+            let result = match super::submit_block(&ctx.blockchain, &ctx.mempool, &ctx.miner, block) {
+                Ok(()) => {
+                    println!("> accepted block {} from an external miner", &compact.hash[..8]);
+                    ctx.net.send::<CompactBlock>(
+                        ActionType::Broadcast(ObjectType::CompactBlock),
+                        &compact,
+                        &[],
+                    ).await?;
+                    WorkResult { accepted: true, reason: None }
+                },
+                Err(e) => WorkResult { accepted: false, reason: Some(e.to_string()) },
+            };
+            respond(reply, ActionType::WorkResult, result, command.id)?;
+        },
+
+        // regtest only: mine blocks on the spot
+        ActionType::Generate => {
+            let Generate { blocks, to } = payload(command.payload)?;
+            if !ctx.params.regtest {
+                let reason = Some("not a regtest node".to_string());
+                return respond(reply, ActionType::Generated, Generated { hashes: vec![], reason }, command.id);
+            }
+
+            let blocks = generate_blocks(ctx, blocks, to).await?;
+            respond(reply, ActionType::Generated, Generated { hashes: blocks, reason: None }, command.id)?;
+        },
+
+        // regtest only: stop or restart the clock blocks are timed with
+        ActionType::SetMockTime => {
+            let SetMockTime { unix_ms } = payload(command.payload)?;
+            let reason = match ctx.params.regtest {
+                true => {
+                    ctx.clock.set_mock_time(unix_ms);
+                    None
+                },
+                false => Some("not a regtest node".to_string()),
+            };
+            respond(reply, ActionType::MockTime, MockTime { unix_ms: ctx.clock.mock_time(), reason }, command.id)?;
+        },
+
+        // received a transaction
+        ActionType::Broadcast(ObjectType::Tx) => {
+            let tx: Tx = payload(command.payload)?;
+            ctx.limits.check_tx(&tx)?;
+
+            // over the relay rate; drop it without penalising the peer
+            if !peer.tx_relay.allow() {
+                return Ok(());
+            }
+
+            accept_tx(ctx, tx).await?.ok();
+        },
+
+        _ => (),
     }
 
-    // received a blocks response from a requested sync
-    if let Ok(command) = serde_json::from_str::<Command<SyncResponse<Block>>>(&text) {
-        let payload = &command.payload;
-        let mut bc = blockchain.lock().unwrap();
+    Ok(())
+}
 
-        for block in &payload.data {
-            bc.add(block.clone());
-        }
+/// Mines up to `blocks` blocks on the spot, paying `to` or the miner
+/// account, and relays them; regtest only. Returns their hashes.
+pub(super) async fn generate_blocks(ctx: &Context, blocks: usize, to: Option<char>) -> io::Result<Vec<String>> {
+    let mut builder = TemplateBuilder::from_settings();
+    builder.miner = to.unwrap_or(builder.miner);
+    let port = ctx.work.lock().unwrap().port();
+    let blocks = super::generate(
+        &ctx.blockchain,
+        &ctx.mempool,
+        &ctx.miner,
+        &builder,
+        port,
+        blocks.min(MAX_GENERATE),
+    );
 
-        return Ok(());
+    for block in &blocks {
+        ctx.net.send::<CompactBlock>(
+            ActionType::Broadcast(ObjectType::CompactBlock),
+            &block.to_compact(),
+            &[],
+        ).await?;
     }
+    Ok(blocks.into_iter().map(|block| block.hash).collect())
+}
+
+/// Adds a tx from a peer or an rpc client to the mempool, relaying it and
+/// any orphans it resolves once it's in.
+pub(super) async fn accept_tx(ctx: &Context, tx: Tx) -> io::Result<Result<(), MempoolError>> {
+    let (added, mp_count, mp_bytes, min_fee_rate, orphans) = {
+        let mut mp = ctx.mempool.lock().unwrap();
+        (mp.add(tx.clone()), mp.len(), mp.bytes(), mp.min_fee_rate(), mp.orphan_count())
+    };
 
-    // received a txs response from a requested sync
-    if let Ok(command) = serde_json::from_str::<Command<SyncResponse<Tx>>>(&text) {
-        let payload = &command.payload;
-        let mut mp = mempool.lock().unwrap();
+    match &added {
+        Ok(resolved) => {
+            println!("added {:?} to mempool ({} total, {} bytes)", tx, mp_count, mp_bytes);
+            ctx.miner.new_txs();
+            for tx in std::iter::once(&tx).chain(resolved) {
+                ctx.net.send::<Tx>(
+                    ActionType::Broadcast(ObjectType::Tx),
+                    tx,
+                    &[],
+                ).await?;
+            }
+        },
+        // already seen, most likely relayed back to us
+        Err(MempoolError::Duplicate) => (),
+        Err(MempoolError::Orphan) => println!("holding orphan {:?} ({} total)", tx, orphans),
+        Err(e) => println!("rejected {:?}: {} (min fee rate {:.4})", tx, e, min_fee_rate),
+    }
+    Ok(added.map(|_| ()))
+}
 
-        for tx in &payload.data {
-            let _ = mp.add(tx.clone());
+/// Handles messages from every session, inbound and outbound, one at a
+/// time on the event loop.
+async fn handle_inbox(mut inbox: queue::Receiver<Incoming>, ctx: Context) {
+    let mut peers: HashMap<String, Peer> = HashMap::new();
+
+    while let Some(incoming) = inbox.recv().await {
+        // make way for a new peer by forgetting the one quiet the longest
+        if peers.len() >= MAX_TRACKED_PEERS && !peers.contains_key(&incoming.peer_id) {
+            let quietest = peers.iter().min_by_key(|(_, p)| p.last_seen).map(|(id, _)| id.clone());
+            if let Some(id) = quietest {
+                peers.remove(&id);
+            }
         }
+        let peer = peers.entry(incoming.peer_id.clone()).or_insert_with(Peer::new);
+        peer.last_seen = Instant::now();
 
+        let result = handler(&incoming.text, &ctx, peer, &incoming.reply).await;
+        if let Err(e) = &result {
+            if e.kind() == io::ErrorKind::InvalidData
+                && ctx.scores.lock().unwrap().penalize(&incoming.peer_id)
+            {
+                println!("banned peer {}", incoming.peer_id);
+            }
+        }
+
+        let _ = incoming.done.send(result);
+    }
+}
+
+/// Serves one inbound connection until the peer hangs up, goes idle or
+/// breaks the rules.
+async fn session(
+    stream: TcpStream,
+    ctx: Context,
+    inbox: queue::Sender<Incoming>,
+) -> io::Result<()> {
+    let conn = Connection::accept(stream).await?;
+    if ctx.scores.lock().unwrap().is_banned(&conn.peer_id) {
         return Ok(());
     }
 
-    // received a block
-    if let Ok(command) = serde_json::from_str::<Command<Block>>(&text) {
-        let block = &command.payload;
+    let (reply, queue) = queue::channel(SETTINGS.get::<usize>("peer_queue_size").unwrap());
+    run_session(conn, queue, reply, Some(inbox)).await
+}
 
-        // remove mined txs from mempool
-        {
-            let mut mp = mempool.lock().unwrap();
-            for tx in block.get_all() {
-                mp.remove(&tx);
-            }
+// where a node on `port` keeps its mempool and fee estimates
+struct DataFiles {
+    mempool: String,
+    fees: String,
+}
+
+impl DataFiles {
+    fn new(port: u16) -> io::Result<Self> {
+        let dir = SETTINGS.get::<String>("data_dir").unwrap();
+        fs::create_dir_all(&dir)?;
+        Ok(DataFiles {
+            mempool: format!("{}/mempool-{}.json", dir, port),
+            fees: format!("{}/fee_estimates-{}.json", dir, port),
+        })
+    }
+
+    fn save(&self, mp: &Mempool) {
+        if let Err(e) = mp.save(&self.mempool) {
+            println!("could not save mempool to {}, {}", self.mempool, e);
         }
+        if let Err(e) = mp.fee_estimator().save(&self.fees) {
+            println!("could not save fee estimates to {}, {}", self.fees, e);
+        }
+    }
 
-        // add the new block
-        {
-            let mut bc = blockchain.lock().unwrap();
-            bc.add(block.clone());
+    fn load(&self, mp: &mut Mempool) {
+        // estimates first, so the reloaded txs aren't timed again
+        match FeeEstimator::load(&self.fees) {
+            Ok(estimator) => mp.set_fee_estimator(estimator),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => println!("could not load fee estimates from {}, {}", self.fees, e),
         }
 
-        return Ok(());
+        match mp.load(&self.mempool) {
+            Ok(count) => println!("> loaded {} tx(s) from {}", count, self.mempool),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => println!("could not load mempool from {}, {}", self.mempool, e),
+        }
     }
+}
 
-    // received a transaction
-    if let Ok(command) = serde_json::from_str::<Command<Tx>>(&text) {
-        let tx = &command.payload;
+/// Saves the mempool and fee estimates every so often, and once more on
+/// Ctrl-C or an rpc `stop` before exiting, so a restart picks up where it
+/// left off.
+async fn persist(mempool: Arc<Mutex<Mempool>>, files: DataFiles, stop: Arc<Notify>) {
+    let secs = SETTINGS.get::<u64>("mempool_save_secs").unwrap();
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+    let mut shutdown = Box::pin(tokio::signal::ctrl_c());
 
-        let (added, mp_count) = {
-            let mut mp = mempool.lock().unwrap();
-            (mp.add(tx.clone()), mp.len())
+    loop {
+        let exit = tokio::select! {
+            _ = interval.tick() => false,
+            _ = &mut shutdown => true,
+            _ = stop.notified() => true,
         };
 
-        if added {
-            println!("added {:?} to mempool ({} total)", tx, mp_count);
-            broadcast::<Tx>(
-                ActionType::Broadcast(ObjectType::Tx),
-                tx,
-                &[],
-                local_port,
-            ).unwrap();
+        files.save(&mempool.lock().unwrap());
+        if exit {
+            println!("> saved mempool to {}", files.mempool);
+            std::process::exit(0);
         }
+    }
+}
 
-        return Ok(());
+async fn serve(
+    tx: mpsc::Sender<(u16, u64)>,
+    outgoing: queue::Receiver<Outgoing>,
+    ctx: Context,
+) -> io::Result<()> {
+    let addrs = get_all_peers(&[], 0);
+    let listener = TcpListener::bind(&addrs[..]).await?;
+
+    let local_port = listener.local_addr()?.port();
+    println!("> listening on {}...", listener.local_addr()?);
+
+    // pick up txs saved by the last run on this port, before peers send
+    // theirs
+    let files = DataFiles::new(local_port)?;
+    files.load(&mut ctx.mempool.lock().unwrap());
+    tokio::spawn(persist(Arc::clone(&ctx.mempool), files, Arc::clone(&ctx.stop)));
+
+    // let the miner know what port we're listening on, if there is one,
+    // and which extra nonces are ours
+    let extra_nonces = {
+        let mut work = ctx.work.lock().unwrap();
+        work.set_port(local_port);
+        work.extra_nonces()
+    };
+    tx.send((local_port, extra_nonces)).ok();
+    http::start(&ctx, local_port).await;
+
+    let (inbox, incoming) = queue::channel(SETTINGS.get::<usize>("outgoing_queue_size").unwrap());
+    tokio::spawn(handle_inbox(incoming, ctx.clone()));
+    tokio::spawn(dispatch(outgoing, local_port, Some(inbox.clone())));
+
+    // ask a peer for any missed blocks & txs; the responses come back on
+    // the same connection
+    let peers = get_live_peers(&[], local_port).await;
+    if !peers.is_empty() {
+        for action in &[
+            ActionType::SyncRequest(ObjectType::Tx),
+            ActionType::SyncRequest(ObjectType::Block),
+        ] {
+            let id = ctx.net.request::<SyncRequest>(
+                action.clone(),
+                &SyncRequest {},
+                &[peers[0].port()],
+            ).await?;
+            ctx.requests.lock().unwrap().insert(id);
+        }
     }
 
-    Ok(())
+    // start handling requests, with a cap on concurrent sessions so a flood
+    // of connections waits in the backlog instead of piling up tasks
+    let slots = Arc::new(Semaphore::new(SETTINGS.get::<usize>("max_inbound").unwrap()));
+    loop {
+        let permit = Arc::clone(&slots).acquire_owned().await.unwrap();
+        let (stream, _) = listener.accept().await?;
+        let ctx = ctx.clone();
+        let inbox = inbox.clone();
+
+        tokio::spawn(async move {
+            match session(stream, ctx, inbox).await {
+                // liveness probes from `get_live_peers` close straight away
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+                Err(e) => println!("handler failed, {}", e),
+                Ok(()) => (),
+            }
+            drop(permit);
+        });
+    }
 }
 
-#[warn(unreachable_code)]
 pub fn start(
-    tx: mpsc::Sender<u16>,
+    tx: mpsc::Sender<(u16, u64)>,
+    net: Network,
+    outgoing: queue::Receiver<Outgoing>,
+    miner: MinerSignal,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    stats: Arc<Mutex<MiningStats>>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        let addrs = get_all_peers(&[], 0);
-        let listener = TcpListener::bind(&addrs[..]).expect("could not bind");
-
-        if let Ok(addr) = listener.local_addr() {
-            println!("> listening on {}...", addr);
-            let local_port = addr.port();
-
-            // let the miner know what port we're listening on
-            tx.send(local_port).unwrap();
-
-            // send a sync request for any missed blocks & txs
-            let peers = get_live_peers(&[], local_port);
-            if !peers.is_empty() {
-                for action in &[
-                    ActionType::SyncRequest(ObjectType::Tx),
-                    ActionType::SyncRequest(ObjectType::Block),
-                ] {
-                    broadcast::<SyncRequest>(
-                        action.clone(),
-                        &SyncRequest { port: local_port },
-                        &[peers[0].port()],
-                        0,
-                    ).unwrap();
-                }
-            }
+        // all networking runs on this one event loop
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("could not start event loop");
 
-            // start handling requests
-            loop {
-                if let Ok((stream, _)) = listener.accept() {
-                    if let Err(e) = handler(
-                        stream,
-                        local_port,
-                        &blockchain,
-                        &mempool,
-                    ) {
-                        println!("handler failed, {}", e);
-                    }
-                }
-            }
+        let ctx = Context::new(net, miner, blockchain, mempool, stats);
+        if let Err(e) = runtime.block_on(serve(tx, outgoing, ctx)) {
+            println!("server failed, {}", e);
         }
     })
 }
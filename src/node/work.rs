@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+use rand::prelude::*;
+use crate::blockchain::{Blockchain, BlockTemplate, Consensus, TemplateBuilder};
+use crate::mempool::Mempool;
+
+/// Hands block templates out to external miners. Every job gets its own
+/// extra nonce range, so workers mining for the same node never hash the
+/// same header.
+pub struct WorkServer {
+    builder: TemplateBuilder,
+    port: u16,
+    jobs: u64,
+
+    // the node's own extra nonce range, drawn at random in the top bits so
+    // that nodes anywhere, on whatever port, don't search the same space
+    extra_nonces: u64,
+}
+
+impl WorkServer {
+    pub fn new(builder: TemplateBuilder) -> Self {
+        WorkServer {
+            builder,
+            port: 0,
+            jobs: 0,
+            extra_nonces: (thread_rng().gen::<u16>() as u64) << 48,
+        }
+    }
+
+    /// The node's port, which goes in its blocks and jobs.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Where the node's extra nonce range starts.
+    pub fn extra_nonces(&self) -> u64 {
+        self.extra_nonces
+    }
+
+    /// A template for the next job, or `None` while there's nothing worth
+    /// mining or the chain isn't proof of work. Each job's extra nonces are
+    /// in the node's own range. The node's miner counts up from the bottom
+    /// of it, one per exhausted nonce space, so jobs start high enough above
+    /// that it never catches up.
+    pub fn job(&mut self, blockchain: &Mutex<Blockchain>, mempool: &Mutex<Mempool>) -> Option<BlockTemplate> {
+        let (head, params) = {
+            let bc = blockchain.lock().unwrap();
+            (bc.head(), bc.params().clone())
+        };
+        if params.consensus != Consensus::Work {
+            return None;
+        }
+
+        self.jobs += 1;
+        let builder = TemplateBuilder { extra_nonce: self.extra_nonces | self.jobs << 24, ..self.builder.clone() };
+        let mp = mempool.lock().unwrap();
+        builder.build(head, &params, &mp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::node::work::*;
+    use crate::blockchain::{ChainParams, Pow};
+
+    fn params(consensus: Consensus) -> ChainParams {
+        ChainParams { consensus, difficulty: 1, pow: Pow::Sha256, genesis_balance: 1000.0, block_reward: 10.0, regtest: false }
+    }
+
+    #[test]
+    fn test_jobs_get_their_own_extra_nonces() {
+        let blockchain = Mutex::new(Blockchain::new(params(Consensus::Work)));
+        let mempool = Mutex::new(Mempool::new());
+        let mut work = WorkServer::new(TemplateBuilder::default());
+        let range = work.extra_nonces();
+        assert_eq!(range & ((1 << 48) - 1), 0);
+
+        let first = work.job(&blockchain, &mempool).unwrap();
+        let second = work.job(&blockchain, &mempool).unwrap();
+        assert_eq!(first.coinbase.extra_nonce, range | 1 << 24);
+        assert_eq!(second.coinbase.extra_nonce, range | 2 << 24);
+        assert_eq!(first.txs, second.txs);
+    }
+
+    #[test]
+    fn test_no_work_without_proof_of_work() {
+        let consensus = Consensus::Authority { validators: vec![], interval: Duration::from_secs(1) };
+        let blockchain = Mutex::new(Blockchain::new(params(consensus)));
+        let mut work = WorkServer::new(TemplateBuilder::default());
+        assert_eq!(work.job(&blockchain, &Mutex::new(Mempool::new())), None);
+    }
+}
@@ -1,9 +1,10 @@
+use std::collections::HashMap;
 use std::thread;
 use std::time::Duration;
 use std::sync::mpsc::channel;
 use rand::prelude::*;
 use crate::types::*;
-use crate::network::broadcast;
+use crate::network::{Network, dispatch};
 use crate::config::*;
 
 fn get_wallets(randomize: bool, i: usize) -> (char, char) {
@@ -18,15 +19,34 @@ fn get_wallets(randomize: bool, i: usize) -> (char, char) {
     )
 }
 
+fn next_nonce(nonces: &mut HashMap<char, u64>, from: char) -> u64 {
+    let nonce = nonces.entry(from).or_insert(0);
+    *nonce += 1;
+    *nonce - 1
+}
+
 pub fn generate(ports: Vec<u16>) {
     let broadcast_random = SETTINGS.get::<bool>("broadcast_random").unwrap();
 
     let mut txs: Vec<Tx> = vec![];
     let (sender, receiver) = channel();
+    let (net, outgoing) = Network::new();
+
+    // connections to peers live on their own event loop
+    thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("could not start event loop")
+            .block_on(dispatch(outgoing, 0, None));
+    });
 
     thread::spawn(move || {
         let mut rng = rand::thread_rng();
         let mut i: usize = 0;
+        let mut nonces: HashMap<char, u64> = HashMap::new();
+        // the latest payment to each wallet, which its next tx spends from
+        let mut received: HashMap<char, String> = HashMap::new();
 
         loop {
             let (from, to) = get_wallets(broadcast_random, i);
@@ -38,7 +58,11 @@ pub fn generate(ports: Vec<u16>) {
                 to,
                 amount,
                 fee: (fee as f32 * 100.0).round() / 100.0,
+                nonce: next_nonce(&mut nonces, from),
+                spends: received.get(&from).cloned(),
+                stake: None,
             };
+            received.insert(to, tx.id());
 
             if sender.send(tx).is_err() {
                 println!("could not send transaction");
@@ -53,11 +77,10 @@ pub fn generate(ports: Vec<u16>) {
 
     for tx in receiver {
         println!("{:?}", tx);
-        broadcast::<Tx>(
+        net.broadcast::<Tx>(
             ActionType::Broadcast(ObjectType::Tx),
             &tx,
             &ports,
-            0,
         ).expect("could not broadcast");
         txs.push(tx);
     }
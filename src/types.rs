@@ -1,9 +1,13 @@
+use std::cmp::Ordering;
 use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::blockchain::{Block, BlockTemplate};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum ObjectType {
     Block,
     Tx,
+    CompactBlock,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -14,12 +18,41 @@ pub enum ActionType {
 
     // let other nodes know of a newly mined block or tx
     Broadcast(ObjectType),
+
+    // fetch the txs a compact block referenced but the mempool lacked
+    GetBlockTxs,
+    BlockTxs,
+
+    // ask what fee rate gets a tx mined within some number of blocks
+    EstimateFee,
+    FeeEstimate,
+
+    // ask how fast a node is mining
+    GetMiningInfo,
+    MiningInfo,
+
+    // external miners fetch block templates and hand back solved blocks
+    GetWork,
+    Work,
+    SubmitWork,
+    WorkResult,
+
+    // regtest only: mine blocks on demand, and stop the clock
+    Generate,
+    Generated,
+    SetMockTime,
+    MockTime,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Command<T> {
     pub action: ActionType,
     pub payload: T,
+
+    // set on requests and echoed back on their responses, which travel on
+    // the same connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
 }
 
 impl<T> Command<T> {
@@ -27,18 +60,125 @@ impl<T> Command<T> {
         Command {
             action,
             payload,
+            id: None,
+        }
+    }
+
+    pub fn with_id(action: ActionType, payload: T, id: u64) -> Self {
+        Command {
+            action,
+            payload,
+            id: Some(id),
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct SyncRequest {
-    pub port: u16,
-}
+pub struct SyncRequest {}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SyncResponse<T> {
     pub data: Vec<T>,
+
+    // large syncs are split up; this is false on the last batch
+    #[serde(default)]
+    pub more: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EstimateFee {
+    pub target_blocks: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FeeEstimate {
+    pub target_blocks: usize,
+
+    // per serialized byte; none until the node has seen enough blocks
+    pub fee_rate: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GetMiningInfo {}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MiningInfo {
+    // 0 when the node only relays
+    pub threads: usize,
+
+    // hashes per second over the last report interval
+    pub hashrate: f64,
+
+    // nonces tried on the current template
+    pub nonce: u64,
+
+    pub difficulty: usize,
+    pub algorithm: String,
+
+    // none until the hashrate is known
+    pub expected_secs: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GetWork {}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Work {
+    // the node's port, which solved blocks carry as their id
+    pub node: u16,
+
+    // none while there's nothing worth mining, or without proof of work
+    pub template: Option<BlockTemplate>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SubmitWork {
+    pub block: Block,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WorkResult {
+    pub accepted: bool,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Generate {
+    pub blocks: usize,
+
+    // the account the coinbases pay; the node's `miner_account` if unset
+    pub to: Option<char>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Generated {
+    pub hashes: Vec<String>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SetMockTime {
+    // unix ms to stop the clock at, or 0 to restart it
+    pub unix_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MockTime {
+    pub unix_ms: Option<u64>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GetBlockTxs {
+    pub hash: String,
+    pub indexes: Vec<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockTxs {
+    pub hash: String,
+    pub indexes: Vec<usize>,
+    pub txs: Vec<Tx>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -47,4 +187,59 @@ pub struct Tx {
     pub to: char,
     pub amount: i32,
     pub fee: f32,
+
+    // orders a sender's txs; two txs with the same sender and nonce conflict
+    #[serde(default)]
+    pub nonce: u64,
+
+    // an unconfirmed payment to the sender that this tx spends from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spends: Option<String>,
+
+    // locks the amount up as stake for this validator key instead of paying
+    // `to`; proof of stake only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stake: Option<String>,
+}
+
+impl Tx {
+    /// Serialized size in bytes; what the tx costs to relay and store.
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).map(|v| v.len()).unwrap_or(0)
+    }
+
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate(self.fee as f64 / self.size().max(1) as f64)
+    }
+
+    pub fn id(&self) -> String {
+        let serialized = serde_json::to_string(self).unwrap_or_default();
+        let mut hasher = Sha256::default();
+        hasher.update(serialized);
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// Fee per serialized byte, with a total order so it can key ordered maps.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct FeeRate(pub f64);
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
 }
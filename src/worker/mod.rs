@@ -0,0 +1,94 @@
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::atomic::AtomicBool;
+use crate::blockchain::{mine, Clock};
+use crate::network::request_from;
+use crate::types::*;
+use crate::config::*;
+
+/// Mines for the node at `pool` on `threads` threads (all cores by default):
+/// fetches a job, hashes it until it's solved or `work_refresh_secs` pass,
+/// then fetches the next one. Never touches a chain or mempool itself.
+pub fn start(pool: SocketAddr, threads: Option<usize>) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("could not start event loop");
+
+    let threads = threads.unwrap_or_else(rayon::current_num_threads);
+    let workers = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("miner-{}", i))
+        .build()
+        .expect("could not start mining threads");
+
+    let concurrent_hashes = SETTINGS.get::<u64>("concurrent_hashes").unwrap();
+    let refresh = Duration::from_secs(SETTINGS.get::<u64>("work_refresh_secs").unwrap());
+
+    // jobs only go stale on a timer, never part way through a round
+    let abort = AtomicBool::new(false);
+
+    // with no chain of its own, a worker's clock never stops
+    let clock = Clock::default();
+
+    println!("> mining for {} on {} thread(s)...", pool, threads);
+    loop {
+        let work = runtime.block_on(request_from::<GetWork, Work>(
+            pool,
+            ActionType::GetWork,
+            &GetWork {},
+            ActionType::Work,
+        ));
+        let (node, mut template) = match work {
+            Ok(Work { node, template: Some(template) }) => (node, template),
+            Ok(Work { template: None, .. }) => {
+                println!("no work from {}, waiting", pool);
+                thread::sleep(refresh);
+                continue;
+            },
+            Err(e) => {
+                println!("could not get work from {}, {}", pool, e);
+                thread::sleep(refresh);
+                continue;
+            },
+        };
+
+        let (fetched, time) = (Instant::now(), clock.now());
+        let mut nonce: u64 = 0;
+        let mut hashes: u64 = 0;
+
+        while fetched.elapsed() < refresh {
+            let found = workers.install(|| mine(&template, node, nonce..nonce + concurrent_hashes, &clock, time, &abort));
+            hashes += concurrent_hashes;
+
+            if let Some(block) = found {
+                let result = runtime.block_on(request_from::<SubmitWork, WorkResult>(
+                    pool,
+                    ActionType::SubmitWork,
+                    &SubmitWork { block: block.clone() },
+                    ActionType::WorkResult,
+                ));
+                match result {
+                    Ok(WorkResult { accepted: true, .. }) => {
+                        println!("> block {} accepted, {} tx(s)", &block.hash[..8], block.len());
+                    },
+                    Ok(WorkResult { reason, .. }) => {
+                        println!("> block {} rejected: {}", &block.hash[..8], reason.unwrap_or_default());
+                    },
+                    Err(e) => println!("could not submit to {}, {}", pool, e),
+                }
+                break;
+            }
+
+            if nonce.checked_add(2 * concurrent_hashes).is_some() {
+                nonce += concurrent_hashes;
+            } else {
+                template.bump_extra_nonce();
+                nonce = 0;
+            }
+        }
+
+        println!("> hashrate {:.0} H/s", hashes as f64 / fetched.elapsed().as_secs_f64());
+    }
+}